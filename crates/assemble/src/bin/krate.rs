@@ -60,7 +60,7 @@ Precompiled binary of `cargo-wasix` for {target}
     fs::create_dir("tmp").context("failed to create `tmp`")?;
 
     let binary = Path::new(&binary);
-    fs::copy(&binary, Path::new("tmp").join(binary.file_name().unwrap()))?;
+    fs::copy(binary, Path::new("tmp").join(binary.file_name().unwrap()))?;
 
     let filename = format!(
         "{}-{}.crate",
@@ -20,7 +20,7 @@ const TARGETS: &[(&str, &str)] = &[
 
 fn main() -> anyhow::Result<()> {
     let tmp = Path::new("tmp");
-    drop(fs::remove_dir_all(&tmp));
+    drop(fs::remove_dir_all(tmp));
     fs::create_dir_all(tmp.join("shim")).context("failed to create `tmp` dir")?;
 
     println!("Copying the shim into `tmp`...");
@@ -48,7 +48,7 @@ fn main() -> anyhow::Result<()> {
             let status = Command::new("tar")
                 .arg("xf")
                 .arg(krate.canonicalize().context("failed to canonicalize")?)
-                .current_dir(&tmp)
+                .current_dir(tmp)
                 .status()
                 .context("failed to spawn `tar`")?;
             if !status.success() {
@@ -65,7 +65,7 @@ fn main() -> anyhow::Result<()> {
         .replace("cargo-wasix-shim", "cargo-wasix")
         .replace("0.0.0", &version);
     manifest.truncate(manifest.find("[features]").unwrap());
-    manifest.push_str("\n");
+    manifest.push('\n');
     for (target, cfg) in TARGETS {
         manifest.push_str("[target.'cfg(");
         manifest.push_str(cfg);
@@ -75,7 +75,7 @@ fn main() -> anyhow::Result<()> {
     manifest.push_str("[target.'cfg(not(any(");
     for (_, cfg) in TARGETS {
         manifest.push_str(cfg);
-        manifest.push_str(",");
+        manifest.push(',');
     }
     manifest.push_str(")))'.dependencies]\n");
     manifest.push_str(&format!("cargo-wasix-src = \"={}\"\n", version));
@@ -128,12 +128,9 @@ fn run_precompiled(bytes: &[u8]) {
     if cfg!(unix) {
         cmd.env("__CARGO_WASIX_SELF_DELETE_FOR_SURE", "1");
     }
-    match exec(&mut cmd) {
-        Ok(()) => return,
-        Err(e) => {
-            eprintln!("failed to spawn child process: {}", e);
-            std::process::exit(5);
-        }
+    if let Err(e) = exec(&mut cmd) {
+        eprintln!("failed to spawn child process: {}", e);
+        std::process::exit(5);
     }
 }
 
@@ -8,7 +8,7 @@ fn me() -> PathBuf {
     me.pop();
     me.push("cargo-wasix-shim");
     me.set_extension(std::env::consts::EXE_EXTENSION);
-    return me;
+    me
 }
 
 fn case() -> (tempfile::TempDir, PathBuf) {
@@ -1,6 +1,21 @@
-use anyhow::Result;
+//! The cargo-wasix cache directory, the archives downloaded into it before
+//! they are extracted, and `cargo wasix cache status` / `cargo wasix cache
+//! prune`.
+
 use std::path::{Path, PathBuf};
 
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    config::Config,
+    error::ToolchainError,
+    meta::ToolchainMeta,
+    release::{authenticated_get, AssetSource, DownloadOptions, HashReader},
+    toolchain::{installed_toolchain, EnsureMemo},
+    utils::{dir_size, format_bytes},
+};
+use log::info;
+
 pub struct Cache {
     all_versions_root: PathBuf,
     root: PathBuf,
@@ -25,3 +40,443 @@ impl Cache {
         &self.all_versions_root
     }
 }
+
+/// Download the archives among `assets` at the same time, into the cache
+/// that [`crate::release::download_release_asset_with`] extracts them from
+/// afterwards.
+///
+/// The first failed download aborts the others, and is the one reported.
+pub(crate) fn prefetch_archives(
+    client: &reqwest::blocking::Client,
+    options: &DownloadOptions,
+    tag: &str,
+    assets: &[&AssetSource],
+) -> Result<(), anyhow::Error> {
+    let archives = assets
+        .iter()
+        .filter(|asset| asset.name.ends_with(".tar.gz") && !asset.url.starts_with("file://"))
+        .collect::<Vec<_>>();
+    if archives.len() < 2 {
+        return Ok(());
+    }
+    let cache = Config::cache_dir()?.join("downloads").join(tag);
+    // Aborts the other downloads without marking the install as cancelled,
+    // and is cancelled itself along with the install.
+    let abort = options.cancel.child();
+    let mut prefetch_options = options.clone();
+    prefetch_options.cancel = abort.clone();
+    let first_error = std::sync::Mutex::new(None);
+
+    std::thread::scope(|scope| {
+        let handles = archives
+            .iter()
+            .map(|asset| {
+                let (options, cache, abort, first_error) =
+                    (&prefetch_options, &cache, &abort, &first_error);
+                scope.spawn(move || {
+                    let url = options.asset_url(tag, &asset.name, &asset.url);
+                    let token = options.token(options.asset_mirror.is_some());
+                    if let Err(err) = cached_download(client, &url, token, options, asset, cache) {
+                        let mut first = first_error.lock().unwrap();
+                        if first.is_none() {
+                            *first = Some(err);
+                        }
+                        abort.cancel();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            if let Err(panic) = handle.join() {
+                std::panic::resume_unwind(panic);
+            }
+        }
+    });
+
+    match first_error.into_inner().unwrap() {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+/// Download `url` to `dir/{name}`, or reuse a complete earlier download of
+/// the same asset there.
+///
+/// Complete downloads are marked by a `{name}.sha256` file next to them.
+/// Incomplete ones are kept as `{name}.part` and resumed with a range request
+/// on retry, or in a later run.
+pub(crate) fn cached_download(
+    client: &reqwest::blocking::Client,
+    url: &str,
+    token: Option<String>,
+    options: &DownloadOptions,
+    asset: &AssetSource,
+    dir: &Path,
+) -> Result<PathBuf, anyhow::Error> {
+    let path = dir.join(&asset.name);
+    let marker = dir.join(format!("{}.sha256", asset.name));
+    if let Ok(recorded) = std::fs::read_to_string(&marker) {
+        let recorded = recorded.split_whitespace().next().unwrap_or_default();
+        let expected = asset.sha256.as_deref();
+        if path.is_file() && expected.is_none_or(|sha| sha.eq_ignore_ascii_case(recorded)) {
+            info!("Using the earlier download at {}", path.display());
+            return Ok(path);
+        }
+    }
+    remove_cached_download(&path);
+
+    info!("Downloading {} from url '{url}'...", asset.name);
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Could not create directory {}", dir.display()))?;
+    let partial = dir.join(format!("{}.part", asset.name));
+    let send = |from: u64| {
+        let mut req = authenticated_get(client, url, token.clone());
+        if from > 0 {
+            req = req.header(reqwest::header::RANGE, format!("bytes={from}-"));
+        }
+        req.send()
+    };
+    let actual = options.retries.run(&asset.name, || {
+        use sha2::Digest;
+        use std::io::Write;
+
+        let mut have = std::fs::metadata(&partial).map_or(0, |meta| meta.len());
+        let mut res = send(have)?;
+        if have > 0 && res.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+            have = 0;
+            res = send(0)?;
+        }
+        let res = res.error_for_status()?;
+
+        // Servers without range support send everything again.
+        let resumed = have > 0 && res.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+        let expected_len = res
+            .content_length()
+            .map(|len| if resumed { have + len } else { len });
+        if !resumed {
+            have = 0;
+        }
+        let (file, hasher) = if resumed {
+            info!("Resuming the download after {}...", format_bytes(have));
+            let mut done = HashReader::new(
+                std::fs::File::open(&partial)
+                    .with_context(|| format!("Could not open {}", partial.display()))?,
+            );
+            std::io::copy(&mut done, &mut std::io::sink())?;
+            let file = std::fs::OpenOptions::new()
+                .append(true)
+                .open(&partial)
+                .with_context(|| format!("Could not open {}", partial.display()))?;
+            (file, done.hasher)
+        } else {
+            let file = std::fs::File::create(&partial)
+                .with_context(|| format!("Could not create {}", partial.display()))?;
+            (file, sha2::Sha256::new())
+        };
+
+        let res = crate::utils::progress(res, &asset.name, have, expected_len);
+        let mut reader = HashReader {
+            inner: options.cancel.reader(res),
+            hasher,
+        };
+        let mut writer = std::io::BufWriter::new(file);
+        std::io::copy(&mut reader, &mut writer)
+            .and_then(|_| writer.flush())
+            .with_context(|| ToolchainError::DownloadFailed {
+                url: url.to_string(),
+            })?;
+        let len = std::fs::metadata(&partial)?.len();
+        if let Some(expected) = expected_len.filter(|expected| *expected != len) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("download ended after {len} of {expected} bytes"),
+            )
+            .into());
+        }
+        reader.finish()
+    })?;
+    if let Some(expected) = &asset.sha256 {
+        if !actual.eq_ignore_ascii_case(expected) {
+            std::fs::remove_file(&partial).ok();
+            bail!("Checksum mismatch for {url}: expected sha256 {expected}, got {actual}");
+        }
+    }
+    std::fs::rename(&partial, &path)
+        .with_context(|| format!("Could not move download to {}", path.display()))?;
+    std::fs::write(&marker, format!("{actual}  {}\n", asset.name))
+        .with_context(|| format!("Could not write {}", marker.display()))?;
+    Ok(path)
+}
+
+/// Remove a download made by [`cached_download`] and its marker.
+pub(crate) fn remove_cached_download(path: &Path) {
+    let marker = path.with_file_name(format!(
+        "{}.sha256",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    ));
+    drop(std::fs::remove_file(marker));
+    drop(std::fs::remove_file(path));
+}
+
+/// A directory held by one of cargo-wasix's caches.
+struct CacheEntry {
+    kind: &'static str,
+    name: String,
+    path: PathBuf,
+    size: u64,
+    last_access: Option<std::time::SystemTime>,
+    /// Whether the entry is still needed by the current install.
+    referenced: bool,
+}
+
+/// Collect installed toolchains, build trees and per-version caches.
+fn cache_entries(config: &Config) -> Result<Vec<CacheEntry>, anyhow::Error> {
+    let linked = installed_toolchain().ok().flatten().map(|chain| chain.path);
+    let is_linked = |path: &Path| linked.as_ref().is_some_and(|l| l.starts_with(path));
+
+    let subdirs = |dir: &Path| -> Result<Vec<PathBuf>, anyhow::Error> {
+        if !dir.is_dir() {
+            return Ok(Vec::new());
+        }
+        let mut out = Vec::new();
+        for entry in std::fs::read_dir(dir)
+            .with_context(|| format!("Could not read directory {}", dir.display()))?
+        {
+            let path = entry?.path();
+            if path.is_dir() {
+                out.push(path);
+            }
+        }
+        out.sort();
+        Ok(out)
+    };
+    let dir_name = |path: &Path| {
+        path.file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()
+    };
+
+    let mut entries = Vec::new();
+    let mut push = |kind, name, path: PathBuf, referenced| -> Result<(), anyhow::Error> {
+        let meta = std::fs::metadata(&path)?;
+        entries.push(CacheEntry {
+            kind,
+            name,
+            size: dir_size(&path)?,
+            last_access: meta.accessed().or_else(|_| meta.modified()).ok(),
+            path,
+            referenced,
+        });
+        Ok(())
+    };
+
+    for path in subdirs(&Config::toolchain_dir()?)? {
+        let name = match ToolchainMeta::load(&path) {
+            Ok(Some(meta)) => format!("{} ({})", meta.tag, meta.target),
+            _ => dir_name(&path),
+        };
+        let referenced = is_linked(&path);
+        push("toolchain", name, path, referenced)?;
+    }
+
+    // LLVM and source checkouts are only needed while a locally built
+    // toolchain is linked.
+    let build_dir = Config::build_dir()?;
+    let build_linked = is_linked(&build_dir);
+    for path in subdirs(&build_dir)? {
+        let kind = if dir_name(&path).starts_with("llvm") {
+            "llvm"
+        } else {
+            "source"
+        };
+        push(kind, dir_name(&path), path, build_linked)?;
+    }
+
+    for path in subdirs(&Config::llvm_cache_dir()?)? {
+        push(
+            "llvm",
+            format!("{} (shared)", dir_name(&path)),
+            path,
+            build_linked,
+        )?;
+    }
+
+    let current = config.cache().root();
+    for path in subdirs(config.cache().all_versions_root())? {
+        let referenced = path == current;
+        push("cache", dir_name(&path), path, referenced)?;
+    }
+
+    Ok(entries)
+}
+
+fn format_age(time: Option<std::time::SystemTime>) -> String {
+    let Some(elapsed) = time.and_then(|t| t.elapsed().ok()) else {
+        return "unknown".to_string();
+    };
+    let days = elapsed.as_secs() / (60 * 60 * 24);
+    match days {
+        0 => "today".to_string(),
+        1 => "1 day ago".to_string(),
+        n => format!("{n} days ago"),
+    }
+}
+
+/// List cache entries, marking the ones that can be pruned.
+pub fn cache_status(config: &Config) -> Result<(), anyhow::Error> {
+    let entries = cache_entries(config)?;
+    if entries.is_empty() {
+        config.info("No cached toolchains or build trees");
+        return Ok(());
+    }
+
+    let mut reclaimable = 0;
+    for entry in &entries {
+        if !entry.referenced {
+            reclaimable += entry.size;
+        }
+        println!(
+            "{:<10} {:<12} {:>10}  {:<12} {}\n{:>11}{}",
+            entry.kind,
+            if entry.referenced {
+                "in use"
+            } else {
+                "orphaned"
+            },
+            format_bytes(entry.size),
+            format_age(entry.last_access),
+            entry.name,
+            "",
+            entry.path.display(),
+        );
+    }
+    config.info(&format!(
+        "{} reclaimable with `cargo wasix cache prune`",
+        format_bytes(reclaimable)
+    ));
+    Ok(())
+}
+
+/// Remove all cache entries not referenced by the current install.
+pub fn cache_prune(config: &Config) -> Result<(), anyhow::Error> {
+    let _lock = Config::acquire_lock()?;
+    EnsureMemo::invalidate();
+
+    let mut freed = 0;
+    for entry in cache_entries(config)? {
+        if entry.referenced {
+            continue;
+        }
+        config.status(
+            "Removing",
+            &format!("{} ({})", entry.path.display(), format_bytes(entry.size)),
+        );
+        std::fs::remove_dir_all(&entry.path)
+            .with_context(|| format!("Could not remove {}", entry.path.display()))?;
+        freed += entry.size;
+    }
+
+    config.info(&format!("Freed {}", format_bytes(freed)));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::release::testing::{header, http_response, serve};
+    use crate::release::Retries;
+
+    #[test]
+    fn test_cached_download_reuses_complete_download() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let data = b"archive";
+        let sha256 = HashReader::new(&data[..]).finish().unwrap();
+        let asset = AssetSource {
+            name: "wasix-libc.tar.gz".to_string(),
+            // Nothing listens here, so any download attempt fails.
+            url: "http://127.0.0.1:9/wasix-libc.tar.gz".to_string(),
+            sha256: Some(sha256.clone()),
+        };
+        let client = reqwest::blocking::Client::new();
+        let mut options = DownloadOptions::default();
+        options.retries = Retries {
+            retries: 0,
+            base_delay: std::time::Duration::ZERO,
+        };
+        let path = dir.path().join(&asset.name);
+        std::fs::write(&path, data).unwrap();
+
+        // Without a marker the download counts as interrupted.
+        assert!(cached_download(&client, &asset.url, None, &options, &asset, dir.path()).is_err());
+        assert!(!path.exists());
+
+        std::fs::write(&path, data).unwrap();
+        std::fs::write(
+            dir.path().join("wasix-libc.tar.gz.sha256"),
+            format!("{sha256}  wasix-libc.tar.gz\n"),
+        )
+        .unwrap();
+        let found =
+            cached_download(&client, &asset.url, None, &options, &asset, dir.path()).unwrap();
+        assert_eq!(found, path);
+
+        remove_cached_download(&path);
+        assert!(std::fs::read_dir(dir.path()).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn test_cached_download_resumes_partial_download() {
+        let data = b"0123456789abcdef";
+        // Serves the rest of `data` for a range request the first time, and
+        // ignores the range the second time.
+        let (base, server) = serve(2, move |i, headers| {
+            let from = header(headers, "range")
+                .and_then(|r| r.strip_prefix("bytes="))
+                .map_or(0, |r| r.trim_end_matches('-').parse().unwrap());
+            if i == 0 && from > 0 {
+                http_response(
+                    "206 Partial Content",
+                    &format!(
+                        "Content-Range: bytes {from}-{}/{}",
+                        data.len() - 1,
+                        data.len()
+                    ),
+                    &data[from..],
+                )
+            } else {
+                http_response("200 OK", "", data)
+            }
+        });
+        let url = format!("{base}/wasix-libc.tar.gz");
+
+        let asset = AssetSource {
+            name: "wasix-libc.tar.gz".to_string(),
+            url: url.clone(),
+            sha256: Some(HashReader::new(&data[..]).finish().unwrap()),
+        };
+        let client = reqwest::blocking::Client::new();
+        let mut options = DownloadOptions::default();
+        options.retries = Retries {
+            retries: 0,
+            base_delay: std::time::Duration::ZERO,
+        };
+        for _ in 0..2 {
+            let dir = tempfile::TempDir::new().unwrap();
+            std::fs::write(dir.path().join("wasix-libc.tar.gz.part"), &data[..6]).unwrap();
+            let path = cached_download(&client, &url, None, &options, &asset, dir.path()).unwrap();
+            assert_eq!(std::fs::read(path).unwrap(), data);
+            assert!(!dir.path().join("wasix-libc.tar.gz.part").exists());
+        }
+        let ranges: Vec<_> = server
+            .join()
+            .unwrap()
+            .iter()
+            .map(|headers| header(headers, "range").map(str::to_string))
+            .collect();
+        assert_eq!(
+            ranges,
+            [Some("bytes=6-".to_string()), Some("bytes=6-".to_string())]
+        );
+    }
+}
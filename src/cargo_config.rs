@@ -0,0 +1,301 @@
+//! The parts of a project's cargo configuration that `cargo wasix` has to
+//! cooperate with: the default build target and target runners.
+//!
+//! Config files are discovered like cargo does: `.cargo/config.toml` (or the
+//! legacy `.cargo/config`) in the current directory and every parent, then in
+//! `$CARGO_HOME`. Files closer to the current directory take precedence.
+
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default)]
+pub struct CargoConfig {
+    /// `build.target`, with target spec paths resolved.
+    pub build_target: Option<String>,
+    /// The argv of `target.<triple>.runner` by triple.
+    runners: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(serde::Deserialize, Default)]
+struct RawConfig {
+    #[serde(default)]
+    build: RawBuild,
+    #[serde(default)]
+    target: BTreeMap<String, RawTarget>,
+}
+
+#[derive(serde::Deserialize, Default)]
+struct RawBuild {
+    target: Option<StringOrList>,
+}
+
+#[derive(serde::Deserialize)]
+struct RawTarget {
+    runner: Option<StringOrList>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum StringOrList {
+    String(String),
+    List(Vec<String>),
+}
+
+impl StringOrList {
+    /// The value, or the first entry of a list.
+    fn first(self) -> Option<String> {
+        match self {
+            Self::String(s) => Some(s),
+            Self::List(list) => list.into_iter().next(),
+        }
+    }
+
+    /// The value as a command line, with a string split on whitespace like
+    /// cargo does.
+    fn into_args(self) -> Vec<String> {
+        match self {
+            Self::String(s) => s.split_whitespace().map(String::from).collect(),
+            Self::List(list) => list,
+        }
+    }
+}
+
+impl CargoConfig {
+    /// Load the configuration that applies in `cwd`.
+    ///
+    /// `CARGO_BUILD_TARGET` overrides `build.target`, like it does for cargo.
+    pub fn load(cwd: &Path) -> Result<Self> {
+        let mut config = Self::default();
+        // Lowest precedence first, so closer files overwrite.
+        let mut files = config_files(cwd);
+        files.reverse();
+        for path in files {
+            config.merge(&path)?;
+        }
+        if let Ok(target) = std::env::var("CARGO_BUILD_TARGET") {
+            config.build_target = Some(target);
+        }
+        Ok(config)
+    }
+
+    fn merge(&mut self, path: &Path) -> Result<()> {
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read cargo config {}", path.display()))?;
+        let raw: RawConfig = toml::from_str(&data)
+            .with_context(|| format!("failed to parse cargo config {}", path.display()))?;
+        if let Some(target) = raw.build.target.and_then(StringOrList::first) {
+            self.build_target = Some(resolve_target(path, target));
+        }
+        for (triple, target) in raw.target {
+            if let Some(runner) = target.runner.map(StringOrList::into_args) {
+                if !runner.is_empty() {
+                    self.runners.insert(triple, runner);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The configured runner for `target` and its arguments, if any.
+    pub fn runner(&self, target: &str) -> Option<&[String]> {
+        self.runners.get(target).map(Vec::as_slice)
+    }
+}
+
+/// Set the `runner` and `rustflags` of each of `targets` in the cargo config
+/// file at `path`, so plain `cargo +wasix run` and `cargo +wasix test` work.
+///
+/// Other settings are kept, but not the comments and formatting of the file.
+/// Differing values are replaced with a warning. Returns whether the file
+/// was changed.
+pub fn setup_runner(
+    path: &Path,
+    targets: &[&str],
+    runner: &[String],
+    rustflags: &[&str],
+) -> Result<bool> {
+    let mut config = match std::fs::read_to_string(path) {
+        Ok(data) => toml::from_str(&data)
+            .with_context(|| format!("failed to parse cargo config {}", path.display()))?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => toml::value::Table::new(),
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("failed to read cargo config {}", path.display()))
+        }
+    };
+    let runner = toml::Value::Array(runner.iter().map(|s| s.as_str().into()).collect());
+    let rustflags = toml::Value::Array(rustflags.iter().map(|&s| s.into()).collect());
+
+    let mut changed = false;
+    let target_table = config
+        .entry("target")
+        .or_insert_with(|| toml::Value::Table(Default::default()))
+        .as_table_mut()
+        .with_context(|| format!("`target` in {} is not a table", path.display()))?;
+    for target in targets {
+        let table = target_table
+            .entry(target.to_string())
+            .or_insert_with(|| toml::Value::Table(Default::default()))
+            .as_table_mut()
+            .with_context(|| format!("`target.{target}` in {} is not a table", path.display()))?;
+        for (key, value) in [("runner", &runner), ("rustflags", &rustflags)] {
+            match table.get(key) {
+                Some(existing) if existing == value => continue,
+                Some(existing) => crate::utils::warn(format!(
+                    "Replacing target.{target}.{key} = {existing} in {}",
+                    path.display()
+                )),
+                None => {}
+            }
+            table.insert(key.to_string(), value.clone());
+            changed = true;
+        }
+    }
+    if changed {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+        }
+        std::fs::write(path, toml::to_string(&config)?)
+            .with_context(|| format!("failed to write cargo config {}", path.display()))?;
+    }
+    Ok(changed)
+}
+
+/// Target spec paths in `build.target` are relative to the directory that
+/// holds the `.cargo` directory of the config file.
+fn resolve_target(config_path: &Path, target: String) -> String {
+    if !target.ends_with(".json") || Path::new(&target).is_absolute() {
+        return target;
+    }
+    match config_path.parent().and_then(Path::parent) {
+        Some(base) => base.join(target).display().to_string(),
+        None => target,
+    }
+}
+
+/// Config files that apply in `cwd`, highest precedence first.
+fn config_files(cwd: &Path) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = cwd.ancestors().map(|dir| dir.join(".cargo")).collect();
+    let cargo_home = std::env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| dirs::home_dir().map(|home| home.join(".cargo")));
+    if let Some(home) = cargo_home {
+        if !dirs.contains(&home) {
+            dirs.push(home);
+        }
+    }
+    dirs.into_iter()
+        .filter_map(|dir| {
+            ["config.toml", "config"]
+                .iter()
+                .map(|name| dir.join(name))
+                .find(|path| path.is_file())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_cargo_config() {
+        let root = tempfile::TempDir::new().unwrap();
+        let project = root.path().join("project");
+        let member = project.join("member");
+        std::fs::create_dir_all(member.join(".cargo")).unwrap();
+        std::fs::create_dir_all(project.join(".cargo")).unwrap();
+        std::fs::write(
+            project.join(".cargo/config.toml"),
+            r#"
+                [build]
+                target = "wasm64-wasmer-wasi"
+
+                [target.wasm32-wasmer-wasi]
+                runner = "wasmer-dev"
+
+                [target.wasm64-wasmer-wasi]
+                runner = ["wasmtime", "run", "--dir=."]
+
+                [target.x86_64-unknown-linux-gnu]
+                runner = "wasmer  run --net"
+            "#,
+        )
+        .unwrap();
+        std::fs::write(
+            member.join(".cargo/config"),
+            "[target.wasm32-wasmer-wasi]\nrunner = \"./run.sh\"\n",
+        )
+        .unwrap();
+
+        let mut config = CargoConfig::default();
+        for path in config_files(&member).iter().rev() {
+            if path.starts_with(root.path()) {
+                config.merge(path).unwrap();
+            }
+        }
+        assert_eq!(config.build_target.as_deref(), Some("wasm64-wasmer-wasi"));
+        assert_eq!(
+            config.runner("wasm32-wasmer-wasi"),
+            Some(&["./run.sh".to_string()][..])
+        );
+        assert_eq!(
+            config.runner("wasm64-wasmer-wasi").unwrap(),
+            ["wasmtime", "run", "--dir=."]
+        );
+        assert_eq!(
+            config.runner("x86_64-unknown-linux-gnu").unwrap(),
+            ["wasmer", "run", "--net"]
+        );
+        assert_eq!(config.runner("aarch64-unknown-linux-gnu"), None);
+
+        std::fs::write(
+            member.join(".cargo/config"),
+            "[build]\ntarget = \"specs/wasm64-custom.json\"\n",
+        )
+        .unwrap();
+        config.merge(&member.join(".cargo/config")).unwrap();
+        assert_eq!(
+            config.build_target,
+            Some(
+                member
+                    .join("specs/wasm64-custom.json")
+                    .display()
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_setup_runner() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join(".cargo/config.toml");
+        let targets = ["wasm32-wasmer-wasi", "wasm64-wasmer-wasi"];
+        let runner = ["wasmer".to_string(), "run".to_string()];
+        let flags = ["-C", "target-feature=+atomics"];
+        assert!(setup_runner(&path, &targets, &runner, &flags).unwrap());
+        assert!(!setup_runner(&path, &targets, &runner, &flags).unwrap());
+
+        std::fs::write(
+            &path,
+            "[build]\njobs = 2\n\n[target.wasm32-wasmer-wasi]\nrunner = \"wasmtime\"\n",
+        )
+        .unwrap();
+        assert!(setup_runner(&path, &targets, &runner, &flags).unwrap());
+        let config: toml::Value = toml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(config["build"]["jobs"].as_integer(), Some(2));
+        assert_eq!(
+            config["target"]["wasm32-wasmer-wasi"]["runner"],
+            toml::Value::Array(vec!["wasmer".into(), "run".into()])
+        );
+
+        let mut merged = CargoConfig::default();
+        merged.merge(&path).unwrap();
+        assert_eq!(
+            merged.runner("wasm64-wasmer-wasi").unwrap(),
+            ["wasmer", "run"]
+        );
+    }
+}
@@ -1,12 +1,13 @@
 use std::path::PathBuf;
 
 use crate::{tool_path::ToolPath, Cache};
-use anyhow::Result;
+use anyhow::{Context, Result};
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
 pub struct Config {
     cache: Option<Cache>,
     verbose: bool,
+    deny_warnings: bool,
     choice: ColorChoice,
 }
 
@@ -15,14 +16,33 @@ impl Config {
         Config {
             cache: None,
             verbose: false,
-            choice: if atty::is(atty::Stream::Stderr) {
-                ColorChoice::Auto
-            } else {
-                ColorChoice::Never
-            },
+            deny_warnings: std::env::var("WASIX_DENY_WARNINGS")
+                .is_ok_and(|v| v == "1" || v == "true"),
+            choice: Self::color_choice("auto").expect("auto is a valid color choice"),
         }
     }
 
+    /// Resolve a `--color` value.
+    ///
+    /// `auto` honors `NO_COLOR` and `CLICOLOR_FORCE` and otherwise only colors
+    /// output to a terminal.
+    fn color_choice(when: &str) -> Result<ColorChoice> {
+        let no_color = std::env::var("NO_COLOR").is_ok_and(|v| !v.is_empty());
+        let force = std::env::var("CLICOLOR_FORCE").is_ok_and(|v| !v.is_empty() && v != "0");
+        let choice = match when {
+            "always" => ColorChoice::Always,
+            "never" => ColorChoice::Never,
+            "auto" if no_color => ColorChoice::Never,
+            "auto" if force => ColorChoice::Always,
+            "auto" if atty::is(atty::Stream::Stderr) => ColorChoice::Auto,
+            "auto" => ColorChoice::Never,
+            other => anyhow::bail!(
+                "invalid `--color` value: {other} - expected `always`, `auto` or `never`"
+            ),
+        };
+        Ok(choice)
+    }
+
     pub fn data_dir() -> Result<PathBuf, anyhow::Error> {
         let dir = if let Ok(dir) = std::env::var("WASIX_DATA_DIR") {
             dir.into()
@@ -53,6 +73,55 @@ impl Config {
         Ok(dir)
     }
 
+    /// Directory used for intermediate download and extraction work.
+    ///
+    /// Defaults to the system temp dir. Set `WASIX_TMPDIR` to move this work
+    /// elsewhere, ideally onto the same filesystem as the toolchain dir.
+    pub fn tmp_dir() -> Result<PathBuf, anyhow::Error> {
+        let dir = match std::env::var_os("WASIX_TMPDIR") {
+            Some(dir) => PathBuf::from(dir),
+            None => std::env::temp_dir(),
+        };
+        std::fs::create_dir_all(&dir).with_context(|| {
+            format!(
+                "Could not create temporary directory {} - set WASIX_TMPDIR to a writable directory",
+                dir.display()
+            )
+        })?;
+        tempfile::tempfile_in(&dir).with_context(|| {
+            format!(
+                "Temporary directory {} is not writable - set WASIX_TMPDIR to a writable directory",
+                dir.display()
+            )
+        })?;
+        Ok(dir)
+    }
+
+    /// Root directory for local toolchain builds.
+    ///
+    /// Overridable via the `WASIX_BUILD_DIR` env var.
+    pub fn build_dir() -> Result<PathBuf, anyhow::Error> {
+        let dir = if let Ok(dir) = std::env::var("WASIX_BUILD_DIR") {
+            PathBuf::from(dir)
+        } else {
+            #[allow(deprecated)]
+            std::env::home_dir()
+                .context("Could not determine home dir. set WASIX_BUILD_DIR env var!")?
+                .join(".wasix")
+        };
+        Ok(dir)
+    }
+
+    /// Where `build-toolchain` puts the std docs of the wasix targets.
+    pub fn std_docs_dir() -> Result<PathBuf, anyhow::Error> {
+        Self::data_dir().map(|d| d.join("std-docs"))
+    }
+
+    /// Shared cache of downloaded LLVM releases, linked into build dirs.
+    pub fn llvm_cache_dir() -> Result<PathBuf, anyhow::Error> {
+        Self::data_dir().map(|d| d.join("llvm-cache"))
+    }
+
     pub fn toolchain_dir() -> Result<PathBuf, anyhow::Error> {
         Self::data_dir().map(|d| d.join("toolchains"))
     }
@@ -61,8 +130,16 @@ impl Config {
         Self::data_dir().map(|p| p.join("rustup-lock"))
     }
 
+    /// Lock the toolchains against other cargo-wasix processes, giving up
+    /// after `WASIX_LOCK_TIMEOUT` seconds if set.
     pub fn acquire_lock() -> Result<crate::utils::FileLock, anyhow::Error> {
-        crate::utils::flock(&Self::lockfile_path()?)
+        let timeout = match std::env::var("WASIX_LOCK_TIMEOUT") {
+            Ok(v) if !v.is_empty() => Some(std::time::Duration::from_secs(v.parse().with_context(
+                || format!("Invalid env var WASIX_LOCK_TIMEOUT with value '{v}' - expected a number of seconds"),
+            )?)),
+            _ => None,
+        };
+        crate::utils::flock_timeout(&Self::lockfile_path()?, timeout)
     }
 
     pub fn load_cache(&mut self) -> Result<()> {
@@ -89,12 +166,35 @@ impl Config {
         self.verbose = verbose;
     }
 
+    pub fn set_color(&mut self, when: &str) -> Result<()> {
+        self.choice = Self::color_choice(when)?;
+        Ok(())
+    }
+
+    pub fn set_deny_warnings(&mut self, deny: bool) {
+        self.deny_warnings = deny;
+    }
+
+    /// Fails if any warnings were emitted and warnings are denied.
+    pub fn check_warnings(&self) -> Result<()> {
+        let warnings = crate::utils::warnings();
+        if self.deny_warnings && !warnings.is_empty() {
+            anyhow::bail!(
+                "{} warning(s) emitted and warnings are denied:\n  {}",
+                warnings.len(),
+                warnings.join("\n  ")
+            );
+        }
+        Ok(())
+    }
+
     pub fn status(&self, name: &str, rest: &str) {
         let mut shell = StandardStream::stderr(self.choice);
         drop(shell.set_color(ColorSpec::new().set_fg(Some(Color::Green)).set_bold(true)));
         eprint!("{:>12}", name);
         drop(shell.reset());
         eprintln!(" {}", rest);
+        crate::utils::log_to_file(&format!("{:>12} {}", name, rest));
     }
 
     pub fn print_error(&self, err: &anyhow::Error) {
@@ -114,6 +214,8 @@ impl Config {
             eprintln!(":");
             eprintln!("    {}", cause.to_string().replace('\n', "\n    "));
         }
+        crate::utils::annotate("error", &format!("{:#}", err));
+        crate::utils::log_to_file(&format!("error: {:?}", err));
     }
 
     pub fn info(&self, msg: &str) {
@@ -122,6 +224,7 @@ impl Config {
         eprint!("info");
         drop(shell.reset());
         eprintln!(": {}", msg);
+        crate::utils::log_to_file(&format!("info: {}", msg));
     }
 
     /// Returns the path to execute a tool, which may be the cache path to
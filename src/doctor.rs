@@ -0,0 +1,281 @@
+//! `cargo wasix doctor` and `cargo wasix toolchain verify`, which check an
+//! install and say how to fix what is wrong with it.
+
+use std::{path::Path, process::Command};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    config::Config,
+    meta::{check_toolchain_layout, ToolchainMeta},
+    release::{
+        authenticated_get, compare_archive, fetch_release_by_tag, http_client, AssetSource,
+        DownloadOptions,
+    },
+    toolchain::{
+        cargo_compat_problem, check_sysroot_arch, guess_host_target, installed_toolchain,
+        newest_installed, rustup_available, rustup_disabled, RustupToolchain,
+        RUSTUP_TOOLCHAIN_NAME, WASIX_TARGETS,
+    },
+    utils::{warn, CommandExt},
+};
+
+/// Outcome of a `doctor` check: what was found, or the problem and how to
+/// fix it.
+type DoctorCheck = (String, Result<String, (String, String)>);
+
+/// Run the checks `ensure_toolchain` relies on, and check for the tools
+/// needed to build toolchains and run binaries, printing a report.
+///
+/// Fails if any check failed.
+pub fn doctor(config: &Config) -> Result<(), anyhow::Error> {
+    let mut checks: Vec<DoctorCheck> = Vec::new();
+
+    if rustup_disabled() {
+        checks.push((
+            "rustup".to_string(),
+            Ok("not used, self-contained (WASIX_NO_RUSTUP is set)".to_string()),
+        ));
+    } else {
+        checks.push(tool_check("rustup", "it is needed to link the wasix toolchain, install it from https://rustup.rs or set WASIX_NO_RUSTUP=1"));
+    }
+
+    match installed_toolchain() {
+        Ok(Some(chain)) => {
+            checks.push((
+                "toolchain".to_string(),
+                Ok(format!("{} at {}", chain.name, chain.path.display())),
+            ));
+            checks.extend(toolchain_checks(&chain));
+        }
+        Ok(None) => checks.push((
+            "toolchain".to_string(),
+            Err((
+                "no wasix toolchain installed".to_string(),
+                "run `cargo wasix download-toolchain`, or `cargo wasix build-toolchain` on hosts without pre-built toolchains".to_string(),
+            )),
+        )),
+        Err(err) => checks.push((
+            "toolchain".to_string(),
+            Err((
+                format!("{err:#}"),
+                "check that `rustup toolchain list` works".to_string(),
+            )),
+        )),
+    }
+
+    checks.push(tool_check(
+        "git",
+        "`cargo wasix build-toolchain` clones the sources with it",
+    ));
+    checks.push(tool_check(
+        "curl",
+        "`cargo wasix build-toolchain` downloads LLVM with it",
+    ));
+    // A custom runner replaces wasmer.
+    if std::env::var_os("CARGO_TARGET_WASM32_WASMER_WASI_RUNNER").is_none() {
+        checks.push(tool_check("wasmer", "`cargo wasix run` and `cargo wasix test` run binaries with it, install it from https://wasmer.io or set CARGO_TARGET_WASM32_WASMER_WASI_RUNNER"));
+    }
+
+    let failed = checks.iter().filter(|(_, result)| result.is_err()).count();
+    for (name, result) in &checks {
+        match result {
+            Ok(found) => println!("  ok    {name}: {found}"),
+            Err((problem, hint)) => {
+                println!("  FAIL  {name}: {problem}");
+                println!("        hint: {hint}");
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("{failed} of {} checks failed", checks.len());
+    }
+    config.info(&format!("All {} checks passed", checks.len()));
+    Ok(())
+}
+
+/// Check that `tool` is on the `PATH`; `hint` says what it is needed for.
+fn tool_check(tool: &str, hint: &str) -> DoctorCheck {
+    let result = match which::which(tool) {
+        Ok(path) => Ok(path.display().to_string()),
+        Err(_) => Err((format!("{tool} not found on PATH"), hint.to_string())),
+    };
+    (tool.to_string(), result)
+}
+
+/// The sysroot and target checks of `ensure_toolchain` for `chain`.
+fn toolchain_checks(chain: &RustupToolchain) -> Vec<DoctorCheck> {
+    let mut checks = Vec::new();
+    let relink =
+        "run `cargo wasix toolchain relink`, or reinstall with `cargo wasix download-toolchain`";
+    let sysroot = match chain.rust_sysroot() {
+        Ok(sysroot) if sysroot == chain.path => Ok(sysroot.display().to_string()),
+        Ok(sysroot) => Err((
+            format!(
+                "rustc reports sysroot {}, expected {}",
+                sysroot.display(),
+                chain.path.display()
+            ),
+            relink.to_string(),
+        )),
+        Err(err) => Err((format!("{err:#}"), relink.to_string())),
+    };
+    checks.push(("rustc --print sysroot".to_string(), sysroot));
+    checks.extend(target_checks(&chain.path));
+    checks
+}
+
+/// Check that the toolchain at `sysroot` contains std for every wasix target.
+fn target_checks(sysroot: &Path) -> Vec<DoctorCheck> {
+    WASIX_TARGETS
+        .iter()
+        .map(|(target, bits)| {
+            let dir = sysroot.join("lib/rustlib").join(target);
+            let result = if dir.is_dir() {
+                Ok(dir.display().to_string())
+            } else {
+                Err((
+                    format!("{} does not exist", dir.display()),
+                    format!("reinstall with `cargo wasix download-toolchain`, or rebuild with WASIX_TARGET_BITS=both or {bits}"),
+                ))
+            };
+            (format!("target {target}"), result)
+        })
+        .collect()
+}
+
+/// Check the downloaded toolchain in use for damage.
+///
+/// Checks its layout and that the compiler and both libc sysroots are
+/// present. With `online`, the published archives of the installed release
+/// are downloaded again, checked against their published checksums, and
+/// every extracted file is compared against its copy in the archive.
+pub(crate) fn verify(config: &Config, online: bool) -> Result<(), anyhow::Error> {
+    let root = Config::toolchain_dir()?;
+    let chain = if rustup_available() {
+        RustupToolchain::find_by_name(RUSTUP_TOOLCHAIN_NAME)?
+            .context("The wasix toolchain is not installed")?
+    } else {
+        let host = guess_host_target()
+            .context("There are no pre-built toolchains for this platform to verify")?;
+        let (_, dir) = newest_installed(&root, host)?
+            .with_context(|| format!("No toolchain for {host} installed in {}", root.display()))?;
+        RustupToolchain::self_contained(&dir.join("rust"))
+    };
+    let dir = chain
+        .path
+        .parent()
+        .context("Invalid toolchain path")?
+        .to_path_buf();
+    if !dir.starts_with(&root) {
+        bail!(
+            "The wasix toolchain at {} was built locally; only downloaded toolchains can be verified",
+            dir.display()
+        );
+    }
+    let _lock = Config::acquire_lock()?;
+
+    let mut problems = Vec::new();
+    if let Err(err) = check_toolchain_layout(&dir) {
+        problems.push(err.to_string());
+    }
+    let rustc = dir.join("rust").join("bin").join("rustc");
+    if !rustc.is_file() {
+        problems.push(format!("{} does not exist", rustc.display()));
+    }
+    for bits in [32, 64] {
+        if let Err(err) =
+            check_sysroot_arch(&dir.join("sysroot").join(format!("sysroot{bits}")), bits)
+        {
+            problems.push(format!("{err:#}"));
+        }
+    }
+
+    if online && problems.is_empty() {
+        let meta = ToolchainMeta::load(&dir)?.context("Toolchain metadata is missing")?;
+        let options = DownloadOptions::from_env()?;
+        let client = http_client()?;
+        let release = fetch_release_by_tag(&client, &options, &meta.tag)?;
+
+        let mut sysroot = AssetSource::from_github(release.sysroot_asset()?);
+        // Fall back to the checksum recorded when the toolchain was installed.
+        if sysroot.sha256.is_none() {
+            sysroot.sha256 = meta.sysroot_sha256.clone();
+        }
+        let rust = AssetSource::from_github(release.rust_asset(&meta.target)?);
+        for (asset, local_dir, strip) in [
+            (sysroot, dir.join("sysroot"), Some("wasix-libc")),
+            (rust, dir.join("rust"), None),
+        ] {
+            if asset.sha256.is_none() {
+                warn(format!(
+                    "No checksum published for {}; comparing against the archive as served",
+                    asset.name
+                ));
+            }
+            config.status("Verifying", &asset.name);
+            let url = options.asset_url(&meta.tag, &asset.name, &asset.url);
+            let res =
+                authenticated_get(&client, &url, options.token(options.asset_mirror.is_some()))
+                    .send()?
+                    .error_for_status()?;
+            let found = compare_archive(res, asset.sha256.as_deref(), &local_dir, strip)
+                .with_context(|| format!("Could not verify against {url}"))?;
+            problems.extend(found);
+        }
+    }
+
+    if !problems.is_empty() {
+        bail!(
+            "The wasix toolchain at {} is damaged:\n  {}\nReinstall it with `cargo wasix download-toolchain`",
+            dir.display(),
+            problems.join("\n  ")
+        );
+    }
+
+    // Report the compiler and the cargo `cargo wasix` would run it with.
+    let rustc_version = chain.rustc().arg("--version").capture_stdout()?;
+    chain.activate()?;
+    let cargo_version = Command::new("cargo")
+        .arg("--version")
+        .capture_stdout()
+        .context("Could not execute cargo")?;
+    config.status("rustc", rustc_version.trim());
+    config.status("cargo", cargo_version.trim());
+    if let Some(problem) = cargo_compat_problem(&cargo_version, &rustc_version) {
+        warn(problem);
+    }
+
+    config.info(&format!(
+        "The wasix toolchain at {} is intact{}",
+        dir.display(),
+        if online {
+            " and matches the published release"
+        } else {
+            ""
+        }
+    ));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_checks() {
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir_all(dir.path().join("lib/rustlib/wasm32-wasmer-wasi")).unwrap();
+        let checks = target_checks(dir.path());
+        assert_eq!(checks.len(), 2);
+        assert_eq!(checks[0].0, "target wasm32-wasmer-wasi");
+        assert!(checks[0].1.is_ok());
+        let (problem, hint) = checks[1].1.as_ref().unwrap_err();
+        assert!(problem.contains("wasm64-wasmer-wasi does not exist"));
+        assert!(hint.contains("WASIX_TARGET_BITS=both or 64"));
+
+        let (name, result) = tool_check("cargo-wasix-no-such-tool", "for testing");
+        assert_eq!(name, "cargo-wasix-no-such-tool");
+        assert_eq!(result.unwrap_err().1, "for testing");
+    }
+}
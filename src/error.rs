@@ -0,0 +1,86 @@
+//! Failure kinds of installing, building and checking toolchains.
+//!
+//! Functions keep returning `anyhow::Error`, with a [`ToolchainError`] as the
+//! error or as context of the underlying cause, so callers can tell failures
+//! apart with `err.downcast_ref::<ToolchainError>()` instead of matching on
+//! messages.
+
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(Debug)]
+pub enum ToolchainError {
+    /// A required program is not installed or fails to run.
+    MissingBinary { name: String },
+    /// A download did not complete.
+    DownloadFailed { url: String },
+    /// A release does not publish an asset.
+    AssetNotFound { release: String, asset: String },
+    /// A release has no pre-built toolchain for the host.
+    NoPrebuiltToolchain { release: String, target: String },
+    /// A libc sysroot is missing, or lacks the listed files.
+    SysrootMissing {
+        path: PathBuf,
+        bits: u32,
+        missing: Vec<String>,
+    },
+    /// The toolchain has no std for a wasix target.
+    TargetMissing {
+        toolchain: String,
+        path: PathBuf,
+        target: String,
+    },
+    /// The toolchain could not be linked into rustup.
+    LinkFailed { name: String, path: PathBuf },
+}
+
+impl fmt::Display for ToolchainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingBinary { name } => write!(f, "Could not find or execute binary: {name}"),
+            Self::DownloadFailed { url } => write!(f, "Could not download {url}"),
+            Self::AssetNotFound { release, asset } => {
+                write!(f, "Release {release} does not have the {asset} asset")
+            }
+            Self::NoPrebuiltToolchain { release, target } => write!(
+                f,
+                "Release {release} does not have a prebuilt toolchain for host {target}"
+            ),
+            Self::SysrootMissing {
+                path,
+                bits,
+                missing,
+            } if missing.is_empty() => write!(
+                f,
+                "The wasm{bits} sysroot at {} was not found - rerun with WASIX_COMPONENTS=libc to build it",
+                path.display()
+            ),
+            Self::SysrootMissing {
+                path,
+                bits,
+                missing,
+            } => write!(
+                f,
+                "The wasm{bits} sysroot at {} is incomplete (missing {}) - rerun with WASIX_COMPONENTS=libc to rebuild it",
+                path.display(),
+                missing.join(", ")
+            ),
+            Self::TargetMissing {
+                toolchain,
+                path,
+                target,
+            } => write!(
+                f,
+                "Invalid wasix rustup toolchain {toolchain} at {}: lib/rustlib/{target} does not exist",
+                path.display()
+            ),
+            Self::LinkFailed { name, path } => write!(
+                f,
+                "Could not link toolchain {name} at {}: rustup not installed?",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ToolchainError {}
@@ -0,0 +1,188 @@
+//! Extraction of downloaded toolchain archives.
+//!
+//! Archives are unpacked entry by entry so that every path, including the
+//! targets of symlinks and hard links, can be checked to stay inside the
+//! destination directory.
+//!
+//! GNU sparse entries are written by seeking over their holes, so the
+//! extracted files stay sparse instead of being filled with zeros on disk.
+
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use tar::EntryType;
+
+/// Unpack `archive` into `dst`.
+///
+/// Symlinks and hard links are recreated as links rather than copied, and
+/// sparse files keep their holes.
+pub fn unpack<R: Read>(archive: &mut tar::Archive<R>, dst: &Path) -> Result<()> {
+    fs::create_dir_all(dst).with_context(|| format!("failed to create `{}`", dst.display()))?;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let rel = normalize(&path).with_context(|| {
+            format!("archive entry `{}` escapes the destination", path.display())
+        })?;
+
+        match entry.header().entry_type() {
+            EntryType::Symlink => {
+                let target = link_target(&entry, &path)?;
+                // Symlink targets are relative to the directory of the link.
+                let parent = rel.parent().unwrap_or_else(|| Path::new(""));
+                if target.has_root() || normalize(&parent.join(&target)).is_err() {
+                    bail!(
+                        "symlink `{}` points outside the destination: `{}`",
+                        path.display(),
+                        target.display()
+                    );
+                }
+            }
+            EntryType::Link => {
+                // Hard link targets are relative to the archive root.
+                let target = link_target(&entry, &path)?;
+                if normalize(&target).is_err() {
+                    bail!(
+                        "hard link `{}` points outside the destination: `{}`",
+                        path.display(),
+                        target.display()
+                    );
+                }
+            }
+            _ => {}
+        }
+
+        if !entry
+            .unpack_in(dst)
+            .with_context(|| format!("failed to unpack `{}`", path.display()))?
+        {
+            bail!("archive entry `{}` escapes the destination", path.display());
+        }
+    }
+    Ok(())
+}
+
+fn link_target<R: Read>(entry: &tar::Entry<'_, R>, path: &Path) -> Result<PathBuf> {
+    let target = entry
+        .link_name()?
+        .with_context(|| format!("link `{}` has no target", path.display()))?;
+    Ok(target.into_owned())
+}
+
+/// Lexically normalize a relative path, failing if it is absolute or climbs
+/// above its starting point.
+fn normalize(path: &Path) -> Result<PathBuf> {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => out.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !out.pop() {
+                    bail!("path `{}` leaves its root", path.display());
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => {
+                bail!("path `{}` is absolute", path.display())
+            }
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive(build: impl FnOnce(&mut tar::Builder<Vec<u8>>)) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        build(&mut builder);
+        builder.into_inner().unwrap()
+    }
+
+    fn link(builder: &mut tar::Builder<Vec<u8>>, kind: EntryType, path: &str, target: &str) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(kind);
+        header.set_size(0);
+        header.set_mode(0o777);
+        builder.append_link(&mut header, path, target).unwrap();
+    }
+
+    fn file(builder: &mut tar::Builder<Vec<u8>>, path: &str, data: &[u8]) {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o755);
+        builder.append_data(&mut header, path, data).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unpack_preserves_links() {
+        let data = archive(|b| {
+            file(b, "bin/rustc", b"rustc");
+            link(b, EntryType::Symlink, "bin/rustc-alias", "rustc");
+            link(b, EntryType::Link, "bin/rustc-hard", "bin/rustc");
+        });
+        let dir = tempfile::TempDir::new().unwrap();
+        unpack(&mut tar::Archive::new(&data[..]), dir.path()).unwrap();
+
+        let alias = dir.path().join("bin/rustc-alias");
+        assert!(fs::symlink_metadata(&alias)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read_link(&alias).unwrap(), Path::new("rustc"));
+        assert_eq!(fs::read(&alias).unwrap(), b"rustc");
+
+        use std::os::unix::fs::MetadataExt;
+        let hard = fs::symlink_metadata(dir.path().join("bin/rustc-hard")).unwrap();
+        let original = fs::metadata(dir.path().join("bin/rustc")).unwrap();
+        assert!(hard.file_type().is_file());
+        assert_eq!(hard.ino(), original.ino());
+    }
+
+    #[test]
+    fn test_unpack_sparse_file() {
+        const REAL_SIZE: u64 = 4 * 1024 * 1024;
+        const OFFSET: u64 = 1024 * 1024;
+        let data = archive(|b| {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(EntryType::GNUSparse);
+            header.set_size(4);
+            header.set_mode(0o644);
+            let gnu = header.as_gnu_mut().unwrap();
+            gnu.set_real_size(REAL_SIZE);
+            gnu.sparse[0].set_offset(OFFSET);
+            gnu.sparse[0].set_length(4);
+            // A trailing empty chunk marks the file as ending in a hole.
+            gnu.sparse[1].set_offset(REAL_SIZE);
+            gnu.sparse[1].set_length(0);
+            b.append_data(&mut header, "lib/sparse.rlib", &b"data"[..])
+                .unwrap();
+        });
+        let dir = tempfile::TempDir::new().unwrap();
+        unpack(&mut tar::Archive::new(&data[..]), dir.path()).unwrap();
+
+        let content = fs::read(dir.path().join("lib/sparse.rlib")).unwrap();
+        assert_eq!(content.len() as u64, REAL_SIZE);
+        let offset = OFFSET as usize;
+        assert!(content[..offset].iter().all(|b| *b == 0));
+        assert_eq!(&content[offset..offset + 4], b"data");
+        assert!(content[offset + 4..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_unpack_rejects_escaping_links() {
+        let dir = tempfile::TempDir::new().unwrap();
+
+        let data = archive(|b| link(b, EntryType::Symlink, "bin/evil", "../../etc/passwd"));
+        let err = unpack(&mut tar::Archive::new(&data[..]), dir.path()).unwrap_err();
+        assert!(err.to_string().contains("outside the destination"));
+
+        let data = archive(|b| link(b, EntryType::Link, "evil", "../secret"));
+        let err = unpack(&mut tar::Archive::new(&data[..]), dir.path()).unwrap_err();
+        assert!(err.to_string().contains("outside the destination"));
+    }
+}
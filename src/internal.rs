@@ -9,7 +9,7 @@ use std::thread;
 use std::time::{Duration, SystemTime};
 
 pub fn main(args: &[OsString], config: &Config) -> Result<()> {
-    match args.get(0).and_then(|s| s.to_str()) {
+    match args.first().and_then(|s| s.to_str()) {
         Some("clean") => clean(config),
         Some("update-check") => update_check(config),
         Some(other) => bail!("unsupported `self` command: {}", other),
@@ -3,6 +3,7 @@ use crate::config::Config;
 use crate::utils::CommandExt;
 use anyhow::{bail, Context, Result};
 use std::env;
+use std::ffi::OsString;
 use std::fs;
 use std::io;
 use std::io::Read;
@@ -11,12 +12,31 @@ use std::process::{Command, Stdio};
 use tool_path::ToolPath;
 
 mod cache;
+mod cargo_config;
 mod config;
+mod doctor;
+mod error;
+mod extract;
 mod internal;
+mod llvm;
+mod manager;
+mod manifest;
+mod meta;
+mod release;
+mod runtime;
+mod target_spec;
 mod tool_path;
 mod toolchain;
+mod uninstall;
 mod utils;
 
+pub use error::ToolchainError;
+pub use manager::ToolchainManager;
+pub use release::{download_release_asset, download_release_asset_cancellable};
+pub use release::{DownloadOptions, Retries, ToolchainPolicy};
+pub use toolchain::InstalledToolchain;
+pub use utils::CancelToken;
+
 pub fn main() {
     // See comments in `rmain` around `*_RUNNER` for why this exists here.
     if env::var("__CARGO_WASIX_RUNNER_SHIM").is_ok() {
@@ -29,7 +49,7 @@ pub fn main() {
     }
 
     let mut config = Config::new();
-    match rmain(&mut config) {
+    match rmain(&mut config).and_then(|()| config.check_warnings()) {
         Ok(()) => {}
         Err(e) => {
             config.print_error(&e);
@@ -49,6 +69,8 @@ enum Subcommand {
     Check,
     Tree,
     Fix,
+    /// Any other cargo subcommand, forwarded as is.
+    Other(String),
 }
 
 fn rmain(config: &mut Config) -> Result<()> {
@@ -57,8 +79,41 @@ fn rmain(config: &mut Config) -> Result<()> {
     // skip the current executable and the `wasix` inserted by Cargo
     let mut is64bit = false;
     let mut no_message_format = false;
-    let mut args = env::args_os().skip(2);
-    let subcommand = args.next().and_then(|s| s.into_string().ok());
+    let mut args = env::args_os().skip(2).collect::<Vec<_>>();
+    let subcommand = if args.is_empty() {
+        None
+    } else {
+        args.remove(0).into_string().ok()
+    };
+
+    // Global flags are applied before any subcommand runs, so they affect the
+    // output of the cargo-wasix subcommands too.
+    let color = take_flag_value(&mut args, "--color")?;
+    if let Some(when) = &color {
+        config.set_color(when)?;
+    }
+    if let Some(path) = take_flag_value(&mut args, "--log-file")? {
+        utils::set_log_file(Path::new(&path))?;
+    }
+    // `--quiet` and `--verbose` are cargo's, so they are only looked at.
+    let end = args.iter().position(|a| a == "--").unwrap_or(args.len());
+    if args[..end].iter().any(|a| a == "--quiet" || a == "-q") {
+        utils::set_quiet(true);
+    }
+    let verbosity = verbosity(&args);
+    if verbosity > 0 {
+        config.set_verbose(true);
+    }
+    utils::set_verbosity(verbosity);
+    utils::init_logger();
+    // The arguments of the cargo-wasix subcommands, without cargo's flags.
+    let own_args = args
+        .iter()
+        .enumerate()
+        .filter(|(i, a)| *i >= end || verbosity_flag(a).is_none() && *a != "--quiet" && *a != "-q")
+        .map(|(_, a)| a.clone())
+        .collect::<Vec<_>>();
+
     let subcommand = match subcommand.as_deref() {
         Some("build") => Subcommand::Build,
         Some("build64") => {
@@ -97,7 +152,48 @@ fn rmain(config: &mut Config) -> Result<()> {
             Subcommand::Tree
         }
         Some("fix") => Subcommand::Fix,
-        Some("self") => return internal::main(&args.collect::<Vec<_>>(), config),
+        Some("self") => return internal::main(&own_args, config),
+        Some("uninstall") => {
+            let mut all = false;
+            for arg in own_args {
+                match arg.to_str() {
+                    Some("--all") => all = true,
+                    _ => bail!("unsupported `uninstall` argument: {:?}", arg),
+                }
+            }
+            return uninstall::uninstall(config, all);
+        }
+        Some("uninstall-toolchain") => return uninstall::uninstall_toolchain(config, &own_args),
+        Some("setup-runner") => return setup_runner(config, own_args),
+        Some("list-toolchains") => {
+            if let Some(arg) = own_args.first() {
+                bail!("unsupported `list-toolchains` argument: {:?}", arg);
+            }
+            return toolchain::list_toolchains(config);
+        }
+        Some("doctor") => {
+            if let Some(arg) = own_args.first() {
+                bail!("unsupported `doctor` argument: {:?}", arg);
+            }
+            return doctor::doctor(config);
+        }
+        Some("targets") => {
+            if let Some(arg) = own_args.first() {
+                bail!("unsupported `targets` argument: {:?}", arg);
+            }
+            return toolchain::print_targets(config);
+        }
+        Some("toolchain") => return toolchain::main(&own_args, config),
+        Some("doc-std") => return toolchain::open_std_docs(config, false),
+        Some("doc-std64") => return toolchain::open_std_docs(config, true),
+        Some("cache") => {
+            return match own_args.first().and_then(|s| s.to_str()) {
+                Some("status") => cache::cache_status(config),
+                Some("prune") => cache::cache_prune(config),
+                Some(other) => bail!("unsupported `cache` command: {}", other),
+                None => bail!("`cache` command must be followed by `status` or `prune`"),
+            };
+        }
         Some("version") | Some("-V") | Some("--version") => {
             let git_info = match option_env!("GIT_INFO") {
                 Some(s) => format!(" ({})", s),
@@ -106,12 +202,115 @@ fn rmain(config: &mut Config) -> Result<()> {
             println!("cargo-wasix {}{}", env!("CARGO_PKG_VERSION"), git_info);
             std::process::exit(0);
         }
+        Some("help") | Some("-h") | Some("--help") | None => print_help(),
+        Some(other) if !other.starts_with('-') => {
+            // Not every cargo subcommand understands `--message-format`.
+            no_message_format = true;
+            Subcommand::Other(other.to_string())
+        }
         _ => print_help(),
     };
 
+    // `--color` is understood by cargo too, so it is passed on.
+    if let Some(when) = color {
+        args.insert(0, format!("--color={when}").into());
+    }
+    // `--offline` is cargo's, so it is only looked at.
+    let end = args.iter().position(|a| a == "--").unwrap_or(args.len());
+    let offline_flag = args[..end].iter().any(|a| a == "--offline");
+    if take_flag(&mut args, "--deny-warnings") {
+        config.set_deny_warnings(true);
+    }
+    let offline_build = take_flag(&mut args, "--offline-build");
+    // `cargo publish --dry-run` is cargo's.
+    let dry_run = match subcommand {
+        Subcommand::BuildToolchain | Subcommand::DownloadToolchain => {
+            take_flag(&mut args, "--dry-run")
+        }
+        _ => false,
+    };
+    utils::set_dry_run(dry_run);
+    let force_refresh_llvm = take_flag(&mut args, "--force-refresh-llvm");
+    let runtime_flag = take_flag_value(&mut args, "--runtime")?;
+    // Same as the `*64` subcommands, for scripts that pick the width.
+    if let Some(bits) = take_flag_value(&mut args, "--bits")? {
+        is64bit = match bits.as_str() {
+            "32" => false,
+            "64" => true,
+            other => bail!("invalid `--bits` value: {other} - expected `32` or `64`"),
+        };
+    }
+    // `--jobs` of the cargo subcommands is cargo's.
+    let build_jobs = match subcommand {
+        Subcommand::BuildToolchain => take_flag_value(&mut args, "--jobs")?,
+        _ => None,
+    };
+
+    let mut download_options = release::DownloadOptions::from_env()?;
+    if take_flag(&mut args, "--accept-prerelease") {
+        download_options.accept_prerelease = true;
+    }
+    if take_flag(&mut args, "--prefer-prebuilt") {
+        download_options.policy = release::ToolchainPolicy::PreferPrebuilt;
+    }
+    if take_flag(&mut args, "--prefer-build") {
+        download_options.policy = release::ToolchainPolicy::PreferBuild;
+    }
+    if let Some(tag) = take_flag_value(&mut args, "--toolchain-version")? {
+        download_options.version = Some(tag);
+    }
+    if let Some(dir) = take_flag_value(&mut args, "--save-assets")? {
+        download_options.save_assets = Some(PathBuf::from(dir));
+    }
+    if let Some(size) = take_flag_value(&mut args, "--max-download-size")? {
+        download_options.max_download_size = Some(
+            size.parse()
+                .with_context(|| format!("invalid `--max-download-size` value: {size}"))?,
+        );
+    }
+
+    // A custom target spec replaces the builtin wasix targets.
+    let mut target_spec = match take_flag_value(&mut args, "--target-spec")? {
+        Some(path) => Some(path),
+        None => env::var("WASIX_TARGET_SPEC").ok().filter(|v| !v.is_empty()),
+    }
+    .map(|path| target_spec::TargetSpec::load(Path::new(&path)))
+    .transpose()?;
+
+    // Cooperate with a wasm setup in the project's cargo config.
+    let cargo_config = cargo_config::CargoConfig::load(&env::current_dir()?)?;
+    if let (None, Some(configured)) = (&target_spec, cargo_config.build_target.as_deref()) {
+        let requested = if is64bit {
+            "wasm64-wasmer-wasi"
+        } else {
+            "wasm32-wasmer-wasi"
+        };
+        if configured.ends_with(".json") {
+            // A configured target spec is used like `--target-spec`.
+            let spec = target_spec::TargetSpec::load(Path::new(configured))?;
+            if spec.is64bit != is64bit {
+                utils::warn(format!(
+                    "The cargo config sets build.target to the wasm{} target spec {}, building for it instead of wasm{}",
+                    if spec.is64bit { 64 } else { 32 },
+                    spec.path.display(),
+                    if is64bit { 64 } else { 32 },
+                ));
+            }
+            target_spec = Some(spec);
+        } else if configured != requested {
+            utils::warn(format!(
+                "The cargo config sets build.target to {configured}, building for {requested} instead"
+            ));
+        }
+    }
+    if let Some(spec) = &target_spec {
+        is64bit = spec.is64bit;
+    }
+
+    // The toolchain is selected through the environment once it is known, see
+    // `RustupToolchain::activate`.
     let mut cargo = Command::new("cargo");
-    cargo.arg("+wasix");
-    cargo.arg(match subcommand {
+    cargo.arg(match &subcommand {
         Subcommand::Build => "build",
         Subcommand::BuildToolchain => "build-toolchain",
         Subcommand::DownloadToolchain => "download-toolchain",
@@ -121,28 +320,28 @@ fn rmain(config: &mut Config) -> Result<()> {
         Subcommand::Tree => "tree",
         Subcommand::Bench => "bench",
         Subcommand::Run => "run",
+        Subcommand::Other(name) => name.as_str(),
     });
 
     // TODO: figure out when these flags are already passed to `cargo` and skip
     // passing them ourselves.
-    let target = if is64bit {
+    let target = if let Some(spec) = &target_spec {
+        cargo.arg("--target").arg(&spec.path);
+        // There is no pre-built standard library for custom targets.
+        cargo.arg("-Zbuild-std=std,panic_abort");
+        spec.name.as_str()
+    } else if is64bit {
         "wasm64-wasmer-wasi"
     } else {
         "wasm32-wasmer-wasi"
     };
-    cargo.arg("--target").arg(target);
+    if target_spec.is_none() {
+        cargo.arg("--target").arg(target);
+    }
     if !no_message_format {
         cargo.arg("--message-format").arg("json-render-diagnostics");
     }
-    for arg in args {
-        if let Some(arg) = arg.to_str() {
-            if arg.starts_with("--verbose") || arg.starts_with("-v") {
-                config.set_verbose(true);
-            }
-        }
-
-        cargo.arg(arg);
-    }
+    cargo.args(args);
 
     let runner_env_var = format!(
         "CARGO_TARGET_{}_RUNNER",
@@ -162,17 +361,52 @@ fn rmain(config: &mut Config) -> Result<()> {
     // execute everything at the end.
     //
     // Also note that we check here before we actually build that a runtime is
-    // present. We first check the CARGO_TARGET_WASM32_WASIX_RUNNER environement
-    // variable for a user-supplied runtime (path or executable) and use the
-    // default, namely `wasmer`, if it is not set.
-    let (wasix_runner, using_default) = env::var(&runner_env_var)
-        .map(|runner_override| (runner_override, false))
-        .unwrap_or_else(|_| ("wasmer".to_string(), true));
+    // present. We first check the `--runtime` flag, then the
+    // CARGO_TARGET_WASM32_WASIX_RUNNER environement variable for a
+    // user-supplied runtime (path or executable), then WASIX_RUNTIME and the
+    // runner in the project's cargo config, and use the default, namely
+    // `wasmer`, if none is set.
+    let runner_spec = match runtime_flag {
+        Some(runtime) => Some((runtime, "`--runtime`".to_string())),
+        None => env::var(&runner_env_var)
+            .ok()
+            .map(|runner| (runner, format!("${runner_env_var}")))
+            .or_else(|| {
+                env::var("WASIX_RUNTIME")
+                    .ok()
+                    .filter(|v| !v.is_empty())
+                    .map(|runtime| (runtime, "$WASIX_RUNTIME".to_string()))
+            }),
+    };
+    let runner_override = match runner_spec {
+        Some((spec, source)) => Some((runtime::Runtime::parse(&spec)?, source)),
+        None => cargo_config
+            .runner(target)
+            .map(|runner| {
+                runtime::Runtime::from_cargo_runner(runner)
+                    .map(|runtime| (runtime, "the cargo config".to_string()))
+            })
+            .transpose()?,
+    };
+    let using_default = runner_override.is_none();
+    let (runtime, runner_source) =
+        runner_override.unwrap_or((runtime::Runtime::Wasmer, String::new()));
+    let wasix_runner = runtime.program().to_string();
 
     match subcommand {
+        Subcommand::DownloadToolchain if dry_run => {
+            return toolchain::plan_prebuilt_toolchain(
+                &download_options,
+                &Config::toolchain_dir()?,
+            );
+        }
         Subcommand::DownloadToolchain => {
             let _lock = Config::acquire_lock()?;
-            let chain = toolchain::install_prebuilt_toolchain(&Config::toolchain_dir()?)?;
+            let _sigint = utils::cancel_on_sigint(&download_options.cancel);
+            let chain = toolchain::install_prebuilt_toolchain(
+                &download_options,
+                &Config::toolchain_dir()?,
+            )?;
             config.info(&format!(
                 "Toolchain {} downloaded and installed to path {}.\nThe wasix toolchain is now ready to use.",
                 chain.name,
@@ -181,8 +415,23 @@ fn rmain(config: &mut Config) -> Result<()> {
             return Ok(());
         }
         Subcommand::BuildToolchain => {
-            let opts = toolchain::BuildToochainOptions::from_env()?;
-            toolchain::build_toolchain(opts)?;
+            let mut opts = toolchain::BuildToochainOptions::from_env()?;
+            if force_refresh_llvm {
+                opts.force_refresh_llvm = true;
+            }
+            if let Some(jobs) = &build_jobs {
+                opts.jobs = Some(toolchain::parse_jobs(jobs)?);
+            }
+            if offline_build {
+                opts.set_offline_build()?;
+            }
+            if let Some(out) = toolchain::build_toolchain(opts)? {
+                config.info(&format!(
+                    "Toolchain for host {} built at {}",
+                    out.target,
+                    out.toolchain_dir.display(),
+                ));
+            }
             return Ok(());
         }
         Subcommand::Run | Subcommand::Bench | Subcommand::Test => {
@@ -190,10 +439,9 @@ fn rmain(config: &mut Config) -> Result<()> {
                 // check if the override is either a valid path or command found on $PATH
                 if !(Path::new(&wasix_runner).exists() || which::which(&wasix_runner).is_ok()) {
                     bail!(
-                        "failed to find `{}` (specified by ${runner_env_var}) \
-                         on the filesytem or in $PATH, you'll want to fix the path or unset \
-                         the ${runner_env_var} environment variable before \
-                         running this command\n",
+                        "failed to find `{}` (specified by {runner_source}) \
+                         on the filesytem or in $PATH, you'll want to fix the path or change \
+                         {runner_source} before running this command\n",
                         &wasix_runner
                     );
                 }
@@ -213,27 +461,44 @@ fn rmain(config: &mut Config) -> Result<()> {
             cargo.env(runner_env_var, env::current_exe()?);
         }
 
-        Subcommand::Build | Subcommand::Check | Subcommand::Tree | Subcommand::Fix => {}
+        Subcommand::Build
+        | Subcommand::Check
+        | Subcommand::Tree
+        | Subcommand::Fix
+        | Subcommand::Other(_) => {}
     }
 
-    // Offline env var disables toolchain downloads and update checks.
+    // `--offline` or the offline env var disable toolchain downloads and
+    // update checks. The flag wins over an env var that says otherwise.
     let is_offline =
-        std::env::var("CARGO_WASIX_OFFLINE").map_or(false, |v| v == "1" || v == "true");
+        offline_flag || std::env::var("CARGO_WASIX_OFFLINE").is_ok_and(|v| v == "1" || v == "true");
 
     let update_check_opt = if is_offline {
         Some(internal::UpdateCheck::new(config))
     } else {
         None
     };
-    let toolchain = toolchain::ensure_toolchain(config, is64bit, is_offline)?;
+    let toolchain = {
+        let _sigint = utils::cancel_on_sigint(&download_options.cancel);
+        toolchain::ensure_toolchain(config, is64bit, is_offline, &download_options)?
+    };
 
-    std::env::set_var("RUSTUP_TOOLCHAIN", &toolchain.name);
+    toolchain.activate()?;
 
+    let threads = env::var("WASIX_LIBC_THREADS").is_ok_and(|v| v == "1" || v == "true");
     if let Ok(dir) = std::env::var("WASI_SDK_DIR") {
         config.verbose(|| config.status("WASI_SDK_DIR=", &dir));
-    } else if let Some(sysroot) = toolchain.sysroot_dir(is64bit) {
+    } else if let Some(sysroot) = toolchain.sysroot_dir(is64bit, threads) {
         std::env::set_var("WASI_SDK_DIR", &sysroot);
         config.verbose(|| config.status("WASI_SDK_DIR={}", &sysroot.display().to_string()));
+    } else if let Some(spec) = &target_spec {
+        bail!(
+            "Target spec {} needs a wasm{} libc sysroot, but the {} toolchain at {} has none",
+            spec.path.display(),
+            if is64bit { 64 } else { 32 },
+            toolchain.name,
+            toolchain.path.display(),
+        );
     }
 
     // Set some flags for rustc (only if RUSTFLAGS is not already set)
@@ -277,14 +542,8 @@ fn rmain(config: &mut Config) -> Result<()> {
 
     for run in build.runs.iter() {
         config.status("Running", &format!("`{}`", run.join(" ")));
-        let mut cmd = Command::new(&wasix_runner);
-
-        if wasix_runner == "wasmer" {
-            cmd.arg("--enable-threads");
-        }
-
-        cmd.arg("--")
-            .args(run.iter())
+        runtime
+            .command(run)
             .run()
             .map_err(|e| utils::hide_normal_process_exit(e, config))?;
     }
@@ -295,6 +554,107 @@ fn rmain(config: &mut Config) -> Result<()> {
     Ok(())
 }
 
+/// `cargo wasix setup-runner [--runtime <RUNTIME>]`
+///
+/// Configures the wasix targets in the project's `.cargo/config.toml`, so
+/// plain `cargo +wasix run` and `cargo +wasix test` run the wasm binaries.
+fn setup_runner(config: &Config, mut args: Vec<OsString>) -> Result<()> {
+    let runtime = match take_flag_value(&mut args, "--runtime")? {
+        Some(runtime) => runtime,
+        None => env::var("WASIX_RUNTIME")
+            .ok()
+            .filter(|v| !v.is_empty())
+            .unwrap_or_else(|| "wasmer".to_string()),
+    };
+    if let Some(arg) = args.first() {
+        bail!("unsupported `setup-runner` argument: {:?}", arg);
+    }
+    let runner = runtime::Runtime::parse(&runtime)?.cargo_runner()?;
+
+    let dir = env::current_dir()?.join(".cargo");
+    // Keep using a legacy `config` file if that is what the project has.
+    let path = match dir.join("config") {
+        legacy if legacy.is_file() && !dir.join("config.toml").exists() => legacy,
+        _ => dir.join("config.toml"),
+    };
+    let targets = toolchain::WASIX_TARGETS
+        .iter()
+        .map(|(target, _)| *target)
+        .collect::<Vec<_>>();
+    if cargo_config::setup_runner(&path, &targets, &runner, &["-C", "target-feature=+atomics"])? {
+        config.status(
+            "Configured",
+            &format!("runner `{}` in {}", runner.join(" "), path.display()),
+        );
+    } else {
+        config.info(&format!("{} is already set up", path.display()));
+    }
+    Ok(())
+}
+
+/// Removes a cargo-wasix specific boolean `--name` flag from `args`,
+/// returning whether it was present.
+///
+/// Arguments after `--` are left alone.
+/// The verbosity of `--verbose`/`-v` flags, which can be repeated like `-vv`.
+fn verbosity(args: &[OsString]) -> u8 {
+    args.iter().filter_map(verbosity_flag).sum()
+}
+
+fn verbosity_flag(arg: &OsString) -> Option<u8> {
+    let arg = arg.to_str()?;
+    if arg == "--verbose" {
+        return Some(1);
+    }
+    let vs = arg.strip_prefix('-').filter(|vs| !vs.is_empty())?;
+    vs.chars().all(|c| c == 'v').then_some(vs.len() as u8)
+}
+
+fn take_flag(args: &mut Vec<OsString>, name: &str) -> bool {
+    let end = args.iter().position(|a| a == "--").unwrap_or(args.len());
+    match args[..end].iter().position(|a| a == name) {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Removes a cargo-wasix specific `--name <value>` or `--name=<value>` flag
+/// from `args`, returning its value.
+///
+/// Arguments after `--` are left alone.
+fn take_flag_value(args: &mut Vec<OsString>, name: &str) -> Result<Option<String>> {
+    let prefix = format!("{name}=");
+    for i in 0..args.len() {
+        let arg = match args[i].to_str() {
+            Some(arg) => arg,
+            None => continue,
+        };
+        if arg == "--" {
+            break;
+        }
+        if let Some(value) = arg.strip_prefix(&prefix) {
+            let value = value.to_string();
+            args.remove(i);
+            return Ok(Some(value));
+        }
+        if arg == name {
+            if i + 1 >= args.len() {
+                bail!("`{name}` requires a value");
+            }
+            let value = args
+                .remove(i + 1)
+                .into_string()
+                .map_err(|v| anyhow::anyhow!("invalid `{name}` value: {v:?}"))?;
+            args.remove(i);
+            return Ok(Some(value));
+        }
+    }
+    Ok(None)
+}
+
 pub const HELP: &str = include_str!("txt/help.txt");
 
 fn print_help() -> ! {
@@ -457,6 +817,7 @@ fn run_wasm_opt(
 /// parsing that into a `CargoBuild`.
 fn execute_cargo(cargo: &mut Command, config: &Config) -> Result<CargoBuild> {
     config.verbose(|| config.status("Running", &format!("{:?}", cargo)));
+    utils::log_spawn(cargo);
     let mut process = cargo
         .stdout(Stdio::piped())
         .spawn()
@@ -0,0 +1,387 @@
+//! The LLVM that wasix-libc is built with.
+//!
+//! Each LLVM release is downloaded once into the `llvm-cache` of the
+//! cargo-wasix data dir and linked into the build dirs that use it.
+
+use std::{path::Path, process::Command};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    config::Config,
+    toolchain::{missing_library_hint, BuildToochainOptions},
+    utils::{warn, CommandExt},
+};
+use log::{debug, info};
+
+/// LLVM release used to build libc, unless `WASIX_LLVM_VERSION` is set.
+pub(crate) const LLVM_DEFAULT_VERSION: &str = "15.0.2";
+
+/// Download url of the Linux build of LLVM `version`.
+fn llvm_linux_source(version: &str) -> String {
+    format!("https://github.com/llvm/llvm-project/releases/download/llvmorg-{version}/clang+llvm-{version}-x86_64-unknown-linux-gnu-rhel86.tar.xz")
+}
+
+/// Download url of the macOS build of LLVM `version` for `arch`.
+fn llvm_macos_source(version: &str, arch: &str) -> String {
+    let platform = match arch {
+        "aarch64" => "arm64-apple-darwin21.0",
+        _ => "x86_64-apple-darwin",
+    };
+    format!("https://github.com/llvm/llvm-project/releases/download/llvmorg-{version}/clang+llvm-{version}-{platform}.tar.xz")
+}
+
+/// Download url of the LLVM `version` build for this host.
+pub(crate) fn llvm_source(version: &str) -> String {
+    if cfg!(target_os = "macos") {
+        llvm_macos_source(version, std::env::consts::ARCH)
+    } else {
+        llvm_linux_source(version)
+    }
+}
+
+/// Name of the build dir subdirectory LLVM `version` is installed to.
+///
+/// The default version keeps the name it always had, so existing installs
+/// stay valid.
+pub(crate) fn llvm_dir_name(version: &str) -> String {
+    if version == LLVM_DEFAULT_VERSION {
+        "llvm-15".to_string()
+    } else {
+        format!("llvm-{version}")
+    }
+}
+
+/// Validate a `WASIX_LLVM_VERSION` like `17.0.6`.
+pub(crate) fn parse_llvm_version(raw: &str) -> Result<String, anyhow::Error> {
+    let version = raw.trim().trim_start_matches("llvmorg-");
+    let valid = version
+        .split('.')
+        .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()));
+    if !valid {
+        bail!("Invalid env var WASIX_LLVM_VERSION with value '{raw}' - expected an LLVM release version like 15.0.2");
+    }
+    Ok(version.to_string())
+}
+
+/// Make sure a usable LLVM is at `options.llvm_dir`, linking it from the
+/// shared cache or downloading it if needed.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub(crate) fn ensure_llvm(options: &BuildToochainOptions) -> Result<(), anyhow::Error> {
+    info!("Ensuring LLVM...");
+    let llvm_dir = options.llvm_dir.clone();
+    // A shared, read-only LLVM (eg a CI cache volume) is only ever read from.
+    let read_only = llvm_dir_read_only(&llvm_dir)?;
+    let mut refresh = if options.force_refresh_llvm && read_only {
+        crate::utils::warn(format!(
+            "Not refreshing the read-only LLVM at {}",
+            llvm_dir.display()
+        ));
+        None
+    } else if options.force_refresh_llvm {
+        Some("a refresh was requested".to_string())
+    } else {
+        llvm_problem(&llvm_dir)
+    };
+    // Another build dir may have downloaded this LLVM version already.
+    if let (Some(cache), Some(_)) = (&options.llvm_cache, &refresh) {
+        if !read_only && !options.force_refresh_llvm && llvm_problem(cache).is_none() {
+            if clang_failure(cache).is_none() {
+                info!("Using the cached LLVM at {}", cache.display());
+                link_llvm(cache, &llvm_dir)?;
+                refresh = llvm_problem(&llvm_dir);
+            } else {
+                info!(
+                    "The cached LLVM at {} is broken, downloading it again",
+                    cache.display()
+                );
+            }
+        }
+    }
+    let mut downloaded = false;
+    loop {
+        if let Some(reason) = refresh.take() {
+            if read_only {
+                bail!(
+                    "The LLVM at {} can not be used ({reason}) and can not be replaced because the directory is read-only",
+                    llvm_dir.display()
+                );
+            }
+            if options.offline {
+                bail!(
+                    "The LLVM at {} can not be used ({reason}) and can not be downloaded in an offline build",
+                    llvm_dir.display()
+                );
+            }
+            if llvm_dir.exists() {
+                info!("Removing LLVM at {} ({reason})...", llvm_dir.display());
+                std::fs::remove_dir_all(&llvm_dir)
+                    .with_context(|| format!("Could not remove {}", llvm_dir.display()))?;
+            }
+            match &options.llvm_cache {
+                Some(cache) => {
+                    if cache.exists() {
+                        std::fs::remove_dir_all(cache)
+                            .with_context(|| format!("Could not remove {}", cache.display()))?;
+                    }
+                    download_llvm(cache, &options.llvm_url)?;
+                    link_llvm(cache, &llvm_dir)?;
+                }
+                None => download_llvm(&llvm_dir, &options.llvm_url)?,
+            }
+            downloaded = true;
+        }
+
+        // A clang that can't load its own libraries is retried once with a
+        // fresh download. Missing system libraries are reported by
+        // check_clang instead, since downloading again won't help.
+        if !downloaded {
+            if let Some(stderr) = clang_failure(&llvm_dir) {
+                if missing_library_hint(&stderr).is_none() {
+                    refresh = Some("clang failed to run".to_string());
+                    continue;
+                }
+            }
+        }
+        break;
+    }
+    check_clang(&llvm_dir, &options.llvm_version)
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn download_llvm(llvm_dir: &Path, url: &str) -> Result<(), anyhow::Error> {
+    info!("Downloading LLVM from {url}...");
+    std::fs::create_dir_all(llvm_dir)?;
+
+    let tmp_dir = tempfile::TempDir::new_in(Config::tmp_dir()?)
+        .context("Could not create temporary directory")?;
+    let archive_path = tmp_dir.path().join("llvm.tar.xz");
+
+    Command::new("curl")
+        .args(["-L", "-o"])
+        .arg(&archive_path)
+        .arg(url)
+        .run_verbose()?;
+
+    info!("Extracting LLVM...");
+    Command::new("tar")
+        .args(["xJf"])
+        .arg(&archive_path)
+        .arg("-C")
+        .arg(llvm_dir)
+        .args(["--strip-components", "1"])
+        .run_verbose()?;
+
+    drop(tmp_dir);
+
+    info!("Downloaded LLVM to {}", llvm_dir.display());
+    Ok(())
+}
+
+/// Point `llvm_dir` at the shared LLVM download in `cache`, replacing
+/// whatever is there.
+#[cfg(all(unix, any(target_os = "linux", target_os = "macos", test)))]
+fn link_llvm(cache: &Path, llvm_dir: &Path) -> Result<(), anyhow::Error> {
+    match std::fs::symlink_metadata(llvm_dir) {
+        Ok(meta) if meta.is_dir() => std::fs::remove_dir_all(llvm_dir),
+        Ok(_) => std::fs::remove_file(llvm_dir),
+        Err(_) => Ok(()),
+    }
+    .with_context(|| format!("Could not remove {}", llvm_dir.display()))?;
+    if let Some(parent) = llvm_dir.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::os::unix::fs::symlink(cache, llvm_dir).with_context(|| {
+        format!(
+            "Could not link {} to {}",
+            llvm_dir.display(),
+            cache.display()
+        )
+    })
+}
+
+/// Whether the LLVM at `llvm_dir` can only be read, failing if it exists but
+/// can't even be listed.
+#[cfg(any(target_os = "linux", target_os = "macos", test))]
+fn llvm_dir_read_only(llvm_dir: &Path) -> Result<bool, anyhow::Error> {
+    if !llvm_dir.exists() {
+        return Ok(false);
+    }
+    std::fs::read_dir(llvm_dir).with_context(|| {
+        format!(
+            "The LLVM directory {} is not readable - check WASIX_LLVM_DIR",
+            llvm_dir.display()
+        )
+    })?;
+    Ok(tempfile::tempfile_in(llvm_dir).is_err())
+}
+
+/// Files every usable LLVM install must contain.
+#[cfg(any(target_os = "linux", target_os = "macos", test))]
+const LLVM_REQUIRED_FILES: &[&str] = &["bin/clang", "bin/llvm-ar", "bin/llvm-nm", "lib/clang"];
+
+/// Why the LLVM at `llvm_dir` is missing or incomplete, if it is.
+#[cfg(any(target_os = "linux", target_os = "macos", test))]
+fn llvm_problem(llvm_dir: &Path) -> Option<String> {
+    if !llvm_dir.exists() {
+        return Some("not downloaded yet".to_string());
+    }
+    let missing = LLVM_REQUIRED_FILES
+        .iter()
+        .filter(|file| !llvm_dir.join(file).exists())
+        .copied()
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        None
+    } else {
+        Some(format!("incomplete, missing {}", missing.join(", ")))
+    }
+}
+
+/// Run `clang --version`, returning its stderr if it fails.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn clang_failure(llvm_dir: &Path) -> Option<String> {
+    match Command::new(llvm_dir.join("bin").join("clang"))
+        .arg("--version")
+        .output()
+    {
+        Ok(output) if output.status.success() => None,
+        Ok(output) => Some(String::from_utf8_lossy(&output.stderr).into_owned()),
+        Err(err) => Some(err.to_string()),
+    }
+}
+
+/// Sanity check that the downloaded clang can actually run on this system.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn check_clang(llvm_dir: &Path, expected_version: &str) -> Result<(), anyhow::Error> {
+    let clang = llvm_dir.join("bin").join("clang");
+    debug!("Running {} --version:", clang.display());
+    let output = Command::new(&clang)
+        .arg("--version")
+        .output()
+        .with_context(|| format!("Could not execute {}", clang.display()))?;
+    if output.status.success() {
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        debug!("{}", stdout.trim_end());
+        match clang_version(&stdout) {
+            Some(version) if version == expected_version => {
+                info!("Using clang {version}");
+            }
+            Some(version) => warn(format!(
+                "The clang at {} is version {version}, but LLVM {expected_version} was requested - set WASIX_FORCE_REFRESH_LLVM=1 to download it",
+                clang.display()
+            )),
+            None => warn(format!(
+                "Could not detect the version of the clang at {}",
+                clang.display()
+            )),
+        }
+        return Ok(());
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if let Some(hint) = missing_library_hint(&stderr) {
+        bail!(
+            "The LLVM clang at {} can not run on this system: {hint}",
+            clang.display()
+        );
+    }
+    bail!(
+        "The LLVM clang at {} failed to run ({}):\n{}",
+        clang.display(),
+        output.status,
+        stderr.trim()
+    )
+}
+
+/// The version from `clang --version` output like
+/// `clang version 15.0.2 (https://github.com/llvm/llvm-project ...)`.
+#[cfg(any(target_os = "linux", target_os = "macos", test))]
+fn clang_version(stdout: &str) -> Option<&str> {
+    let rest = &stdout[stdout.find("clang version ")? + "clang version ".len()..];
+    rest.split_whitespace().next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_link_llvm() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let cache = dir.path().join("llvm-cache").join("15.0.2");
+        std::fs::create_dir_all(cache.join("bin")).unwrap();
+        std::fs::write(cache.join("bin/clang"), "").unwrap();
+
+        // An incomplete download in the build dir is replaced.
+        let llvm = dir.path().join("build").join("llvm-15");
+        std::fs::create_dir_all(llvm.join("lib")).unwrap();
+        link_llvm(&cache, &llvm).unwrap();
+        assert_eq!(std::fs::read_link(&llvm).unwrap(), cache);
+        assert!(llvm.join("bin/clang").is_file());
+
+        // So is a dangling link.
+        let other = dir.path().join("llvm-cache").join("17.0.6");
+        std::fs::rename(&cache, &other).unwrap();
+        link_llvm(&other, &llvm).unwrap();
+        assert_eq!(std::fs::read_link(&llvm).unwrap(), other);
+        assert!(other.join("bin/clang").is_file());
+    }
+
+    #[test]
+    fn test_llvm_version() {
+        assert_eq!(parse_llvm_version(" 17.0.6 ").unwrap(), "17.0.6");
+        assert_eq!(parse_llvm_version("llvmorg-18.1.8").unwrap(), "18.1.8");
+        assert!(parse_llvm_version("latest").is_err());
+        assert!(parse_llvm_version("17..6").is_err());
+
+        assert_eq!(llvm_dir_name(LLVM_DEFAULT_VERSION), "llvm-15");
+        assert_eq!(llvm_dir_name("17.0.6"), "llvm-17.0.6");
+        assert!(llvm_linux_source("17.0.6").contains("llvmorg-17.0.6/clang+llvm-17.0.6-"));
+        assert!(llvm_macos_source("17.0.6", "aarch64")
+            .ends_with("/clang+llvm-17.0.6-arm64-apple-darwin21.0.tar.xz"));
+
+        let stdout = "clang version 15.0.2 (https://github.com/llvm/llvm-project 4bd3f3759259548e159aeba5c76efb9a0864e6fa)\nTarget: x86_64-unknown-linux-gnu\n";
+        assert_eq!(clang_version(stdout), Some("15.0.2"));
+        assert_eq!(
+            clang_version("Ubuntu clang version 14.0.0-1ubuntu1\n"),
+            Some("14.0.0-1ubuntu1")
+        );
+        assert_eq!(clang_version("gcc (GCC) 13.2.0"), None);
+    }
+
+    #[test]
+    fn test_llvm_problem() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let llvm = dir.path().join("llvm-15");
+        assert_eq!(llvm_problem(&llvm).unwrap(), "not downloaded yet");
+
+        std::fs::create_dir_all(llvm.join("bin")).unwrap();
+        std::fs::write(llvm.join("bin/clang"), "").unwrap();
+        assert_eq!(
+            llvm_problem(&llvm).unwrap(),
+            "incomplete, missing bin/llvm-ar, bin/llvm-nm, lib/clang"
+        );
+
+        std::fs::write(llvm.join("bin/llvm-ar"), "").unwrap();
+        std::fs::write(llvm.join("bin/llvm-nm"), "").unwrap();
+        std::fs::create_dir_all(llvm.join("lib/clang")).unwrap();
+        assert_eq!(llvm_problem(&llvm), None);
+    }
+
+    #[test]
+    fn test_llvm_dir_read_only() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let llvm = dir.path().join("llvm-15");
+        assert!(!llvm_dir_read_only(&llvm).unwrap());
+        std::fs::create_dir_all(&llvm).unwrap();
+        assert!(!llvm_dir_read_only(&llvm).unwrap());
+
+        // Something that exists but can't be listed is not usable at all.
+        let file = dir.path().join("llvm-file");
+        std::fs::write(&file, "").unwrap();
+        let err = llvm_dir_read_only(&file).unwrap_err();
+        assert!(err.to_string().contains("is not readable"));
+    }
+}
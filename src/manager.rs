@@ -0,0 +1,240 @@
+//! Toolchain installation as a library API, for tools that embed
+//! cargo-wasix.
+//!
+//! Unlike the `cargo wasix` subcommands, a [`ToolchainManager`] only reads
+//! the options it is given, returns what it did instead of printing it, and
+//! never touches rustup.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+
+use crate::config::Config;
+use crate::error::ToolchainError;
+use crate::meta::{check_toolchain_layout, ToolchainMeta};
+use crate::release::DownloadOptions;
+use crate::toolchain::{
+    guess_host_target, installed_toolchains, newest_installed, obtain_toolchain, InstalledToolchain,
+};
+use crate::utils::{set_log_hook, FileLock, LogHook};
+
+/// Installs, lists and removes the pre-built toolchains in one directory.
+pub struct ToolchainManager {
+    root: PathBuf,
+    options: DownloadOptions,
+    on_log: Option<LogHook>,
+}
+
+impl ToolchainManager {
+    /// A manager of the toolchains in `root`, with default download options.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            options: DownloadOptions::default(),
+            on_log: None,
+        }
+    }
+
+    /// A manager of the toolchains `cargo wasix` uses, configured by the
+    /// same env vars.
+    pub fn from_env() -> Result<Self> {
+        Ok(Self::new(Config::toolchain_dir()?).with_options(DownloadOptions::from_env()?))
+    }
+
+    pub fn with_options(mut self, options: DownloadOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Receive the log lines of all operations, instead of them going to
+    /// stderr.
+    ///
+    /// Installs the cargo-wasix logger at info level, unless the program
+    /// already set up a `log` logger, which then gets the messages instead.
+    pub fn on_log(mut self, f: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.on_log = Some(Arc::new(f));
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn options(&self) -> &DownloadOptions {
+        &self.options
+    }
+
+    /// The installed toolchains, sorted by target and tag.
+    pub fn list(&self) -> Result<Vec<InstalledToolchain>> {
+        installed_toolchains(&self.root)
+    }
+
+    /// Install the toolchain for host `target`, of release tag `version` or
+    /// the latest release.
+    pub fn install(&self, target: &str, version: Option<&str>) -> Result<InstalledToolchain> {
+        let _log = self.log_scope();
+        let _lock = self.lock()?;
+        let mut options = self.options.clone();
+        if let Some(version) = version {
+            options.version = Some(version.to_string());
+        }
+        let path = obtain_toolchain(&options, target, &self.root)?;
+        Self::describe(path)
+    }
+
+    /// Remove all installed toolchains with `tag`, returning what was
+    /// removed.
+    pub fn remove(&self, tag: &str) -> Result<Vec<InstalledToolchain>> {
+        let _log = self.log_scope();
+        let _lock = self.lock()?;
+        let remove: Vec<_> = self.list()?.into_iter().filter(|t| t.tag == tag).collect();
+        if remove.is_empty() {
+            bail!(
+                "No toolchain with tag {tag} installed in {}",
+                self.root.display()
+            );
+        }
+        for toolchain in &remove {
+            log::info!("Removing {} {}", toolchain.target, toolchain.tag);
+            std::fs::remove_dir_all(&toolchain.path)
+                .with_context(|| format!("Could not remove {}", toolchain.path.display()))?;
+        }
+        Ok(remove)
+    }
+
+    /// A toolchain for this host with std for the wasm{bits} wasix target,
+    /// installing one if none is.
+    ///
+    /// Uses the release of the `version` option if set, and the newest
+    /// installed toolchain otherwise.
+    pub fn ensure(&self, bits: u32) -> Result<InstalledToolchain> {
+        let target = match bits {
+            32 => "wasm32-wasmer-wasi",
+            64 => "wasm64-wasmer-wasi",
+            _ => bail!("Invalid target bits {bits} - expected 32 or 64"),
+        };
+        let host =
+            guess_host_target().context("There are no pre-built toolchains for this platform")?;
+        let _log = self.log_scope();
+        let _lock = self.lock()?;
+
+        let installed = match &self.options.version {
+            Some(tag) => self.list()?.into_iter().find(|t| {
+                t.target == host && &t.tag == tag && check_toolchain_layout(&t.path).is_ok()
+            }),
+            None => newest_installed(&self.root, host)?.map(|(meta, path)| InstalledToolchain {
+                target: meta.target,
+                tag: meta.tag,
+                path,
+            }),
+        };
+        let toolchain = match installed {
+            Some(toolchain) if has_target(&toolchain.path, target) => return Ok(toolchain),
+            _ => Self::describe(obtain_toolchain(&self.options, host, &self.root)?)?,
+        };
+        if !has_target(&toolchain.path, target) {
+            return Err(ToolchainError::TargetMissing {
+                toolchain: toolchain.tag,
+                path: toolchain.path.join("rust"),
+                target: target.to_string(),
+            }
+            .into());
+        }
+        Ok(toolchain)
+    }
+
+    /// The installed toolchain at `path`.
+    fn describe(path: PathBuf) -> Result<InstalledToolchain> {
+        let meta = ToolchainMeta::load(&path)?
+            .with_context(|| format!("{} is not an installed toolchain", path.display()))?;
+        Ok(InstalledToolchain {
+            target: meta.target,
+            tag: meta.tag,
+            path,
+        })
+    }
+
+    /// Serialize with other managers and `cargo wasix` invocations.
+    ///
+    /// The lock file sits next to the toolchains dir, which for the default
+    /// dir is the lock `cargo wasix` takes.
+    fn lock(&self) -> Result<FileLock> {
+        crate::utils::flock(&self.root.with_file_name("rustup-lock"))
+    }
+
+    /// Route log lines to the `on_log` callback until dropped.
+    fn log_scope(&self) -> LogScope {
+        if self.on_log.is_some() {
+            crate::utils::init_logger();
+        }
+        LogScope(
+            self.on_log
+                .as_ref()
+                .map(|hook| set_log_hook(Some(hook.clone()))),
+        )
+    }
+}
+
+fn has_target(toolchain_dir: &Path, target: &str) -> bool {
+    toolchain_dir.join("rust/lib/rustlib").join(target).is_dir()
+}
+
+/// Restores the previous log hook on drop.
+struct LogScope(Option<Option<LogHook>>);
+
+impl Drop for LogScope {
+    fn drop(&mut self) {
+        if let Some(previous) = self.0.take() {
+            set_log_hook(previous);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_toolchain_manager() {
+        let Some(host) = guess_host_target() else {
+            return;
+        };
+        let dir = tempfile::TempDir::new().unwrap();
+        let root = dir.path().join("toolchains");
+        let install = |tag: &str, target: &str| {
+            let path = root.join(format!("{host}_{tag}"));
+            std::fs::create_dir_all(path.join("rust/bin")).unwrap();
+            std::fs::create_dir_all(path.join("rust/lib/rustlib").join(target)).unwrap();
+            std::fs::write(path.join("rust/bin/rustc"), b"").unwrap();
+            ToolchainMeta::new(tag, host).save(&path).unwrap();
+            path
+        };
+        let old = install("v1", "wasm32-wasmer-wasi");
+        let new = install("v2", "wasm64-wasmer-wasi");
+
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let sink = lines.clone();
+        let mut options = DownloadOptions::default();
+        options.version = Some("v1".to_string());
+        let manager = ToolchainManager::new(&root)
+            .with_options(options)
+            .on_log(move |line| sink.lock().unwrap().push(line.to_string()));
+
+        let tags: Vec<_> = manager.list().unwrap().into_iter().map(|t| t.tag).collect();
+        assert_eq!(tags, ["v1", "v2"]);
+        assert_eq!(manager.ensure(32).unwrap().path, old);
+        assert!(manager.ensure(16).is_err());
+
+        let removed = manager.remove("v1").unwrap();
+        assert_eq!(removed.len(), 1);
+        assert!(!old.exists() && new.exists());
+        assert!(manager.remove("v1").is_err());
+        assert!(lines
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|line| line == &format!("Removing {host} v1")));
+    }
+}
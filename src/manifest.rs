@@ -0,0 +1,439 @@
+//! Toolchain manifests: a committed record of a release's assets and their
+//! checksums for one or more host targets.
+//!
+//! `cargo wasix toolchain export-manifest` writes a manifest for the latest
+//! release, and `cargo wasix toolchain install --from-manifest` installs every
+//! target it lists, verifying each archive against the recorded checksum.
+//! `cargo wasix toolchain verify-manifest --online` checks that the recorded
+//! assets can still be downloaded, without installing anything.
+//!
+//! Installs with `--save-assets <DIR>` keep the downloaded archives in `DIR`
+//! together with a manifest of them, which
+//! `cargo wasix toolchain install --from-archive <DIR>` installs from offline.
+
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::release::{self, AssetSource, DownloadOptions, GithubAsset};
+use crate::toolchain;
+
+/// Version of the manifest file format.
+const MANIFEST_VERSION: u32 = 1;
+
+/// Manifest `--save-assets` writes next to the saved archives.
+const SAVED_MANIFEST: &str = "wasix-toolchain.json";
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+pub struct ToolchainManifest {
+    pub version: u32,
+    /// Release tag the assets belong to.
+    pub tag: String,
+    /// The libc sysroot, shared by all hosts.
+    pub sysroot: ManifestAsset,
+    /// Rust toolchain asset by host target triple.
+    pub targets: BTreeMap<String, ManifestAsset>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+pub struct ManifestAsset {
+    pub name: String,
+    pub url: String,
+    pub sha256: String,
+    /// Size in bytes, missing in manifests exported by older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+}
+
+impl ManifestAsset {
+    fn source(&self) -> AssetSource {
+        AssetSource {
+            name: self.name.clone(),
+            url: self.url.clone(),
+            sha256: Some(self.sha256.clone()),
+        }
+    }
+}
+
+impl ToolchainManifest {
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read manifest {}", path.display()))?;
+        let manifest: Self = serde_json::from_str(&data)
+            .with_context(|| format!("Could not parse manifest {}", path.display()))?;
+        if manifest.version != MANIFEST_VERSION {
+            bail!(
+                "Manifest {} has unsupported version {} (expected {MANIFEST_VERSION})",
+                path.display(),
+                manifest.version
+            );
+        }
+        if manifest.targets.is_empty() {
+            bail!("Manifest {} does not list any targets", path.display());
+        }
+        Ok(manifest)
+    }
+}
+
+/// `cargo wasix toolchain export-manifest [--target <TRIPLE>]... [-o <FILE>]`
+pub fn export_main(args: &mut dyn Iterator<Item = &str>, config: &Config) -> Result<()> {
+    let mut targets = Vec::new();
+    let mut output = None;
+    while let Some(arg) = args.next() {
+        match arg {
+            "--target" => targets.push(flag_value(args, arg)?.to_string()),
+            "--output" | "-o" => output = Some(PathBuf::from(flag_value(args, arg)?)),
+            _ => bail!("unsupported `toolchain export-manifest` argument: {arg}"),
+        }
+    }
+    if targets.is_empty() {
+        targets.extend(toolchain::guess_host_target().map(str::to_string));
+    }
+    if targets.is_empty() {
+        bail!("Could not determine the host target, pass `--target`");
+    }
+    export_manifest(config, &targets, output.as_deref())
+}
+
+/// `cargo wasix toolchain verify-manifest <FILE> [--online]`
+pub fn verify_main(args: &mut dyn Iterator<Item = &str>, config: &Config) -> Result<()> {
+    let mut manifest = None;
+    let mut online = false;
+    for arg in args {
+        match arg {
+            "--online" => online = true,
+            _ if manifest.is_none() && !arg.starts_with('-') => manifest = Some(PathBuf::from(arg)),
+            _ => bail!("unsupported `toolchain verify-manifest` argument: {arg}"),
+        }
+    }
+    let manifest = manifest.context("`toolchain verify-manifest` requires a manifest file")?;
+    verify_manifest(config, &manifest, online)
+}
+
+/// `cargo wasix toolchain install --from-manifest <FILE>` or
+/// `cargo wasix toolchain install --from-archive <DIR>`
+pub fn install_main(args: &mut dyn Iterator<Item = &str>, config: &Config) -> Result<()> {
+    let mut manifest = None;
+    let mut archive = None;
+    while let Some(arg) = args.next() {
+        match arg {
+            "--from-manifest" => manifest = Some(PathBuf::from(flag_value(args, arg)?)),
+            "--from-archive" => archive = Some(PathBuf::from(flag_value(args, arg)?)),
+            _ => bail!("unsupported `toolchain install` argument: {arg}"),
+        }
+    }
+    match (manifest, archive) {
+        (Some(manifest), None) => install_from_manifest(config, &manifest),
+        (None, Some(dir)) => install_from_archive(config, &dir),
+        (Some(_), Some(_)) => {
+            bail!("`--from-manifest` and `--from-archive` can not be combined")
+        }
+        (None, None) => {
+            bail!("`toolchain install` requires `--from-manifest` or `--from-archive`")
+        }
+    }
+}
+
+pub(crate) fn flag_value<'a>(
+    args: &mut dyn Iterator<Item = &'a str>,
+    flag: &str,
+) -> Result<&'a str> {
+    args.next()
+        .with_context(|| format!("`{flag}` requires a value"))
+}
+
+fn export_manifest(config: &Config, targets: &[String], output: Option<&Path>) -> Result<()> {
+    let options = DownloadOptions::from_env()?;
+    let client = release::http_client()?;
+    let release = release::fetch_release(&client, &options)?;
+
+    let checksummed = |asset: &GithubAsset| -> Result<ManifestAsset> {
+        let sha256 = match asset.sha256() {
+            Some(sha256) => sha256.to_string(),
+            None => {
+                config.status("Hashing", &asset.name);
+                release::download_sha256(&client, &asset.browser_download_url, None)?
+            }
+        };
+        Ok(ManifestAsset {
+            name: asset.name.clone(),
+            url: asset.browser_download_url.clone(),
+            sha256,
+            size: Some(asset.size),
+        })
+    };
+
+    let mut manifest = ToolchainManifest {
+        version: MANIFEST_VERSION,
+        tag: release.tag_name.clone(),
+        sysroot: checksummed(release.sysroot_asset()?)?,
+        targets: BTreeMap::new(),
+    };
+    for target in targets {
+        let asset = checksummed(release.rust_asset(target)?)?;
+        manifest.targets.insert(target.clone(), asset);
+    }
+
+    let data = serde_json::to_string_pretty(&manifest)?;
+    match output {
+        Some(path) => {
+            std::fs::write(path, data + "\n")
+                .with_context(|| format!("Could not write {}", path.display()))?;
+            config.info(&format!(
+                "Wrote manifest for release {} to {}",
+                manifest.tag,
+                path.display()
+            ));
+        }
+        None => println!("{data}"),
+    }
+    Ok(())
+}
+
+fn verify_manifest(config: &Config, path: &Path, online: bool) -> Result<()> {
+    let manifest = ToolchainManifest::load(path)?;
+    if !online {
+        config.info(&format!(
+            "Manifest {} for release {} is valid; pass `--online` to check that its assets still resolve",
+            path.display(),
+            manifest.tag
+        ));
+        return Ok(());
+    }
+
+    let options = DownloadOptions::from_env()?;
+    let client = release::http_client()?;
+    let assets = std::iter::once(&manifest.sysroot).chain(manifest.targets.values());
+    let mut problems = Vec::new();
+    let mut count = 0;
+    for asset in assets {
+        count += 1;
+        config.status("Checking", &asset.name);
+        match release::probe_asset(&client, &options, &manifest.tag, &asset.source()) {
+            Ok(size) => problems.extend(size_problem(asset, size)),
+            Err(err) => problems.push(format!("{}: {err:#}", asset.name)),
+        }
+    }
+    if !problems.is_empty() {
+        bail!(
+            "{} of {count} asset(s) in {} did not verify:\n  {}",
+            problems.len(),
+            path.display(),
+            problems.join("\n  ")
+        );
+    }
+    config.info(&format!(
+        "All {count} asset(s) of release {} in {} still resolve",
+        manifest.tag,
+        path.display()
+    ));
+    Ok(())
+}
+
+/// Describes a mismatch between the recorded and the served size of `asset`.
+///
+/// Sizes unknown on either side are not compared.
+fn size_problem(asset: &ManifestAsset, served: Option<u64>) -> Option<String> {
+    match (asset.size, served) {
+        (Some(recorded), Some(served)) if recorded != served => Some(format!(
+            "{}: size changed from {recorded} to {served} bytes, the release may have been re-tagged",
+            asset.name
+        )),
+        _ => None,
+    }
+}
+
+/// Record the assets of `target` saved to `dir` in the manifest there,
+/// replacing a manifest for another release.
+pub(crate) fn record_saved_assets(
+    dir: &Path,
+    tag: &str,
+    target: &str,
+    sysroot: (&AssetSource, &str),
+    rust: (&AssetSource, &str),
+) -> Result<()> {
+    let saved = |(source, sha256): (&AssetSource, &str)| ManifestAsset {
+        name: source.name.clone(),
+        url: source.url.clone(),
+        sha256: sha256.to_string(),
+        size: std::fs::metadata(dir.join(&source.name))
+            .ok()
+            .map(|m| m.len()),
+    };
+    let path = dir.join(SAVED_MANIFEST);
+    let mut manifest = ToolchainManifest::load(&path)
+        .ok()
+        .filter(|manifest| manifest.tag == tag)
+        .unwrap_or_else(|| ToolchainManifest {
+            version: MANIFEST_VERSION,
+            tag: tag.to_string(),
+            sysroot: saved(sysroot),
+            targets: BTreeMap::new(),
+        });
+    manifest.sysroot = saved(sysroot);
+    manifest.targets.insert(target.to_string(), saved(rust));
+    let data = serde_json::to_string_pretty(&manifest)?;
+    std::fs::write(&path, data + "\n")
+        .with_context(|| format!("Could not write {}", path.display()))
+}
+
+fn install_from_archive(config: &Config, dir: &Path) -> Result<()> {
+    let path = dir.join(SAVED_MANIFEST);
+    if !path.is_file() {
+        bail!(
+            "{} does not contain assets saved with `--save-assets` ({} not found)",
+            dir.display(),
+            SAVED_MANIFEST
+        );
+    }
+    let mut manifest = ToolchainManifest::load(&path)?;
+    let dir = dir
+        .canonicalize()
+        .with_context(|| format!("Could not find {}", dir.display()))?;
+    let local = |asset: &mut ManifestAsset| {
+        asset.url = format!("file://{}", dir.join(&asset.name).display());
+    };
+    local(&mut manifest.sysroot);
+    manifest.targets.values_mut().for_each(local);
+    install_manifest(config, &manifest, &path)
+}
+
+fn install_from_manifest(config: &Config, path: &Path) -> Result<()> {
+    let manifest = ToolchainManifest::load(path)?;
+    install_manifest(config, &manifest, path)
+}
+
+fn install_manifest(config: &Config, manifest: &ToolchainManifest, path: &Path) -> Result<()> {
+    let options = DownloadOptions::from_env()?;
+    let client = release::http_client()?;
+    let root = Config::toolchain_dir()?;
+    let _lock = Config::acquire_lock()?;
+
+    let host = toolchain::guess_host_target();
+    let mut host_dir = None;
+    for (target, asset) in &manifest.targets {
+        config.status("Installing", &format!("{} ({target})", manifest.tag));
+        let dir = toolchain::install_release_assets(
+            &client,
+            &options,
+            &manifest.tag,
+            target,
+            &manifest.sysroot.source(),
+            &asset.source(),
+            &root,
+        )?;
+        if Some(target.as_str()) == host {
+            host_dir = Some(dir);
+        }
+    }
+
+    match host_dir {
+        Some(dir) => {
+            let chain = toolchain::activate_toolchain(&dir.join("rust"))?;
+            if chain.self_contained {
+                config.info(&format!(
+                    "Installed {} toolchain(s) from {}; rustup is not available, so {} is used self-contained",
+                    manifest.targets.len(),
+                    path.display(),
+                    chain.path.display()
+                ));
+            } else {
+                config.info(&format!(
+                    "Installed {} toolchain(s) from {}; {} is linked to {}",
+                    manifest.targets.len(),
+                    path.display(),
+                    chain.name,
+                    chain.path.display()
+                ));
+            }
+        }
+        None => config.info(&format!(
+            "Installed {} toolchain(s) from {}; none matches this host, so nothing was linked",
+            manifest.targets.len(),
+            path.display()
+        )),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_manifest() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("wasix-toolchain.json");
+        let asset = |name: &str| ManifestAsset {
+            name: name.to_string(),
+            url: format!("https://example.com/{name}"),
+            sha256: "ab".repeat(32),
+            size: Some(1024),
+        };
+        let mut manifest = ToolchainManifest {
+            version: MANIFEST_VERSION,
+            tag: "v2023-05-01".to_string(),
+            sysroot: asset("wasix-libc.tar.gz"),
+            targets: BTreeMap::new(),
+        };
+        manifest.targets.insert(
+            "x86_64-unknown-linux-gnu".to_string(),
+            asset("rust-toolchain-x86_64-unknown-linux-gnu.tar.gz"),
+        );
+        std::fs::write(&path, serde_json::to_string(&manifest).unwrap()).unwrap();
+        assert_eq!(ToolchainManifest::load(&path).unwrap(), manifest);
+
+        manifest.version += 1;
+        std::fs::write(&path, serde_json::to_string(&manifest).unwrap()).unwrap();
+        let err = ToolchainManifest::load(&path).unwrap_err();
+        assert!(err.to_string().contains("unsupported version"));
+    }
+
+    #[test]
+    fn test_record_saved_assets() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let source = |name: &str| AssetSource {
+            name: name.to_string(),
+            url: format!("https://example.com/{name}"),
+            sha256: None,
+        };
+        let sysroot = source("wasix-libc.tar.gz");
+        std::fs::write(dir.path().join(&sysroot.name), "libc").unwrap();
+        let linux = source("rust-toolchain-x86_64-unknown-linux-gnu.tar.gz");
+        let mac = source("rust-toolchain-aarch64-apple-darwin.tar.gz");
+        let (a, b) = ("aa".repeat(32), "bb".repeat(32));
+
+        let record = |tag: &str, target: &str, rust: &AssetSource| {
+            record_saved_assets(dir.path(), tag, target, (&sysroot, &a), (rust, &b)).unwrap();
+            ToolchainManifest::load(&dir.path().join(SAVED_MANIFEST)).unwrap()
+        };
+        record("v1", "x86_64-unknown-linux-gnu", &linux);
+        let manifest = record("v1", "aarch64-apple-darwin", &mac);
+        assert_eq!(manifest.targets.len(), 2);
+        assert_eq!(manifest.sysroot.size, Some(4));
+        assert_eq!(manifest.sysroot.url, sysroot.url);
+
+        // A different release starts over.
+        let manifest = record("v2", "aarch64-apple-darwin", &mac);
+        assert_eq!(manifest.tag, "v2");
+        assert_eq!(manifest.targets.len(), 1);
+    }
+
+    #[test]
+    fn test_size_problem() {
+        let mut asset = ManifestAsset {
+            name: "wasix-libc.tar.gz".to_string(),
+            url: "https://example.com/wasix-libc.tar.gz".to_string(),
+            sha256: "ab".repeat(32),
+            size: Some(1024),
+        };
+        assert_eq!(size_problem(&asset, Some(1024)), None);
+        assert_eq!(size_problem(&asset, None), None);
+        let problem = size_problem(&asset, Some(2048)).unwrap();
+        assert!(problem.contains("size changed from 1024 to 2048"));
+
+        asset.size = None;
+        assert_eq!(size_problem(&asset, Some(2048)), None);
+    }
+}
@@ -0,0 +1,188 @@
+//! The `toolchain-meta.json` recorded in every downloaded toolchain, and the
+//! layout version of the toolchain directories.
+
+use std::path::Path;
+
+use anyhow::{bail, Context};
+
+/// Version of the on-disk layout of downloaded toolchains.
+///
+/// Bump this whenever the directory structure or target names change in a way
+/// that makes toolchains installed by older cargo-wasix versions unusable.
+const TOOLCHAIN_LAYOUT_VERSION: u32 = 1;
+
+/// Name of the metadata file written into each downloaded toolchain dir.
+pub(crate) const TOOLCHAIN_META_FILE: &str = "toolchain-meta.json";
+
+/// Version of the [`ToolchainMeta`] schema.
+///
+/// Bump this when fields change meaning or are removed; files written before
+/// the version was recorded read as 0.
+const TOOLCHAIN_META_VERSION: u32 = 1;
+
+/// Metadata stored alongside a downloaded toolchain.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct ToolchainMeta {
+    /// Schema version of this file, see [`TOOLCHAIN_META_VERSION`].
+    #[serde(default)]
+    pub meta_version: u32,
+    pub layout_version: u32,
+    /// Version of cargo-wasix that installed the toolchain.
+    pub installed_by: String,
+    pub tag: String,
+    pub target: String,
+    /// Hex sha256 of the libc sysroot archive.
+    #[serde(default)]
+    pub sysroot_sha256: Option<String>,
+    /// Hex sha256 of the rust toolchain archive.
+    #[serde(default)]
+    pub rust_sha256: Option<String>,
+    /// Where the libc sysroot archive was downloaded from.
+    #[serde(default)]
+    pub sysroot_url: Option<String>,
+    /// Where the rust toolchain archive was downloaded from.
+    #[serde(default)]
+    pub rust_url: Option<String>,
+    /// When the toolchain was installed, as `YYYY-MM-DD HH:MM:SS` in UTC.
+    #[serde(default)]
+    pub installed_at: Option<String>,
+}
+
+impl ToolchainMeta {
+    pub(crate) fn new(tag: &str, target: &str) -> Self {
+        Self {
+            meta_version: TOOLCHAIN_META_VERSION,
+            layout_version: TOOLCHAIN_LAYOUT_VERSION,
+            installed_by: env!("CARGO_PKG_VERSION").to_string(),
+            tag: tag.to_string(),
+            target: target.to_string(),
+            sysroot_sha256: None,
+            rust_sha256: None,
+            sysroot_url: None,
+            rust_url: None,
+            installed_at: Some(crate::utils::format_utc(std::time::SystemTime::now())),
+        }
+    }
+
+    /// Read the metadata of the toolchain installed at `toolchain_dir`.
+    ///
+    /// Returns `None` if the toolchain has no metadata file.
+    pub fn load(toolchain_dir: &Path) -> Result<Option<Self>, anyhow::Error> {
+        let path = toolchain_dir.join(TOOLCHAIN_META_FILE);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let data = std::fs::read_to_string(&path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        let meta: Self = serde_json::from_str(&data)
+            .with_context(|| format!("Could not parse {}", path.display()))?;
+        if meta.meta_version > TOOLCHAIN_META_VERSION {
+            bail!(
+                "{} was written by a newer cargo-wasix ({}); please upgrade cargo-wasix",
+                path.display(),
+                meta.installed_by
+            );
+        }
+        Ok(Some(meta))
+    }
+
+    pub(crate) fn save(&self, toolchain_dir: &Path) -> Result<(), anyhow::Error> {
+        let path = toolchain_dir.join(TOOLCHAIN_META_FILE);
+        std::fs::write(&path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("Could not write {}", path.display()))
+    }
+}
+
+/// Describe how the sysroot changed compared to the `previous` install, if it
+/// is known to have changed.
+pub(crate) fn sysroot_change(previous: &ToolchainMeta, sysroot_sha256: &str) -> Option<String> {
+    let old = previous.sysroot_sha256.as_deref()?;
+    if old.eq_ignore_ascii_case(sysroot_sha256) {
+        return None;
+    }
+    let short = |sha: &str| sha.chars().take(12).collect::<String>();
+    Some(format!(
+        "the wasix-libc sysroot changed ({} {} -> {}); recompile native dependencies",
+        previous.tag,
+        short(old),
+        short(sysroot_sha256)
+    ))
+}
+
+/// Make sure the toolchain at `toolchain_dir` uses the layout this version of
+/// cargo-wasix expects.
+pub(crate) fn check_toolchain_layout(toolchain_dir: &Path) -> Result<(), anyhow::Error> {
+    match ToolchainMeta::load(toolchain_dir)? {
+        Some(meta) if meta.layout_version == TOOLCHAIN_LAYOUT_VERSION => Ok(()),
+        Some(meta) if meta.layout_version > TOOLCHAIN_LAYOUT_VERSION => bail!(
+            "The wasix toolchain at {} was installed by a newer cargo-wasix ({}); please upgrade cargo-wasix or reinstall the toolchain with `cargo wasix download-toolchain`",
+            toolchain_dir.display(),
+            meta.installed_by,
+        ),
+        _ => bail!(
+            "The wasix toolchain at {} was installed by an older cargo-wasix; please reinstall it with `cargo wasix download-toolchain`",
+            toolchain_dir.display(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_toolchain_layout() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let err = check_toolchain_layout(dir.path()).unwrap_err();
+        assert!(err.to_string().contains("older cargo-wasix"));
+
+        let mut meta = ToolchainMeta::new("v1", "x86_64-unknown-linux-gnu");
+        meta.save(dir.path()).unwrap();
+        check_toolchain_layout(dir.path()).unwrap();
+
+        meta.layout_version = TOOLCHAIN_LAYOUT_VERSION + 1;
+        meta.save(dir.path()).unwrap();
+        let err = check_toolchain_layout(dir.path()).unwrap_err();
+        assert!(err.to_string().contains("newer cargo-wasix"));
+    }
+
+    #[test]
+    fn test_toolchain_meta_versions() {
+        let dir = tempfile::TempDir::new().unwrap();
+        // Metadata from before the schema version was recorded.
+        std::fs::write(
+            dir.path().join(TOOLCHAIN_META_FILE),
+            r#"{"layout_version": 1, "installed_by": "0.1.0", "tag": "v1", "target": "x86_64-unknown-linux-gnu"}"#,
+        )
+        .unwrap();
+        let meta = ToolchainMeta::load(dir.path()).unwrap().unwrap();
+        assert_eq!(meta.meta_version, 0);
+        assert_eq!(meta.installed_at, None);
+
+        let mut meta = ToolchainMeta::new("v2", "x86_64-unknown-linux-gnu");
+        meta.rust_url = Some("https://example.com/rust.tar.gz".to_string());
+        meta.save(dir.path()).unwrap();
+        let loaded = ToolchainMeta::load(dir.path()).unwrap().unwrap();
+        assert_eq!(loaded.meta_version, TOOLCHAIN_META_VERSION);
+        assert_eq!(loaded.rust_url, meta.rust_url);
+        assert!(loaded.installed_at.is_some());
+
+        meta.meta_version = TOOLCHAIN_META_VERSION + 1;
+        meta.save(dir.path()).unwrap();
+        let err = ToolchainMeta::load(dir.path()).unwrap_err();
+        assert!(err.to_string().contains("newer cargo-wasix"), "{}", err);
+    }
+
+    #[test]
+    fn test_sysroot_change() {
+        let mut meta = ToolchainMeta::new("v1", "x86_64-unknown-linux-gnu");
+        assert_eq!(sysroot_change(&meta, "abc"), None);
+
+        meta.sysroot_sha256 = Some("0123456789abcdef".to_string());
+        assert_eq!(sysroot_change(&meta, "0123456789ABCDEF"), None);
+        assert_eq!(
+            sysroot_change(&meta, "fedcba9876543210").unwrap(),
+            "the wasix-libc sysroot changed (v1 0123456789ab -> fedcba987654); recompile native dependencies"
+        );
+    }
+}
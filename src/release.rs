@@ -0,0 +1,1851 @@
+//! Finding wasix toolchain releases and downloading their assets.
+//!
+//! Release info comes from the Github API, a Github compatible API or a
+//! `WASIX_RELEASE_API` mirror, and assets from Github or a
+//! `WASIX_ASSET_MIRROR`. Every asset is verified against its published
+//! checksum while it is written to disk.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    cache::{cached_download, remove_cached_download},
+    config::Config,
+    error::ToolchainError,
+    extract,
+    toolchain::RUST_REPO,
+    utils::{format_bytes, warn},
+};
+use log::{debug, info};
+
+/// How a missing toolchain is acquired, set with `WASIX_TOOLCHAIN_POLICY`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ToolchainPolicy {
+    /// Download a pre-built toolchain, building only if there is none for
+    /// this host.
+    #[default]
+    PreferPrebuilt,
+    /// Build locally where that is supported, otherwise download.
+    PreferBuild,
+    /// Download, never build.
+    PrebuiltOnly,
+    /// Build, never download.
+    BuildOnly,
+}
+
+impl ToolchainPolicy {
+    fn parse(raw: &str) -> Result<Self, anyhow::Error> {
+        match raw {
+            "prefer-prebuilt" => Ok(Self::PreferPrebuilt),
+            "prefer-build" => Ok(Self::PreferBuild),
+            "prebuilt-only" => Ok(Self::PrebuiltOnly),
+            "build-only" => Ok(Self::BuildOnly),
+            _ => bail!(
+                "Invalid env var WASIX_TOOLCHAIN_POLICY with value '{raw}' - expected prefer-prebuilt, prefer-build, prebuilt-only or build-only"
+            ),
+        }
+    }
+
+    /// Whether to build instead of download, given whether a pre-built
+    /// toolchain exists for the host and whether it can build locally.
+    pub(crate) fn should_build(self, prebuilt_available: bool, can_build: bool) -> bool {
+        match self {
+            Self::PreferPrebuilt => !prebuilt_available,
+            Self::PreferBuild => can_build || !prebuilt_available,
+            Self::PrebuiltOnly => false,
+            Self::BuildOnly => true,
+        }
+    }
+}
+
+/// How often failed downloads are retried, set with `WASIX_DOWNLOAD_RETRIES`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Retries {
+    /// Attempts after the first one.
+    pub retries: u32,
+    /// Delay before the first retry, doubled for every further one.
+    pub base_delay: std::time::Duration,
+}
+
+impl Default for Retries {
+    fn default() -> Self {
+        Self {
+            retries: 4,
+            base_delay: std::time::Duration::from_secs(1),
+        }
+    }
+}
+
+impl Retries {
+    /// Parse `<retries>` or `<retries>:<base delay>`, like `4` or `4:2s`.
+    ///
+    /// The delay is in seconds, or in milliseconds with a `ms` suffix.
+    fn parse(raw: &str) -> Result<Self, anyhow::Error> {
+        let invalid = || {
+            anyhow::anyhow!(
+                "Invalid env var WASIX_DOWNLOAD_RETRIES with value '{raw}' - expected a number of retries like 4, optionally with a base delay like 4:2s or 4:500ms"
+            )
+        };
+        let (retries, delay) = match raw.trim().split_once(':') {
+            Some((retries, delay)) => (retries, Some(delay)),
+            None => (raw.trim(), None),
+        };
+        let retries = retries.parse().map_err(|_| invalid())?;
+        let base_delay = match delay {
+            None => Self::default().base_delay,
+            Some(delay) => match delay.strip_suffix("ms") {
+                Some(ms) => std::time::Duration::from_millis(ms.parse().map_err(|_| invalid())?),
+                None => std::time::Duration::from_secs(
+                    delay
+                        .strip_suffix('s')
+                        .unwrap_or(delay)
+                        .parse()
+                        .map_err(|_| invalid())?,
+                ),
+            },
+        };
+        Ok(Self {
+            retries,
+            base_delay,
+        })
+    }
+
+    /// Delay before retry number `retry`, starting at 1.
+    fn delay(&self, retry: u32) -> std::time::Duration {
+        self.base_delay
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+    }
+
+    /// Run `attempt` until it succeeds, fails permanently or runs out of
+    /// retries. `what` names the download for messages.
+    pub(crate) fn run<T>(
+        &self,
+        what: &str,
+        mut attempt: impl FnMut() -> Result<T, anyhow::Error>,
+    ) -> Result<T, anyhow::Error> {
+        let mut retry = 0;
+        loop {
+            match attempt().map_err(|err| explain_timeout(err, what)) {
+                Ok(value) => return Ok(value),
+                Err(err) if retry < self.retries && is_transient(&err) => {
+                    retry += 1;
+                    let delay = self.delay(retry);
+                    info!(
+                        "Could not download {what} ({err:#}), retrying in {:.1}s ({retry}/{})...",
+                        delay.as_secs_f32(),
+                        self.retries
+                    );
+                    std::thread::sleep(delay);
+                }
+                Err(err) if retry > 0 => {
+                    return Err(err.context(format!(
+                        "Could not download {what} after {} attempts",
+                        retry + 1
+                    )))
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+/// Seconds without progress after which a download is abandoned, unless
+/// `WASIX_DOWNLOAD_TIMEOUT_SECS` says otherwise.
+const DEFAULT_DOWNLOAD_TIMEOUT_SECS: u64 = 30;
+
+/// The idle timeout of downloads, `None` if disabled with
+/// `WASIX_DOWNLOAD_TIMEOUT_SECS=0`.
+fn download_timeout() -> Result<Option<std::time::Duration>, anyhow::Error> {
+    match std::env::var("WASIX_DOWNLOAD_TIMEOUT_SECS") {
+        Ok(raw) if !raw.trim().is_empty() => parse_download_timeout(&raw),
+        _ => Ok(Some(std::time::Duration::from_secs(
+            DEFAULT_DOWNLOAD_TIMEOUT_SECS,
+        ))),
+    }
+}
+
+fn parse_download_timeout(raw: &str) -> Result<Option<std::time::Duration>, anyhow::Error> {
+    match raw.trim().parse::<u64>() {
+        Ok(0) => Ok(None),
+        Ok(secs) => Ok(Some(std::time::Duration::from_secs(secs))),
+        Err(_) => bail!(
+            "Invalid env var WASIX_DOWNLOAD_TIMEOUT_SECS with value '{raw}' - expected a number of seconds, or 0 for no timeout"
+        ),
+    }
+}
+
+/// Say how long a download that timed out waited.
+fn explain_timeout(err: anyhow::Error, what: &str) -> anyhow::Error {
+    let timed_out = err.chain().any(|cause| {
+        cause
+            .downcast_ref::<reqwest::Error>()
+            .is_some_and(|err| err.is_timeout())
+            || cause
+                .downcast_ref::<std::io::Error>()
+                .is_some_and(|err| err.kind() == std::io::ErrorKind::TimedOut)
+    });
+    match download_timeout() {
+        Ok(Some(timeout)) if timed_out => err.context(format!(
+            "Download of {what} timed out after {} seconds without data - set WASIX_DOWNLOAD_TIMEOUT_SECS to wait longer",
+            timeout.as_secs()
+        )),
+        _ => err,
+    }
+}
+
+/// Whether a download failed in a way that may go away on its own: network
+/// errors and `5xx` or `429` responses.
+fn is_transient(err: &anyhow::Error) -> bool {
+    fn transient_http(err: &reqwest::Error) -> bool {
+        match err.status() {
+            Some(status) => {
+                status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            }
+            None => !(err.is_decode() || err.is_builder() || err.is_redirect()),
+        }
+    }
+    err.chain().any(|cause| {
+        if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+            return transient_http(err);
+        }
+        // Errors while reading a response body.
+        if let Some(err) = cause.downcast_ref::<std::io::Error>() {
+            if let Some(err) = err
+                .get_ref()
+                .and_then(|e| e.downcast_ref::<reqwest::Error>())
+            {
+                return transient_http(err);
+            }
+            use std::io::ErrorKind::*;
+            return matches!(
+                err.kind(),
+                ConnectionReset | ConnectionAborted | UnexpectedEof | TimedOut
+            );
+        }
+        false
+    })
+}
+
+/// Options for downloading pre-built toolchains.
+#[derive(Clone, Debug, Default)]
+pub struct DownloadOptions {
+    /// Url of the release info endpoint, replacing the Github releases API.
+    pub(crate) release_api: Option<String>,
+    /// Base url of a Github compatible API serving the wasix releases, like
+    /// a Github Enterprise `https://ghe.example.com/api/v3`.
+    releases_base: Option<String>,
+    /// Git url of a fork of the wasix rust repo to get the releases of.
+    rust_repo: Option<String>,
+    /// Base url that release assets are downloaded from instead of Github.
+    ///
+    /// Assets are fetched from `{asset_mirror}/{tag}/{asset_name}`.
+    pub(crate) asset_mirror: Option<String>,
+    /// Bearer token sent to the release api and asset mirror.
+    mirror_token: Option<String>,
+    /// Refuse downloads larger than this many bytes in total.
+    pub max_download_size: Option<u64>,
+    /// Install the newest release even if it is a prerelease.
+    pub accept_prerelease: bool,
+    /// Umask applied to the whole extracted toolchain on unix.
+    pub install_umask: Option<u32>,
+    /// Name toolchain dirs by a hash of their asset checksums, not the tag.
+    pub content_addressed: bool,
+    /// Also save the downloaded archives to this directory.
+    pub save_assets: Option<PathBuf>,
+    /// Download or build a missing toolchain.
+    pub policy: ToolchainPolicy,
+    /// Retries of failed downloads.
+    pub retries: Retries,
+    /// Aborts downloads and extraction, removing the partial toolchain.
+    pub cancel: crate::utils::CancelToken,
+    /// Install this release tag instead of the latest release.
+    pub version: Option<String>,
+    /// Install from the release archives in this directory, without any
+    /// network access.
+    pub archive_dir: Option<PathBuf>,
+    /// File the latest release info is cached in.
+    releases_cache: Option<PathBuf>,
+    /// How long cached release info is used without asking the API again.
+    pub releases_cache_ttl: std::time::Duration,
+    /// Ignore the cached release info.
+    pub refresh_releases: bool,
+    /// Update the installed toolchain to the latest release, checking for
+    /// one at most this often.
+    pub auto_update: Option<std::time::Duration>,
+}
+
+impl DownloadOptions {
+    pub fn from_env() -> Result<Self, anyhow::Error> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        let max_download_size = var("WASIX_MAX_DOWNLOAD_SIZE")
+            .map(|v| {
+                v.parse().with_context(|| {
+                    format!("Invalid env var WASIX_MAX_DOWNLOAD_SIZE with value '{v}' - expected a number of bytes")
+                })
+            })
+            .transpose()?;
+        Ok(Self {
+            release_api: var("WASIX_RELEASE_API"),
+            releases_base: var("WASIX_RELEASES_BASE_URL"),
+            rust_repo: var("WASIX_RUST_REPO"),
+            asset_mirror: var("WASIX_ASSET_MIRROR"),
+            mirror_token: var("WASIX_MIRROR_TOKEN"),
+            max_download_size,
+            accept_prerelease: var("WASIX_ACCEPT_PRERELEASE")
+                .is_some_and(|v| v == "1" || v == "true"),
+            install_umask: var("WASIX_INSTALL_UMASK")
+                .map(|v| parse_umask(&v))
+                .transpose()?,
+            content_addressed: var("WASIX_CONTENT_ADDRESSED")
+                .is_some_and(|v| v == "1" || v == "true"),
+            save_assets: None,
+            policy: var("WASIX_TOOLCHAIN_POLICY")
+                .map(|v| ToolchainPolicy::parse(&v))
+                .transpose()?
+                .unwrap_or_default(),
+            retries: var("WASIX_DOWNLOAD_RETRIES")
+                .map(|v| Retries::parse(&v))
+                .transpose()?
+                .unwrap_or_default(),
+            cancel: Default::default(),
+            version: var("WASIX_TOOLCHAIN_VERSION"),
+            archive_dir: var("WASIX_TOOLCHAIN_ARCHIVE_DIR").map(PathBuf::from),
+            releases_cache: Config::toolchain_dir()
+                .ok()
+                .map(|dir| dir.join(RELEASES_CACHE_FILE)),
+            releases_cache_ttl: var("WASIX_RELEASES_CACHE_TTL")
+                .map(|v| {
+                    v.parse().map(std::time::Duration::from_secs).with_context(|| {
+                        format!("Invalid env var WASIX_RELEASES_CACHE_TTL with value '{v}' - expected a number of seconds")
+                    })
+                })
+                .transpose()?
+                .unwrap_or(DEFAULT_RELEASES_CACHE_TTL),
+            refresh_releases: var("WASIX_REFRESH_RELEASES").is_some_and(|v| v == "1" || v == "true"),
+            auto_update: if var("WASIX_AUTO_UPDATE").is_some_and(|v| v == "1" || v == "true") {
+                Some(
+                    var("WASIX_AUTO_UPDATE_INTERVAL")
+                        .map(|v| {
+                            v.parse().map(std::time::Duration::from_secs).with_context(|| {
+                                format!("Invalid env var WASIX_AUTO_UPDATE_INTERVAL with value '{v}' - expected a number of seconds")
+                            })
+                        })
+                        .transpose()?
+                        .unwrap_or(DEFAULT_AUTO_UPDATE_INTERVAL),
+                )
+            } else {
+                None
+            },
+        })
+    }
+
+    /// Url to fetch the release info from.
+    pub(crate) fn release_url(&self) -> String {
+        if let Some(url) = &self.release_api {
+            return url.clone();
+        }
+        format!("{}/latest", self.github_releases_url())
+    }
+
+    /// Url of the releases of the wasix rust repo, or the `WASIX_RUST_REPO`
+    /// fork, in the Github API or the `WASIX_RELEASES_BASE_URL` API.
+    fn github_releases_url(&self) -> String {
+        let repo = github_repo_path(self.rust_repo.as_deref().unwrap_or(RUST_REPO));
+        let base = self
+            .releases_base
+            .as_deref()
+            .unwrap_or("https://api.github.com")
+            .trim_end_matches('/');
+        format!("{base}/repos/{repo}/releases")
+    }
+
+    /// Url to download a release asset from.
+    ///
+    /// `url` is the asset's original download url.
+    pub(crate) fn asset_url(&self, tag: &str, name: &str, url: &str) -> String {
+        match &self.asset_mirror {
+            Some(base) => format!("{}/{}/{}", base.trim_end_matches('/'), tag, name),
+            None => url.to_string(),
+        }
+    }
+
+    /// Auth token for a request, depending on whether it goes to a mirror.
+    pub(crate) fn token(&self, mirrored: bool) -> Option<String> {
+        if mirrored {
+            self.mirror_token.clone()
+        } else {
+            // Use a GITHUB_TOKEN env var as auth token if present.
+            // Useful on Github Actions CI, since those IPs often get rate limited.
+            std::env::var("GITHUB_TOKEN").ok()
+        }
+    }
+}
+
+/// Parse an octal umask like `022` or `0o027`.
+fn parse_umask(raw: &str) -> Result<u32, anyhow::Error> {
+    let digits = raw.trim().trim_start_matches("0o");
+    match u32::from_str_radix(digits, 8) {
+        Ok(umask) if umask <= 0o777 => Ok(umask),
+        _ => bail!("Invalid env var WASIX_INSTALL_UMASK with value '{raw}' - expected an octal umask like 022"),
+    }
+}
+
+/// Start a GET request, authenticated with `token` if given.
+pub(crate) fn authenticated_get(
+    client: &reqwest::blocking::Client,
+    url: &str,
+    token: Option<String>,
+) -> reqwest::blocking::RequestBuilder {
+    let req = client.get(url);
+    match token {
+        Some(token) => req.bearer_auth(token),
+        None => req,
+    }
+}
+
+/// Fail on an error status of a releases API response.
+///
+/// Github answers an exhausted rate limit and a rejected `GITHUB_TOKEN` with
+/// 403 or 401, which are explained here. Responses of a `WASIX_RELEASE_API`
+/// override (`mirrored`) are only checked for their status.
+fn github_api_status(
+    res: reqwest::blocking::Response,
+    mirrored: bool,
+) -> Result<reqwest::blocking::Response, anyhow::Error> {
+    use reqwest::StatusCode;
+    let status = res.status();
+    if mirrored || !matches!(status, StatusCode::FORBIDDEN | StatusCode::UNAUTHORIZED) {
+        return Ok(res.error_for_status()?);
+    }
+    let header = |name: &str| {
+        res.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok())
+    };
+    let has_token = std::env::var_os("GITHUB_TOKEN").is_some();
+    if header("x-ratelimit-remaining") == Some(0) {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        bail!(rate_limit_message(
+            has_token,
+            header("x-ratelimit-reset"),
+            now
+        ));
+    }
+    if has_token {
+        bail!("The Github API rejected the request with {status} - the GITHUB_TOKEN env var is likely invalid or expired");
+    }
+    Ok(res.error_for_status()?)
+}
+
+/// Explain an exhausted Github API rate limit that resets at unix time `reset`.
+fn rate_limit_message(has_token: bool, reset: Option<u64>, now: u64) -> String {
+    let resets = match reset {
+        Some(reset) => {
+            let minutes = reset.saturating_sub(now).div_ceil(60);
+            format!("It resets in {minutes} minute(s), at unix time {reset}.")
+        }
+        None => "It resets within an hour.".to_string(),
+    };
+    if has_token {
+        format!("The Github API rate limit of the GITHUB_TOKEN is exhausted. {resets}")
+    } else {
+        format!("The Github API rate limit for unauthenticated requests is exhausted. {resets} Set the GITHUB_TOKEN env var to a Github access token for a higher limit, on Github Actions: `GITHUB_TOKEN: ${{{{ secrets.GITHUB_TOKEN }}}}`.")
+    }
+}
+
+/// Fail fast if `url` can't be reached, before starting a long download or
+/// build that needs it. Any HTTP response counts as reachable.
+///
+/// Uses `client`, so the same proxy and TLS settings apply as for the real
+/// requests. Skipped with `WASIX_NO_NETWORK_CHECK=1`.
+pub(crate) fn check_network(
+    client: &reqwest::blocking::Client,
+    url: &str,
+    offline_hint: &str,
+) -> Result<(), anyhow::Error> {
+    if std::env::var("WASIX_NO_NETWORK_CHECK").is_ok_and(|v| v == "1" || v == "true") {
+        return Ok(());
+    }
+    match client
+        .head(url)
+        .timeout(std::time::Duration::from_secs(10))
+        .send()
+    {
+        Ok(_) => Ok(()),
+        Err(err) => Err(anyhow::Error::new(err).context(format!(
+            "no network: could not reach {url}; use {offline_hint} or check your connection"
+        ))),
+    }
+}
+
+/// The `owner/repo` of a git url like `https://github.com/owner/repo.git` or
+/// `git@github.com:owner/repo.git`.
+fn github_repo_path(url: &str) -> String {
+    let path = url.trim_end_matches('/').trim_end_matches(".git");
+    let mut parts = path.rsplit(['/', ':']);
+    let repo = parts.next().unwrap_or_default();
+    let owner = parts.next().unwrap_or_default();
+    format!("{owner}/{repo}")
+}
+
+/// The `scheme://host[:port]` part of `url`.
+pub(crate) fn url_origin(url: &str) -> String {
+    match url.split_once("://") {
+        Some((scheme, rest)) => {
+            let host = rest.split('/').next().unwrap_or_default();
+            format!("{scheme}://{host}")
+        }
+        None => url.to_string(),
+    }
+}
+
+pub(crate) fn http_client() -> Result<reqwest::blocking::Client, anyhow::Error> {
+    let env = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+    // The blocking client applies the timeout to connecting, to receiving
+    // the response headers and to every read of the body, so it limits how
+    // long a download may stall rather than how long it may take.
+    let timeout = download_timeout()?;
+    let mut builder = reqwest::blocking::Client::builder()
+        .user_agent("cargo-wasix")
+        .timeout(timeout)
+        .connect_timeout(timeout);
+
+    // Proxies are set explicitly, so the env vars honored are the ones listed
+    // here and in the docs.
+    for (scheme, url) in proxy_urls(env) {
+        let proxy = match scheme {
+            "http" => reqwest::Proxy::http(&url),
+            _ => reqwest::Proxy::https(&url),
+        }
+        .with_context(|| format!("Invalid {scheme} proxy url '{url}'"))?;
+        builder = builder.proxy(proxy.no_proxy(reqwest::NoProxy::from_env()));
+    }
+
+    if let Some(path) = env("WASIX_CA_BUNDLE") {
+        for cert in load_ca_bundle(Path::new(&path))? {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+
+    if env("WASIX_INSECURE_TLS").is_some_and(|v| v == "1" || v == "true") {
+        static WARNED: std::sync::Once = std::sync::Once::new();
+        WARNED.call_once(|| {
+            warn("WASIX_INSECURE_TLS is set: TLS certificates are NOT verified, downloads can be tampered with!");
+        });
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    Ok(builder.build()?)
+}
+
+/// The http and https proxies configured by the `HTTP_PROXY` and
+/// `HTTPS_PROXY` env vars, or their lowercase variants, as looked up by
+/// `env`.
+fn proxy_urls(env: impl Fn(&str) -> Option<String>) -> Vec<(&'static str, String)> {
+    vec![
+        ("http", ["http_proxy", "HTTP_PROXY"]),
+        ("https", ["https_proxy", "HTTPS_PROXY"]),
+    ]
+    .into_iter()
+    .filter_map(|(scheme, vars)| Some((scheme, vars.iter().find_map(|var| env(var))?)))
+    .collect()
+}
+
+/// Read the extra CA certificates of `WASIX_CA_BUNDLE`.
+fn load_ca_bundle(path: &Path) -> Result<Vec<reqwest::Certificate>, anyhow::Error> {
+    let pem = std::fs::read(path)
+        .with_context(|| format!("Could not read the WASIX_CA_BUNDLE {}", path.display()))?;
+    let certs = reqwest::Certificate::from_pem_bundle(&pem)
+        .with_context(|| format!("Invalid certificates in WASIX_CA_BUNDLE {}", path.display()))?;
+    if certs.is_empty() {
+        bail!(
+            "WASIX_CA_BUNDLE {} contains no PEM certificates",
+            path.display()
+        );
+    }
+    Ok(certs)
+}
+
+/// Release returned by Github API.
+#[derive(serde::Deserialize)]
+pub(crate) struct GithubReleaseData {
+    pub assets: Vec<GithubAsset>,
+    pub tag_name: String,
+    #[serde(default)]
+    pub prerelease: bool,
+    #[serde(default)]
+    pub draft: bool,
+    /// RFC 3339 timestamp, absent for drafts.
+    #[serde(default)]
+    pub published_at: Option<String>,
+    /// Release notes.
+    #[serde(default)]
+    pub body: Option<String>,
+}
+
+/// The most recently published non-draft release, prereleases included.
+fn newest_release(releases: Vec<GithubReleaseData>) -> Option<GithubReleaseData> {
+    releases
+        .into_iter()
+        .filter(|release| !release.draft && release.published_at.is_some())
+        // RFC 3339 UTC timestamps sort chronologically as strings.
+        .max_by(|a, b| a.published_at.cmp(&b.published_at))
+}
+
+impl GithubReleaseData {
+    /// The Rust toolchain asset for host `target`.
+    pub fn rust_asset(&self, target: &str) -> Result<&GithubAsset, anyhow::Error> {
+        let name = format!("rust-toolchain-{target}.tar.gz");
+        self.assets
+            .iter()
+            .find(|asset| asset.name == name)
+            .with_context(|| ToolchainError::NoPrebuiltToolchain {
+                release: self.tag_name.clone(),
+                target: target.to_string(),
+            })
+    }
+
+    /// The libc sysroot asset.
+    pub fn sysroot_asset(&self) -> Result<&GithubAsset, anyhow::Error> {
+        self.assets
+            .iter()
+            .find(|asset| asset.name == "wasix-libc.tar.gz")
+            .with_context(|| ToolchainError::AssetNotFound {
+                release: self.tag_name.clone(),
+                asset: "wasix-libc.tar.gz".to_string(),
+            })
+    }
+
+    /// Host triples the release has a prebuilt Rust toolchain for.
+    fn host_targets(&self) -> Vec<&str> {
+        self.assets
+            .iter()
+            .filter_map(|asset| {
+                asset
+                    .name
+                    .strip_prefix("rust-toolchain-")?
+                    .strip_suffix(".tar.gz")
+            })
+            .collect()
+    }
+
+    /// Human readable summary for `toolchain info`.
+    pub(crate) fn describe(&self) -> String {
+        let mut out = format!("Release {}", self.tag_name);
+        if self.prerelease {
+            out.push_str(" (prerelease)");
+        }
+        out.push('\n');
+        if let Some(published) = &self.published_at {
+            out.push_str(&format!("Published: {published}\n"));
+        }
+        let hosts = self.host_targets();
+        if hosts.is_empty() {
+            out.push_str("Hosts: none\n");
+        } else {
+            out.push_str(&format!("Hosts: {}\n", hosts.join(", ")));
+        }
+        out.push_str("Assets:\n");
+        for asset in &self.assets {
+            out.push_str(&format!(
+                "  {:<50} {:>10}\n",
+                asset.name,
+                format_bytes(asset.size)
+            ));
+        }
+        if let Some(notes) = self
+            .body
+            .as_deref()
+            .map(str::trim)
+            .filter(|b| !b.is_empty())
+        {
+            out.push_str("\nRelease notes:\n");
+            for line in notes.lines() {
+                out.push_str(&format!("  {}\n", line.trim_end()));
+            }
+        }
+        out
+    }
+}
+
+/// Release asset returned by Github API.
+#[derive(serde::Deserialize)]
+pub(crate) struct GithubAsset {
+    pub browser_download_url: String,
+    pub name: String,
+    /// Size in bytes.
+    #[serde(default)]
+    pub size: u64,
+    /// Digest like `sha256:<hex>`, if Github computed one.
+    #[serde(default)]
+    pub digest: Option<String>,
+}
+
+impl GithubAsset {
+    /// The hex sha256 from the asset's digest, if available.
+    pub fn sha256(&self) -> Option<&str> {
+        self.digest.as_deref()?.strip_prefix("sha256:")
+    }
+}
+
+/// Fetch the info of the release to install from.
+pub(crate) fn fetch_release(
+    client: &reqwest::blocking::Client,
+    options: &DownloadOptions,
+) -> Result<GithubReleaseData, anyhow::Error> {
+    // `releases/latest` never returns prereleases, so look at all of them.
+    if options.accept_prerelease && options.release_api.is_none() {
+        let url = format!("{}?per_page=30", options.github_releases_url());
+        let releases: Vec<GithubReleaseData> = options
+            .retries
+            .run("the release list", || {
+                github_api_status(
+                    authenticated_get(client, &url, options.token(false)).send()?,
+                    false,
+                )
+            })
+            .context("Could not download release list")?
+            .json()
+            .context("Could not deserialize release list")?;
+        return newest_release(releases).context("No published releases found");
+    }
+
+    let release_url = options.release_url();
+    let token = options.token(options.release_api.is_some());
+    let now = unix_now();
+    let cached = match &options.releases_cache {
+        Some(path) if !options.refresh_releases => ReleaseCache::load(path, &release_url),
+        _ => None,
+    };
+    if let Some(cache) = &cached {
+        if now.saturating_sub(cache.fetched_at) < options.releases_cache_ttl.as_secs() {
+            debug!("Using the cached info of the latest release");
+            return cache.release();
+        }
+    }
+
+    let etag = cached.as_ref().and_then(|cache| cache.etag.clone());
+    let res = options
+        .retries
+        .run("the release info", || {
+            let mut req = authenticated_get(client, &release_url, token.clone());
+            // Revalidating doesn't count against the Github rate limit.
+            if let Some(etag) = &etag {
+                req = req.header(reqwest::header::IF_NONE_MATCH, etag);
+            }
+            github_api_status(req.send()?, options.release_api.is_some())
+        })
+        .context("Could not download release info")?;
+    let cache = match cached {
+        Some(mut cache) if res.status() == reqwest::StatusCode::NOT_MODIFIED => {
+            debug!("The cached info of the latest release is still current");
+            cache.fetched_at = now;
+            cache
+        }
+        _ => ReleaseCache {
+            url: release_url,
+            etag: res
+                .headers()
+                .get(reqwest::header::ETAG)
+                .and_then(|v| v.to_str().ok())
+                .map(String::from),
+            fetched_at: now,
+            body: res.text().context("Could not download release info")?,
+        },
+    };
+    let release = cache.release()?;
+    if let Some(path) = &options.releases_cache {
+        cache.save(path);
+    }
+    Ok(release)
+}
+
+/// Seconds since the unix epoch.
+pub(crate) fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// File in the toolchains dir that caches the latest release info.
+const RELEASES_CACHE_FILE: &str = ".releases-cache.json";
+
+/// Default of `WASIX_RELEASES_CACHE_TTL`.
+const DEFAULT_RELEASES_CACHE_TTL: std::time::Duration = std::time::Duration::from_secs(3600);
+
+/// Default of `WASIX_AUTO_UPDATE_INTERVAL`.
+const DEFAULT_AUTO_UPDATE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(24 * 3600);
+
+/// A cached response of the latest release endpoint.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ReleaseCache {
+    url: String,
+    etag: Option<String>,
+    /// Unix time the response was fetched or last revalidated.
+    fetched_at: u64,
+    body: String,
+}
+
+impl ReleaseCache {
+    /// The cached response of `url`, if any.
+    fn load(path: &Path, url: &str) -> Option<Self> {
+        let data = std::fs::read_to_string(path).ok()?;
+        serde_json::from_str::<Self>(&data)
+            .ok()
+            .filter(|cache| cache.url == url)
+    }
+
+    /// Best effort, the cache only saves requests.
+    fn save(&self, path: &Path) {
+        let written = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|()| std::fs::write(path, serde_json::to_vec(self).unwrap_or_default()));
+        if let Err(err) = written {
+            debug!(
+                "Could not cache the release info at {}: {err}",
+                path.display()
+            );
+        }
+    }
+
+    fn release(&self) -> Result<GithubReleaseData, anyhow::Error> {
+        serde_json::from_str(&self.body).context("Could not deserialize release info")
+    }
+}
+
+/// Fetch the info of release `tag`.
+///
+/// A `WASIX_RELEASE_API` override only serves a single release, which must be
+/// the requested one.
+pub(crate) fn fetch_release_by_tag(
+    client: &reqwest::blocking::Client,
+    options: &DownloadOptions,
+    tag: &str,
+) -> Result<GithubReleaseData, anyhow::Error> {
+    if options.release_api.is_some() {
+        let release = fetch_release(client, options)?;
+        if release.tag_name != tag {
+            bail!(
+                "The release API returned release {}, but {tag} was requested",
+                release.tag_name
+            );
+        }
+        return Ok(release);
+    }
+    let url = format!("{}/tags/{tag}", options.github_releases_url());
+    options
+        .retries
+        .run(&format!("the info of release {tag}"), || {
+            github_api_status(
+                authenticated_get(client, &url, options.token(false)).send()?,
+                false,
+            )
+        })
+        .with_context(|| format!("Could not download info of release {tag}"))?
+        .json()
+        .context("Could not deserialize release info")
+}
+
+/// Fetch the pinned release if a version is pinned, else the latest one.
+pub(crate) fn fetch_selected_release(
+    client: &reqwest::blocking::Client,
+    options: &DownloadOptions,
+) -> Result<GithubReleaseData, anyhow::Error> {
+    let Some(tag) = &options.version else {
+        return fetch_release(client, options);
+    };
+    let err = match fetch_release_by_tag(client, options, tag) {
+        Ok(release) => return Ok(release),
+        Err(err) => err,
+    };
+    let not_found = err.chain().any(|cause| {
+        cause
+            .downcast_ref::<reqwest::Error>()
+            .and_then(reqwest::Error::status)
+            == Some(reqwest::StatusCode::NOT_FOUND)
+    });
+    if !not_found || options.release_api.is_some() {
+        return Err(err);
+    }
+    let tags = fetch_release_tags(client, options)
+        .map(|tags| tags.join(", "))
+        .unwrap_or_else(|err| format!("(could not list releases: {err:#})"));
+    bail!("Toolchain release {tag} does not exist (WASIX_TOOLCHAIN_VERSION / --toolchain-version). Available releases: {tags}")
+}
+
+/// Tags of the most recent published releases, newest first.
+fn fetch_release_tags(
+    client: &reqwest::blocking::Client,
+    options: &DownloadOptions,
+) -> Result<Vec<String>, anyhow::Error> {
+    let url = format!("{}?per_page=100", options.github_releases_url());
+    let releases: Vec<GithubReleaseData> = options
+        .retries
+        .run("the release list", || {
+            github_api_status(
+                authenticated_get(client, &url, options.token(false)).send()?,
+                false,
+            )
+        })
+        .context("Could not download release list")?
+        .json()
+        .context("Could not deserialize release list")?;
+    Ok(releases.into_iter().map(|r| r.tag_name).collect())
+}
+
+/// A release asset to download, optionally with its expected checksum.
+pub(crate) struct AssetSource {
+    pub name: String,
+    pub url: String,
+    pub sha256: Option<String>,
+}
+
+impl AssetSource {
+    pub(crate) fn from_github(asset: &GithubAsset) -> Self {
+        Self {
+            name: asset.name.clone(),
+            url: asset.browser_download_url.clone(),
+            sha256: asset.sha256().map(str::to_string),
+        }
+    }
+}
+
+/// Reader that computes the sha256 of everything read through it.
+pub(crate) struct HashReader<R> {
+    pub(crate) inner: R,
+    pub(crate) hasher: sha2::Sha256,
+}
+
+impl<R: std::io::Read> std::io::Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        use sha2::Digest;
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<R: std::io::Read> HashReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        use sha2::Digest;
+        Self {
+            inner,
+            hasher: sha2::Sha256::new(),
+        }
+    }
+
+    /// Consume the rest of the stream and return the hex sha256.
+    pub(crate) fn finish(mut self) -> Result<String, anyhow::Error> {
+        use sha2::Digest;
+        std::io::copy(&mut self, &mut std::io::sink())?;
+        Ok(self
+            .hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect())
+    }
+}
+
+/// Download the asset at `url` and return its hex sha256.
+pub(crate) fn download_sha256(
+    client: &reqwest::blocking::Client,
+    url: &str,
+    token: Option<String>,
+) -> Result<String, anyhow::Error> {
+    let res = authenticated_get(client, url, token)
+        .send()?
+        .error_for_status()
+        .with_context(|| ToolchainError::DownloadFailed {
+            url: url.to_string(),
+        })?;
+    HashReader::new(res).finish()
+}
+
+/// [`download_release_asset`] for callers that already have the release
+/// info: download `asset` of release `tag` and verify it against its
+/// checksum, if known.
+///
+/// `.tar.gz` assets are unpacked into `dst`, anything else is saved as
+/// `dst/{name}`. Returns that path and the hex sha256 of the asset.
+pub(crate) fn download_release_asset_with(
+    client: &reqwest::blocking::Client,
+    options: &DownloadOptions,
+    tag: &str,
+    asset: &AssetSource,
+    dst: &Path,
+) -> Result<(PathBuf, String), anyhow::Error> {
+    let out = if asset.name.ends_with(".tar.gz") {
+        dst.to_path_buf()
+    } else {
+        dst.join(&asset.name)
+    };
+
+    // Assets saved with `--save-assets`, see `toolchain install --from-archive`.
+    if let Some(path) = asset.url.strip_prefix("file://") {
+        info!("Installing {} from {path}...", asset.name);
+        let file = std::fs::File::open(path).with_context(|| format!("Could not open {path}"))?;
+        let sha256 = store_asset(file, asset, dst, None, path)?;
+        return Ok((out, sha256));
+    }
+
+    let url = options.asset_url(tag, &asset.name, &asset.url);
+    let token = options.token(options.asset_mirror.is_some());
+    if !asset.name.ends_with(".tar.gz") {
+        info!("Downloading {} from url '{url}'...", asset.name);
+        let sha256 = options.retries.run(&asset.name, || {
+            let res = authenticated_get(client, &url, token.clone())
+                .send()?
+                .error_for_status()?;
+            let res = options.cancel.reader(res);
+            store_asset(res, asset, dst, options.save_assets.as_deref(), &url)
+        })?;
+        return Ok((out, sha256));
+    }
+
+    // Archives are downloaded completely before they are extracted, so an
+    // interrupted extraction can be redone without downloading again.
+    let cache = Config::cache_dir()?.join("downloads").join(tag);
+    let path = cached_download(client, &url, token, options, asset, &cache)?;
+    let file =
+        std::fs::File::open(&path).with_context(|| format!("Could not open {}", path.display()))?;
+    let file = options.cancel.reader(file);
+    let result = store_asset(file, asset, dst, options.save_assets.as_deref(), &url);
+    // Keep a verified archive around for a retry, but not one that may be
+    // what broke the extraction.
+    if result.is_ok() || asset.sha256.is_none() {
+        remove_cached_download(&path);
+    }
+    Ok((out, result?))
+}
+
+/// Unpack or save the contents of `asset` read from `reader`, see
+/// [`download_release_asset_with`], and copy them to `save_dir/{name}` if given.
+///
+/// `source` names where the asset is read from, for errors.
+fn store_asset<R: std::io::Read>(
+    reader: R,
+    asset: &AssetSource,
+    dst: &Path,
+    save_dir: Option<&Path>,
+    source: &str,
+) -> Result<String, anyhow::Error> {
+    let saved = save_dir.map(|dir| dir.join(&asset.name));
+    let copy = match (save_dir, &saved) {
+        (Some(dir), Some(path)) => {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Could not create directory {}", dir.display()))?;
+            let file = std::fs::File::create(path)
+                .with_context(|| format!("Could not create {}", path.display()))?;
+            Some(file)
+        }
+        _ => None,
+    };
+    let reader = TeeReader {
+        inner: reader,
+        copy,
+    };
+
+    let result = if asset.name.ends_with(".tar.gz") {
+        info!("Extracting...");
+        unpack_hashed(reader, asset.sha256.as_deref(), dst)
+            .with_context(|| format!("Invalid archive at {source}"))
+    } else {
+        save_hashed(reader, asset, dst, source)
+    };
+
+    if let Some(path) = saved {
+        match &result {
+            Ok(sha256) => {
+                let sums = path.with_file_name(format!("{}.sha256", asset.name));
+                std::fs::write(&sums, format!("{sha256}  {}\n", asset.name))
+                    .with_context(|| format!("Could not write {}", sums.display()))?;
+            }
+            Err(_) => drop(std::fs::remove_file(&path)),
+        }
+    }
+    result
+}
+
+/// Save a non-archive asset as `dst/{name}`, returning its hex sha256.
+fn save_hashed<R: std::io::Read>(
+    reader: R,
+    asset: &AssetSource,
+    dst: &Path,
+    source: &str,
+) -> Result<String, anyhow::Error> {
+    std::fs::create_dir_all(dst)
+        .with_context(|| format!("Could not create directory {}", dst.display()))?;
+    let path = dst.join(&asset.name);
+    let file = std::fs::File::create(&path)
+        .with_context(|| format!("Could not create {}", path.display()))?;
+    let mut reader = HashReader::new(reader);
+    std::io::copy(&mut reader, &mut std::io::BufWriter::new(file))
+        .with_context(|| format!("Could not download {source}"))?;
+    let actual = reader.finish()?;
+    if let Some(expected) = &asset.sha256 {
+        if !actual.eq_ignore_ascii_case(expected) {
+            std::fs::remove_file(&path).ok();
+            bail!("Checksum mismatch for {source}: expected sha256 {expected}, got {actual}");
+        }
+    }
+    Ok(actual)
+}
+
+/// Reader that also writes everything read through it to `copy`.
+struct TeeReader<R> {
+    inner: R,
+    copy: Option<std::fs::File>,
+}
+
+impl<R: std::io::Read> std::io::Read for TeeReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        use std::io::Write;
+        let n = self.inner.read(buf)?;
+        if let Some(copy) = &mut self.copy {
+            copy.write_all(&buf[..n])?;
+        }
+        Ok(n)
+    }
+}
+
+/// Check that `asset` can still be downloaded, with a HEAD request.
+///
+/// Returns the size reported by the server, if any.
+pub(crate) fn probe_asset(
+    client: &reqwest::blocking::Client,
+    options: &DownloadOptions,
+    tag: &str,
+    asset: &AssetSource,
+) -> Result<Option<u64>, anyhow::Error> {
+    let url = options.asset_url(tag, &asset.name, &asset.url);
+    let mut req = client.head(&url);
+    if let Some(token) = options.token(options.asset_mirror.is_some()) {
+        req = req.bearer_auth(token);
+    }
+    let res = req
+        .send()
+        .with_context(|| format!("Could not reach {url}"))?
+        .error_for_status()
+        .with_context(|| format!("{url} does not resolve"))?;
+    // `Response::content_length` is the (empty) body size for HEAD requests.
+    Ok(res
+        .headers()
+        .get(reqwest::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse().ok()))
+}
+
+/// Download the asset `asset_name` of release `release_tag` into `dest`.
+///
+/// Archives (`.tar.gz`) are extracted into `dest` and `dest` is returned,
+/// other assets are saved as `dest/{asset_name}` and that path is returned.
+/// The download is verified against the checksum published with the release,
+/// if there is one.
+pub fn download_release_asset(
+    release_tag: &str,
+    asset_name: &str,
+    dest: &Path,
+) -> Result<PathBuf, anyhow::Error> {
+    download_release_asset_cancellable(release_tag, asset_name, dest, &Default::default())
+}
+
+/// Like [`download_release_asset`], but aborts once `cancel` is cancelled.
+pub fn download_release_asset_cancellable(
+    release_tag: &str,
+    asset_name: &str,
+    dest: &Path,
+    cancel: &crate::utils::CancelToken,
+) -> Result<PathBuf, anyhow::Error> {
+    let mut options = DownloadOptions::from_env()?;
+    options.cancel = cancel.clone();
+    let client = http_client()?;
+    let release = fetch_release_by_tag(&client, &options, release_tag)?;
+    let asset = release
+        .assets
+        .iter()
+        .find(|asset| asset.name == asset_name)
+        .with_context(|| {
+            format!(
+                "Release {} does not have an asset named {asset_name}",
+                release.tag_name
+            )
+        })?;
+    let source = checked_source(&client, &options, &release, asset)?;
+    let (path, _) =
+        download_release_asset_with(&client, &options, &release.tag_name, &source, dest)?;
+    Ok(path)
+}
+
+/// Unpack a `.tar.gz` stream into `dst`, hashing the compressed bytes as they
+/// stream through the decoder instead of re-reading them afterwards.
+///
+/// Returns the hex sha256 of the stream, and fails (removing `dst`) if it
+/// doesn't match `expected_sha256`.
+fn unpack_hashed<R: std::io::Read>(
+    reader: R,
+    expected_sha256: Option<&str>,
+    dst: &Path,
+) -> Result<String, anyhow::Error> {
+    let mut reader = HashReader::new(reader);
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(&mut reader));
+    extract::unpack(&mut archive, dst)?;
+    drop(archive);
+
+    let actual = reader.finish()?;
+    if let Some(expected) = expected_sha256 {
+        if !actual.eq_ignore_ascii_case(expected) {
+            std::fs::remove_dir_all(dst).ok();
+            bail!("Checksum mismatch: expected sha256 {expected}, got {actual}");
+        }
+    }
+    Ok(actual)
+}
+
+/// Compare the files in a `.tar.gz` stream against their extracted copies
+/// below `local_dir`, with a leading `strip` directory removed from the entry
+/// paths.
+///
+/// Fails if the stream doesn't match `expected_sha256`, and otherwise returns
+/// a description of every file that is missing or differs locally.
+pub(crate) fn compare_archive<R: std::io::Read>(
+    reader: R,
+    expected_sha256: Option<&str>,
+    local_dir: &Path,
+    strip: Option<&str>,
+) -> Result<Vec<String>, anyhow::Error> {
+    let mut reader = HashReader::new(reader);
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(&mut reader));
+    let mut problems = Vec::new();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let kind = entry.header().entry_type();
+        if !kind.is_file() && !kind.is_gnu_sparse() {
+            continue;
+        }
+        let path = entry.path()?.into_owned();
+        let rel = match strip {
+            Some(prefix) => path.strip_prefix(prefix).unwrap_or(&path).to_path_buf(),
+            None => path,
+        };
+        let archived = HashReader::new(entry).finish()?;
+        let local_path = local_dir.join(&rel);
+        match std::fs::File::open(&local_path) {
+            Ok(file) => {
+                if HashReader::new(file).finish()? != archived {
+                    problems.push(format!("{}: content differs", local_path.display()));
+                }
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                problems.push(format!("{}: missing", local_path.display()));
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("Could not read {}", local_path.display()))
+            }
+        }
+    }
+    drop(archive);
+
+    let actual = reader.finish()?;
+    if let Some(expected) = expected_sha256 {
+        if !actual.eq_ignore_ascii_case(expected) {
+            bail!("Checksum mismatch: expected sha256 {expected}, got {actual}");
+        }
+    }
+    Ok(problems)
+}
+
+/// The download source of `asset`, with its checksum from the Github digest
+/// or a checksum asset published with the release.
+///
+/// Assets without any known checksum are installed unverified, with a
+/// warning.
+pub(crate) fn checked_source(
+    client: &reqwest::blocking::Client,
+    options: &DownloadOptions,
+    release: &GithubReleaseData,
+    asset: &GithubAsset,
+) -> Result<AssetSource, anyhow::Error> {
+    let mut source = AssetSource::from_github(asset);
+    if source.sha256.is_none() {
+        source.sha256 = published_checksum(client, options, release, &asset.name)?;
+    }
+    if source.sha256.is_none() {
+        warn(format!(
+            "Release {} publishes no checksum for {}, it can not be verified",
+            release.tag_name, asset.name
+        ));
+    }
+    Ok(source)
+}
+
+/// Checksum of asset `name` from a `{name}.sha256` companion asset, or else
+/// from a `checksums.txt` asset of the release.
+fn published_checksum(
+    client: &reqwest::blocking::Client,
+    options: &DownloadOptions,
+    release: &GithubReleaseData,
+    name: &str,
+) -> Result<Option<String>, anyhow::Error> {
+    let companion = format!("{name}.sha256");
+    let Some(sums) = [companion.as_str(), "checksums.txt"]
+        .iter()
+        .find_map(|sums| release.assets.iter().find(|asset| asset.name == *sums))
+    else {
+        return Ok(None);
+    };
+    let url = options.asset_url(&release.tag_name, &sums.name, &sums.browser_download_url);
+    let token = options.token(options.asset_mirror.is_some());
+    let text = options
+        .retries
+        .run(&sums.name, || {
+            Ok(authenticated_get(client, &url, token.clone())
+                .send()?
+                .error_for_status()?
+                .text()?)
+        })
+        .with_context(|| format!("Could not download checksum asset {}", sums.name))?;
+    let sha256 = parse_checksums(&text, name);
+    if sha256.is_none() {
+        bail!("Checksum asset {} has no sha256 for {name}", sums.name);
+    }
+    Ok(sha256)
+}
+
+/// Find the sha256 of `name` in `sha256sum` style output.
+///
+/// A line holding only a hash, as in single-file companion assets, matches
+/// any name.
+fn parse_checksums(text: &str, name: &str) -> Option<String> {
+    text.lines().find_map(|line| {
+        let mut parts = line.split_whitespace();
+        let hash = parts.next()?;
+        let matches = match parts.next() {
+            // `*` marks binary mode.
+            Some(file) => file.trim_start_matches('*') == name,
+            None => true,
+        };
+        let valid = hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit());
+        (matches && valid).then(|| hash.to_ascii_lowercase())
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    /// Answer `n` HTTP requests on a local port with the responses of
+    /// `respond`, which gets the index and the header lines of each request.
+    ///
+    /// Returns the base url of the server, and the server thread, which
+    /// returns the header lines of all requests.
+    pub(crate) fn serve(
+        n: usize,
+        mut respond: impl FnMut(usize, &[String]) -> Vec<u8> + Send + 'static,
+    ) -> (String, std::thread::JoinHandle<Vec<Vec<String>>>) {
+        use std::io::{BufRead, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for i in 0..n {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+                let mut headers = Vec::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    headers.push(line.trim().to_string());
+                }
+                stream.write_all(&respond(i, &headers)).unwrap();
+                requests.push(headers);
+            }
+            requests
+        });
+        (base, server)
+    }
+
+    /// The value of header `name` in the header lines of a request.
+    pub(crate) fn header<'a>(headers: &'a [String], name: &str) -> Option<&'a str> {
+        headers.iter().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.eq_ignore_ascii_case(name).then_some(value.trim())
+        })
+    }
+
+    /// A complete HTTP response with `headers` (`\r\n` separated) and `body`.
+    pub(crate) fn http_response(status: &str, headers: &str, body: &[u8]) -> Vec<u8> {
+        let mut response = format!("HTTP/1.1 {status}\r\nContent-Length: {}\r\n", body.len());
+        if !headers.is_empty() {
+            response.push_str(headers);
+            response.push_str("\r\n");
+        }
+        response.push_str("Connection: close\r\n\r\n");
+        let mut response = response.into_bytes();
+        response.extend_from_slice(body);
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{header, http_response, serve};
+    use super::*;
+
+    #[test]
+    fn test_compare_archive() {
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            Vec::new(),
+            flate2::Compression::default(),
+        ));
+        for (path, data) in [
+            ("wasix-libc/sysroot32/lib/libc.a", &b"libc"[..]),
+            ("wasix-libc/sysroot32/lib/libm.a", b"libm"),
+            ("wasix-libc/sysroot64/lib/libc.a", b"libc64"),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, path, data).unwrap();
+        }
+        let data = builder.into_inner().unwrap().finish().unwrap();
+        let sha256 = HashReader::new(&data[..]).finish().unwrap();
+
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir_all(dir.path().join("sysroot32/lib")).unwrap();
+        std::fs::write(dir.path().join("sysroot32/lib/libc.a"), "libc").unwrap();
+        std::fs::write(dir.path().join("sysroot32/lib/libm.a"), "bitrot").unwrap();
+
+        let problems =
+            compare_archive(&data[..], Some(&sha256), dir.path(), Some("wasix-libc")).unwrap();
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].ends_with("libm.a: content differs"));
+        assert!(problems[1].ends_with("libc.a: missing"));
+
+        let err = compare_archive(&data[..], Some(&"00".repeat(32)), dir.path(), None).unwrap_err();
+        assert!(err.to_string().contains("Checksum mismatch"));
+    }
+
+    #[test]
+    fn test_parse_checksums() {
+        let sysroot = "a".repeat(64);
+        let rust = "B".repeat(64);
+        let text = format!("{sysroot}  wasix-libc.tar.gz\n{rust} *rust-toolchain-x86_64-unknown-linux-gnu.tar.gz\n");
+        assert_eq!(
+            parse_checksums(&text, "wasix-libc.tar.gz"),
+            Some(sysroot.clone())
+        );
+        assert_eq!(
+            parse_checksums(&text, "rust-toolchain-x86_64-unknown-linux-gnu.tar.gz"),
+            Some(rust.to_ascii_lowercase())
+        );
+        assert_eq!(parse_checksums(&text, "other.tar.gz"), None);
+        assert_eq!(
+            parse_checksums(&format!("{sysroot}\n"), "wasix-libc.tar.gz"),
+            Some(sysroot)
+        );
+        assert_eq!(
+            parse_checksums("not-a-hash  wasix-libc.tar.gz", "wasix-libc.tar.gz"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_retries() {
+        let secs = std::time::Duration::from_secs;
+        assert_eq!(Retries::parse("4").unwrap(), Retries::default());
+        let retries = Retries::parse("2:500ms").unwrap();
+        assert_eq!(retries.retries, 2);
+        assert_eq!(retries.base_delay, std::time::Duration::from_millis(500));
+        assert_eq!(Retries::parse("3:2s").unwrap().delay(3), secs(8));
+        assert_eq!(Retries::default().delay(1), secs(1));
+        assert!(Retries::parse("many").is_err());
+        assert!(Retries::parse("2:soon").is_err());
+
+        let quick = Retries {
+            retries: 2,
+            base_delay: std::time::Duration::ZERO,
+        };
+        let mut attempts = 0;
+        let err = quick
+            .run("test", || -> Result<(), anyhow::Error> {
+                attempts += 1;
+                Err(std::io::Error::from(std::io::ErrorKind::ConnectionReset).into())
+            })
+            .unwrap_err();
+        assert_eq!(attempts, 3);
+        assert!(err.to_string().contains("after 3 attempts"));
+
+        attempts = 0;
+        quick
+            .run("test", || -> Result<(), anyhow::Error> {
+                attempts += 1;
+                bail!("Checksum mismatch")
+            })
+            .unwrap_err();
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn test_cancelled_download() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let data = vec![7u8; 64 * 1024];
+        let asset = AssetSource {
+            name: "wasix-libc.tar.gz".to_string(),
+            url: "file:///unused".to_string(),
+            sha256: None,
+        };
+        let token = crate::utils::CancelToken::default();
+        token.cancel();
+        let err =
+            store_asset(token.reader(&data[..]), &asset, dir.path(), None, "test").unwrap_err();
+        assert!(format!("{err:#}").contains("cancelled"));
+        assert!(!is_transient(&err));
+    }
+
+    #[test]
+    fn test_release_info_cache() {
+        // Answers revalidations with 304.
+        let (base, server) = serve(3, |_, headers| {
+            if header(headers, "if-none-match").is_some() {
+                http_response("304 Not Modified", "", b"")
+            } else {
+                http_response(
+                    "200 OK",
+                    "ETag: \"abc\"",
+                    br#"{"tag_name": "v1", "assets": []}"#,
+                )
+            }
+        });
+        let url = format!("{base}/release.json");
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut options = DownloadOptions {
+            release_api: Some(url),
+            releases_cache: Some(dir.path().join(RELEASES_CACHE_FILE)),
+            releases_cache_ttl: std::time::Duration::from_secs(3600),
+            ..Default::default()
+        };
+        let client = reqwest::blocking::Client::new();
+        let fetch = |options: &DownloadOptions| fetch_release(&client, options).unwrap().tag_name;
+        assert_eq!(fetch(&options), "v1");
+        // Within the TTL the API is not asked at all.
+        assert_eq!(fetch(&options), "v1");
+        options.releases_cache_ttl = std::time::Duration::ZERO;
+        assert_eq!(fetch(&options), "v1");
+        options.refresh_releases = true;
+        assert_eq!(fetch(&options), "v1");
+        let etags: Vec<_> = server
+            .join()
+            .unwrap()
+            .iter()
+            .map(|headers| header(headers, "if-none-match").map(str::to_string))
+            .collect();
+        assert_eq!(etags, [None, Some("\"abc\"".to_string()), None]);
+    }
+
+    #[test]
+    fn test_release_urls() {
+        let mut options = DownloadOptions::default();
+        assert_eq!(
+            options.release_url(),
+            "https://api.github.com/repos/wasmerio/rust/releases/latest"
+        );
+        assert_eq!(github_repo_path(RUST_REPO), "wasmerio/rust");
+        assert_eq!(github_repo_path("git@github.com:me/rust.git"), "me/rust");
+        options.rust_repo = Some("https://github.com/me/rust/".to_string());
+        assert_eq!(
+            options.release_url(),
+            "https://api.github.com/repos/me/rust/releases/latest"
+        );
+        options.rust_repo = None;
+        options.releases_base = Some("https://ghe.example.com/api/v3/".to_string());
+        assert_eq!(
+            options.release_url(),
+            "https://ghe.example.com/api/v3/repos/wasmerio/rust/releases/latest"
+        );
+        // Github authentication still applies to the Github compatible API.
+        assert_eq!(options.token(false), std::env::var("GITHUB_TOKEN").ok());
+        options.release_api = Some("https://mirror.example.com/release.json".to_string());
+        assert_eq!(
+            options.release_url(),
+            "https://mirror.example.com/release.json"
+        );
+    }
+
+    #[test]
+    fn test_parse_download_timeout() {
+        assert_eq!(
+            parse_download_timeout(" 90 ").unwrap(),
+            Some(std::time::Duration::from_secs(90))
+        );
+        assert_eq!(parse_download_timeout("0").unwrap(), None);
+        let err = parse_download_timeout("1m").unwrap_err();
+        assert!(err.to_string().contains("WASIX_DOWNLOAD_TIMEOUT_SECS"));
+
+        let err = explain_timeout(
+            std::io::Error::new(std::io::ErrorKind::TimedOut, "stalled").into(),
+            "rust.tar.gz",
+        );
+        assert!(
+            err.to_string().contains("rust.tar.gz timed out after"),
+            "{}",
+            err
+        );
+        let err = explain_timeout(anyhow::anyhow!("not found"), "rust.tar.gz");
+        assert_eq!(err.to_string(), "not found");
+    }
+
+    #[test]
+    fn test_proxy_urls() {
+        let vars = |pairs: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                pairs
+                    .iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+        assert!(proxy_urls(vars(&[])).is_empty());
+        assert_eq!(
+            proxy_urls(vars(&[
+                ("HTTPS_PROXY", "http://upper:3128"),
+                ("https_proxy", "http://lower:3128"),
+                ("HTTP_PROXY", "http://plain:8080"),
+            ])),
+            [
+                ("http", "http://plain:8080".to_string()),
+                ("https", "http://lower:3128".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_load_ca_bundle() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("ca.pem");
+        let err = load_ca_bundle(&path).unwrap_err();
+        assert!(err.to_string().contains("Could not read"), "{}", err);
+        std::fs::write(&path, "not a certificate\n").unwrap();
+        let err = load_ca_bundle(&path).unwrap_err();
+        assert!(err.to_string().contains("no PEM certificates"), "{}", err);
+    }
+
+    #[test]
+    fn test_rate_limit_message() {
+        let msg = rate_limit_message(false, Some(1_000_000 + 121), 1_000_000);
+        assert!(
+            msg.contains("resets in 3 minute(s), at unix time 1000121"),
+            "{}",
+            msg
+        );
+        assert!(msg.contains("Set the GITHUB_TOKEN env var"));
+        assert!(msg.contains("${{ secrets.GITHUB_TOKEN }}"));
+
+        let msg = rate_limit_message(true, None, 1_000_000);
+        assert!(msg.contains("of the GITHUB_TOKEN is exhausted"));
+        assert!(msg.contains("within an hour"));
+        assert!(!msg.contains("Set the GITHUB_TOKEN"));
+    }
+
+    #[test]
+    fn test_check_network() {
+        assert_eq!(url_origin(RUST_REPO), "https://github.com");
+        assert_eq!(
+            url_origin("http://127.0.0.1:8080/releases/latest"),
+            "http://127.0.0.1:8080"
+        );
+
+        let err = check_network(
+            &http_client().unwrap(),
+            "http://127.0.0.1:9",
+            "--offline-build",
+        )
+        .unwrap_err();
+        assert!(err.to_string().starts_with("no network"));
+        assert!(err.to_string().contains("--offline-build"));
+    }
+
+    #[test]
+    fn test_toolchain_policy() {
+        assert_eq!(
+            ToolchainPolicy::parse("prefer-build").unwrap(),
+            ToolchainPolicy::PreferBuild
+        );
+        assert!(ToolchainPolicy::parse("build")
+            .unwrap_err()
+            .to_string()
+            .contains("WASIX_TOOLCHAIN_POLICY"));
+
+        use ToolchainPolicy::*;
+        assert!(!PreferPrebuilt.should_build(true, true));
+        assert!(PreferPrebuilt.should_build(false, true));
+        assert!(PreferBuild.should_build(true, true));
+        assert!(!PreferBuild.should_build(true, false));
+        assert!(!PrebuiltOnly.should_build(false, true));
+        assert!(BuildOnly.should_build(true, false));
+    }
+
+    #[test]
+    fn test_parse_umask() {
+        assert_eq!(parse_umask("022").unwrap(), 0o022);
+        assert_eq!(parse_umask("0o027").unwrap(), 0o027);
+        assert!(parse_umask("1000").is_err());
+        assert!(parse_umask("abc").is_err());
+    }
+
+    #[test]
+    fn test_store_asset_saves_copy() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let save_dir = dir.path().join("saved");
+        let asset = AssetSource {
+            name: "notes.txt".to_string(),
+            url: "https://example.com/notes.txt".to_string(),
+            sha256: None,
+        };
+        let sha256 = store_asset(
+            &b"release notes"[..],
+            &asset,
+            &dir.path().join("out"),
+            Some(&save_dir),
+            &asset.url,
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read(dir.path().join("out/notes.txt")).unwrap(),
+            b"release notes"
+        );
+        assert_eq!(
+            std::fs::read(save_dir.join("notes.txt")).unwrap(),
+            b"release notes"
+        );
+        assert_eq!(
+            std::fs::read_to_string(save_dir.join("notes.txt.sha256")).unwrap(),
+            format!("{sha256}  notes.txt\n")
+        );
+
+        let asset = AssetSource {
+            sha256: Some("00".repeat(32)),
+            ..asset
+        };
+        let out = dir.path().join("out2");
+        assert!(store_asset(&b"tampered"[..], &asset, &out, Some(&save_dir), &asset.url).is_err());
+        assert!(!save_dir.join("notes.txt").exists());
+    }
+
+    #[test]
+    fn test_hash_reader() {
+        let mut reader = HashReader::new(&b"abcdef"[..]);
+        let mut buf = [0; 3];
+        std::io::Read::read_exact(&mut reader, &mut buf).unwrap();
+        assert_eq!(
+            reader.finish().unwrap(),
+            "bef57ec7f53a6d40beb640a780a639c83bc29ac8a9816f1fc6c5c6dcd93c4721"
+        );
+    }
+
+    #[test]
+    fn test_unpack_hashed_matches_file_hash() {
+        use sha2::Digest;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let archive_path = dir.path().join("sysroot.tar.gz");
+        {
+            let file = std::fs::File::create(&archive_path).unwrap();
+            let gz = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+            let mut builder = tar::Builder::new(gz);
+            let data = vec![7u8; 300_000];
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder
+                .append_data(&mut header, "lib/libc.a", &data[..])
+                .unwrap();
+            builder.into_inner().unwrap().finish().unwrap();
+        }
+        let file_hash: String = sha2::Sha256::digest(std::fs::read(&archive_path).unwrap())
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+
+        let out = dir.path().join("out");
+        let file = std::fs::File::open(&archive_path).unwrap();
+        let streamed = unpack_hashed(file, Some(&file_hash), &out).unwrap();
+        assert_eq!(streamed, file_hash);
+        assert_eq!(
+            std::fs::read(out.join("lib/libc.a")).unwrap().len(),
+            300_000
+        );
+
+        let file = std::fs::File::open(&archive_path).unwrap();
+        let err = unpack_hashed(file, Some(&"0".repeat(64)), &out).unwrap_err();
+        assert!(err.to_string().contains("Checksum mismatch"));
+        assert!(!out.exists());
+    }
+
+    #[test]
+    fn test_describe_release() {
+        let asset = |name: &str, size| GithubAsset {
+            browser_download_url: format!("https://example.com/{name}"),
+            name: name.to_string(),
+            size,
+            digest: None,
+        };
+        let release = GithubReleaseData {
+            assets: vec![
+                asset("wasix-libc.tar.gz", 2048),
+                asset(
+                    "rust-toolchain-x86_64-unknown-linux-gnu.tar.gz",
+                    3 * 1024 * 1024,
+                ),
+                asset("rust-toolchain-aarch64-apple-darwin.tar.gz", 1024),
+            ],
+            tag_name: "v2023-05-01".to_string(),
+            prerelease: true,
+            draft: false,
+            published_at: Some("2023-05-01T10:00:00Z".to_string()),
+            body: Some("Fixes things.\r\n".to_string()),
+        };
+        assert_eq!(
+            release.host_targets(),
+            vec!["x86_64-unknown-linux-gnu", "aarch64-apple-darwin"]
+        );
+        let info = release.describe();
+        assert!(
+            info.starts_with("Release v2023-05-01 (prerelease)\nPublished: 2023-05-01T10:00:00Z\n")
+        );
+        assert!(info.contains("Hosts: x86_64-unknown-linux-gnu, aarch64-apple-darwin\n"));
+        assert!(info.contains("wasix-libc.tar.gz"));
+        assert!(info.ends_with("\nRelease notes:\n  Fixes things.\n"));
+    }
+
+    #[test]
+    fn test_newest_release() {
+        let release =
+            |tag: &str, published_at: Option<&str>, prerelease, draft| GithubReleaseData {
+                assets: Vec::new(),
+                tag_name: tag.to_string(),
+                prerelease,
+                draft,
+                published_at: published_at.map(str::to_string),
+                body: None,
+            };
+        let newest = newest_release(vec![
+            release("v1", Some("2023-05-01T10:00:00Z"), false, false),
+            release("v3-draft", None, false, true),
+            release("v2-rc1", Some("2023-06-01T10:00:00Z"), true, false),
+        ])
+        .unwrap();
+        assert_eq!(newest.tag_name, "v2-rc1");
+        assert!(newest.prerelease);
+        assert!(newest_release(Vec::new()).is_none());
+    }
+}
@@ -0,0 +1,172 @@
+//! The wasm runtime that `run`, `test` and `bench` execute binaries in.
+//!
+//! Selected with `--runtime` or `WASIX_RUNTIME`, which take `wasmer`,
+//! `wasmtime` or a command line template like `myrt {wasm} -- {args}`.
+
+use anyhow::{bail, Result};
+use std::process::Command;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Runtime {
+    Wasmer,
+    Wasmtime,
+    /// A command line with a `{wasm}` and optionally an `{args}` placeholder.
+    Template(Vec<String>),
+    /// A runner program, run as `<program> -- <wasm> <args>`.
+    Program(String),
+    /// A runner with arguments from a cargo config, run like cargo runs it:
+    /// `<runner...> <wasm> <args>`.
+    Runner(Vec<String>),
+}
+
+impl Runtime {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let spec = spec.trim();
+        let runtime = match spec {
+            "" => bail!("the wasm runtime must not be empty"),
+            "wasmer" => Self::Wasmer,
+            "wasmtime" => Self::Wasmtime,
+            _ if spec.contains("{wasm}") => {
+                Self::Template(spec.split_whitespace().map(String::from).collect())
+            }
+            _ if spec.contains('{') => bail!(
+                "invalid wasm runtime template `{spec}` - it must contain a `{{wasm}}` placeholder"
+            ),
+            _ => Self::Program(spec.to_string()),
+        };
+        Ok(runtime)
+    }
+
+    /// The runtime of a cargo config `runner`, split into its program and
+    /// arguments.
+    pub fn from_cargo_runner(runner: &[String]) -> Result<Self> {
+        match runner {
+            [] => bail!("the wasm runtime must not be empty"),
+            [program] => Self::parse(program),
+            _ => Ok(Self::Runner(runner.to_vec())),
+        }
+    }
+
+    /// The program that is executed.
+    pub fn program(&self) -> &str {
+        match self {
+            Self::Wasmer => "wasmer",
+            Self::Wasmtime => "wasmtime",
+            Self::Template(parts) => &parts[0],
+            Self::Program(program) => program,
+            Self::Runner(parts) => &parts[0],
+        }
+    }
+
+    /// The `runner` of a cargo config that runs binaries in this runtime.
+    ///
+    /// cargo appends the wasm file and its arguments, so templates can't be
+    /// expressed as a runner.
+    pub fn cargo_runner(&self) -> Result<Vec<String>> {
+        Ok(match self {
+            Self::Wasmer => vec!["wasmer".to_string(), "run".to_string()],
+            Self::Wasmtime => vec!["wasmtime".to_string(), "run".to_string()],
+            Self::Template(parts) => bail!(
+                "the wasm runtime template `{}` can not be used as a cargo runner",
+                parts.join(" ")
+            ),
+            Self::Program(program) => vec![program.clone()],
+            Self::Runner(parts) => parts.clone(),
+        })
+    }
+
+    /// The command that runs `run`, the wasm file followed by its arguments.
+    pub fn command(&self, run: &[String]) -> Command {
+        let mut cmd = Command::new(self.program());
+        match self {
+            Self::Wasmer => {
+                cmd.arg("--enable-threads").arg("--").args(run);
+            }
+            Self::Wasmtime => {
+                cmd.arg("run").arg("--").args(run);
+            }
+            Self::Template(parts) => {
+                let (wasm, args) = run.split_first().expect("a wasm file to run");
+                let mut has_args = false;
+                for part in &parts[1..] {
+                    if part == "{args}" {
+                        cmd.args(args);
+                        has_args = true;
+                    } else {
+                        cmd.arg(part.replace("{wasm}", wasm));
+                    }
+                }
+                if !has_args {
+                    cmd.args(args);
+                }
+            }
+            Self::Program(_) => {
+                cmd.arg("--").args(run);
+            }
+            Self::Runner(parts) => {
+                cmd.args(&parts[1..]).args(run);
+            }
+        }
+        cmd
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runtime_command() {
+        let run = ["app.wasm".to_string(), "-x".to_string(), "y".to_string()];
+        let args = |runtime: &str| {
+            let cmd = Runtime::parse(runtime).unwrap().command(&run);
+            let mut args = vec![cmd.get_program().to_string_lossy().into_owned()];
+            args.extend(cmd.get_args().map(|a| a.to_string_lossy().into_owned()));
+            args
+        };
+        assert_eq!(
+            args("wasmer"),
+            ["wasmer", "--enable-threads", "--", "app.wasm", "-x", "y"]
+        );
+        assert_eq!(
+            args("wasmtime"),
+            ["wasmtime", "run", "--", "app.wasm", "-x", "y"]
+        );
+        assert_eq!(
+            args("myrt --file={wasm} -- {args}"),
+            ["myrt", "--file=app.wasm", "--", "-x", "y"]
+        );
+        assert_eq!(args("myrt {wasm}"), ["myrt", "app.wasm", "-x", "y"]);
+        assert_eq!(
+            args("/opt/bin/runner"),
+            ["/opt/bin/runner", "--", "app.wasm", "-x", "y"]
+        );
+
+        assert_eq!(
+            Runtime::parse("wasmer").unwrap().cargo_runner().unwrap(),
+            ["wasmer", "run"]
+        );
+        assert!(Runtime::parse("myrt {wasm}")
+            .unwrap()
+            .cargo_runner()
+            .is_err());
+        assert!(Runtime::parse(" ").is_err());
+
+        let runner = |parts: &[&str]| {
+            let parts: Vec<_> = parts.iter().map(|p| p.to_string()).collect();
+            Runtime::from_cargo_runner(&parts).unwrap()
+        };
+        assert_eq!(runner(&["wasmer"]), Runtime::Wasmer);
+        let wasmtime = runner(&["wasmtime", "run", "--dir=."]);
+        assert_eq!(wasmtime.program(), "wasmtime");
+        let cmd = wasmtime.command(&run);
+        let args: Vec<_> = cmd.get_args().map(|a| a.to_string_lossy()).collect();
+        assert_eq!(args, ["run", "--dir=.", "app.wasm", "-x", "y"]);
+        assert_eq!(
+            wasmtime.cargo_runner().unwrap(),
+            ["wasmtime", "run", "--dir=."]
+        );
+        assert!(Runtime::from_cargo_runner(&[]).is_err());
+        assert!(Runtime::parse("myrt {file}").is_err());
+    }
+}
@@ -0,0 +1,70 @@
+//! Custom target-spec JSON files for experimental wasm targets.
+//!
+//! A spec is passed to cargo as `--target path/to/spec.json` and the standard
+//! library is compiled for it with `-Z build-std`, linking against the libc
+//! sysroot of the installed toolchain that matches the spec's architecture.
+
+use anyhow::{bail, Context, Result};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub struct TargetSpec {
+    pub path: PathBuf,
+    /// Target name cargo derives from the file name.
+    pub name: String,
+    pub is64bit: bool,
+}
+
+#[derive(serde::Deserialize)]
+struct RawSpec {
+    arch: String,
+}
+
+impl TargetSpec {
+    pub fn load(path: &Path) -> Result<Self> {
+        let path = path
+            .canonicalize()
+            .with_context(|| format!("Could not find target spec {}", path.display()))?;
+        let data = std::fs::read(&path)
+            .with_context(|| format!("Could not read target spec {}", path.display()))?;
+        let raw: RawSpec = serde_json::from_slice(&data)
+            .with_context(|| format!("Invalid target spec {}", path.display()))?;
+        let is64bit = match raw.arch.as_str() {
+            "wasm32" => false,
+            "wasm64" => true,
+            other => bail!(
+                "Target spec {} has unsupported arch '{other}' - expected wasm32 or wasm64",
+                path.display()
+            ),
+        };
+        let name = path
+            .file_stem()
+            .context("Target spec path has no file name")?
+            .to_string_lossy()
+            .into_owned();
+        Ok(Self {
+            path,
+            name,
+            is64bit,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_target_spec() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("wasm64-custom.json");
+        std::fs::write(&path, r#"{"arch": "wasm64", "os": "wasi"}"#).unwrap();
+        let spec = TargetSpec::load(&path).unwrap();
+        assert_eq!(spec.name, "wasm64-custom");
+        assert!(spec.is64bit);
+
+        std::fs::write(&path, r#"{"arch": "x86_64"}"#).unwrap();
+        let err = TargetSpec::load(&path).unwrap_err();
+        assert!(err.to_string().contains("unsupported arch 'x86_64'"));
+    }
+}
@@ -14,67 +14,452 @@ use std::{
 use anyhow::{bail, Context};
 
 use crate::{
+    cache::prefetch_archives,
     config::Config,
-    utils::{ensure_binary, CommandExt},
+    doctor::verify,
+    error::ToolchainError,
+    llvm::{ensure_llvm, llvm_dir_name, llvm_source, parse_llvm_version, LLVM_DEFAULT_VERSION},
+    manifest,
+    meta::{check_toolchain_layout, sysroot_change, ToolchainMeta, TOOLCHAIN_META_FILE},
+    release::{
+        check_network, checked_source, download_release_asset_with, fetch_release,
+        fetch_release_by_tag, fetch_selected_release, http_client, unix_now, url_origin,
+        AssetSource, DownloadOptions, HashReader, ToolchainPolicy,
+    },
+    uninstall::linked_toolchain_path,
+    utils::{dir_size, ensure_binary, format_bytes, warn, CommandExt},
 };
+use log::{debug, info};
 
 const LIBC_REPO: &str = "https://github.com/wasmerio/wasix-libc.git";
+/// Branch to use in the wasix-libc repo.
+const LIBC_BRANCH: &str = "main";
 
 /// Custom rust repository.
-const RUST_REPO: &str = "https://github.com/wasmerio/rust.git";
+pub(crate) const RUST_REPO: &str = "https://github.com/wasmerio/rust.git";
 /// Branch to use in the custom Rust repo.
 const RUST_BRANCH: &str = "wasix";
 
-/// Download url for LLVM + clang.
-const LLVM_LINUX_SOURCE: &str = "https://github.com/llvm/llvm-project/releases/download/llvmorg-15.0.2/clang+llvm-15.0.2-x86_64-unknown-linux-gnu-rhel86.tar.xz";
+/// The target triples cargo-wasix compiles for, with their pointer width.
+pub(crate) const WASIX_TARGETS: &[(&str, u32)] =
+    &[("wasm32-wasmer-wasi", 32), ("wasm64-wasmer-wasi", 64)];
 
-const RUSTUP_TOOLCHAIN_NAME: &str = "wasix";
+pub(crate) const RUSTUP_TOOLCHAIN_NAME: &str = "wasix";
+
+/// File in the Rust toolchain and both libc sysroots of a build that
+/// identifies the build, so mismatched pairs can be refused on install.
+const BUILD_ID_FILE: &str = "wasix-build-id";
+
+/// File in the build root with `config.toml` settings that are merged over the
+/// config generated by build_rust.
+const RUST_CONFIG_OVERRIDES_FILE: &str = "wasix-rust-config-overrides.toml";
+
+/// Make variables that build_libc sets itself.
+const LIBC_MAKE_RESERVED_VARS: &[&str] = &["TARGET_ARCH", "TARGET_OS", "CC", "NM", "AR"];
+
+/// Default of the libc Makefile's `EXTRA_CFLAGS`, which `WASIX_LIBC_CFLAGS`
+/// is appended to.
+const LIBC_DEFAULT_CFLAGS: &str = "-O2 -DNDEBUG";
+
+/// Compiler flags the libc Makefile derives from the target and sysroot.
+const LIBC_RESERVED_CFLAGS: &[&str] = &["--target", "-target", "--sysroot", "-isysroot"];
 
 /// Options for a toolchain build.
 pub struct BuildToochainOptions {
     root: PathBuf,
+    /// Checkout of the wasix rust repo.
+    rust_dir: PathBuf,
+    /// Checkout of the wasix-libc repo.
+    libc_dir: PathBuf,
+    /// Directory containing LLVM + clang.
+    pub(crate) llvm_dir: PathBuf,
+    /// LLVM release to download, like `15.0.2`.
+    pub(crate) llvm_version: String,
+    /// Where to download LLVM from.
+    pub(crate) llvm_url: String,
+    /// Shared download of this LLVM version that `llvm_dir` links to, unless
+    /// `WASIX_LLVM_DIR` is set.
+    pub(crate) llvm_cache: Option<PathBuf>,
     build_libc: bool,
     build_rust: bool,
+    /// Which of wasm32 and wasm64 to build libc and std for.
+    target_bits: Vec<u32>,
     rust_host_triple: Option<String>,
+    /// Extra arguments appended to the libc `make` invocations.
+    libc_make_args: Vec<String>,
+    /// Extra compiler flags for the libc build.
+    libc_cflags: Vec<String>,
+    /// Also build thread-enabled sysroots (`sysroot{32,64}-threads`).
+    libc_threads: bool,
+    /// Prebuilt wasm32 and wasm64 sysroots to use instead of building libc.
+    custom_sysroots: Option<(PathBuf, PathBuf)>,
+    /// Delete and re-download LLVM even if it looks intact.
+    pub force_refresh_llvm: bool,
+    /// Parallel jobs of the libc and Rust builds, all cores if unset.
+    pub jobs: Option<usize>,
+    /// Also build the std docs for the wasix targets.
+    build_docs: bool,
+    /// Build std with debug assertions and overflow checks.
+    std_debug: bool,
+    /// `rust.optimize` of the build, as a TOML value.
+    std_opt_level: Option<String>,
+    /// Upper bound for the wall time of the whole build.
+    timeout: Option<std::time::Duration>,
 
     update_repos: bool,
+    /// Clone and fetch only the requested commit of the repos and submodules.
+    shallow_clone: bool,
+    /// sccache or ccache binary used to cache the compilation of LLVM and
+    /// rustc, unless `WASIX_DISABLE_SCCACHE` is set.
+    compiler_cache: Option<PathBuf>,
+    /// Build without any network access.
+    pub(crate) offline: bool,
+    /// Git url and branch or tag of the wasix-libc repo.
+    libc_repo: String,
+    libc_branch: String,
+    /// Git url and branch or tag of the wasix rust repo.
+    rust_repo: String,
+    rust_branch: String,
+}
+
+/// The value of env var `name`, or `default` if it is unset or empty.
+fn env_or(name: &str, default: &str) -> String {
+    std::env::var(name)
+        .ok()
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| default.to_string())
 }
 
 impl BuildToochainOptions {
     pub fn from_env() -> Result<Self, anyhow::Error> {
         // Read components to build from env var.
-        let (build_libc, build_rust) = match std::env::var("WASIX_COMPONENTS")
-            .unwrap_or_default()
-            .as_str()
-        {
-            "" | "all" => (true, true),
-            "libc" => (true, false),
-            "rust" => (false, true),
-            other => {
-                bail!("Invalid env var WASIX_COMPONENTS with value '{other}' - expected 'all' or 'libc'");
-            }
+        let (mut build_libc, build_rust) =
+            parse_components(&std::env::var("WASIX_COMPONENTS").unwrap_or_default())?;
+        let target_bits =
+            parse_target_bits(&std::env::var("WASIX_TARGET_BITS").unwrap_or_default())?;
+
+        let root = Config::build_dir()?;
+        let dir_var = |name: &str, default: &str| {
+            std::env::var_os(name)
+                .map(PathBuf::from)
+                .unwrap_or_else(|| root.join(default))
         };
-
-        let root = if let Ok(dir) = std::env::var("WASIX_BUILD_DIR") {
-            PathBuf::from(dir)
-        } else {
-            #[allow(deprecated)]
-            std::env::home_dir()
-                .context("Could not determine home dir. set WASIX_BUILD_DIR env var!")?
-                .join(".wasix")
+        let rust_dir = dir_var("WASIX_RUST_SOURCE_DIR", "wasix-rust");
+        let libc_dir = dir_var("WASIX_LIBC_SOURCE_DIR", "wasix-libc");
+        let llvm_version = match std::env::var("WASIX_LLVM_VERSION") {
+            Ok(v) if !v.trim().is_empty() => parse_llvm_version(&v)?,
+            _ => LLVM_DEFAULT_VERSION.to_string(),
+        };
+        let llvm_url = std::env::var("WASIX_LLVM_URL")
+            .ok()
+            .filter(|v| !v.is_empty())
+            .unwrap_or_else(|| llvm_source(&llvm_version));
+        let llvm_dir = dir_var("WASIX_LLVM_DIR", &llvm_dir_name(&llvm_version));
+        let llvm_cache = match std::env::var_os("WASIX_LLVM_DIR") {
+            Some(_) => None,
+            None => Some(Config::llvm_cache_dir()?.join(&llvm_version)),
         };
 
         let rust_host_triple = std::env::var("WASIX_RUST_HOST").ok();
         let update_repos = std::env::var("WASIX_NO_UPDATE_REPOS").is_err();
+        let libc_make_args =
+            parse_libc_make_args(&std::env::var("WASIX_LIBC_MAKE_ARGS").unwrap_or_default())?;
+        let libc_cflags = parse_libc_cflags(
+            &std::env::var("WASIX_LIBC_CFLAGS").unwrap_or_default(),
+            &libc_make_args,
+        )?;
+        let libc_threads =
+            std::env::var("WASIX_LIBC_THREADS").is_ok_and(|v| v == "1" || v == "true");
+
+        let custom_sysroots = match (
+            std::env::var_os("WASIX_LIBC_SYSROOT32"),
+            std::env::var_os("WASIX_LIBC_SYSROOT64"),
+        ) {
+            (None, None) => None,
+            (Some(dir32), Some(dir64)) => {
+                let (dir32, dir64) = (PathBuf::from(dir32), PathBuf::from(dir64));
+                check_libc_sysroot("WASIX_LIBC_SYSROOT32", &dir32, 32)?;
+                check_libc_sysroot("WASIX_LIBC_SYSROOT64", &dir64, 64)?;
+                build_libc = false;
+                Some((dir32, dir64))
+            }
+            _ => bail!("WASIX_LIBC_SYSROOT32 and WASIX_LIBC_SYSROOT64 must be set together"),
+        };
 
         Ok(Self {
             root,
+            rust_dir,
+            libc_dir,
+            llvm_dir,
+            llvm_version,
+            llvm_url,
+            llvm_cache,
             build_rust,
             build_libc,
+            target_bits,
             rust_host_triple,
+            libc_make_args,
+            libc_cflags,
+            libc_threads,
+            custom_sysroots,
+            force_refresh_llvm: std::env::var("WASIX_FORCE_REFRESH_LLVM")
+                .is_ok_and(|v| v == "1" || v == "true"),
+            jobs: None,
+            build_docs: std::env::var("WASIX_BUILD_DOCS").is_ok_and(|v| v == "1" || v == "true"),
+            std_debug: std::env::var("WASIX_STD_DEBUG").is_ok_and(|v| v == "1" || v == "true"),
+            std_opt_level: std::env::var("WASIX_STD_OPT_LEVEL")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .map(|v| parse_std_opt_level(&v))
+                .transpose()?,
+            timeout: std::env::var("WASIX_BUILD_TIMEOUT")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .map(|v| parse_timeout(&v))
+                .transpose()?,
             update_repos,
+            shallow_clone: !std::env::var("WASIX_SHALLOW_CLONE")
+                .is_ok_and(|v| v == "0" || v == "false"),
+            compiler_cache: find_compiler_cache(
+                std::env::var("WASIX_DISABLE_SCCACHE").is_ok_and(|v| v == "1" || v == "true"),
+                |name| which::which(name).ok(),
+            ),
+            offline: false,
+            libc_repo: env_or("WASIX_LIBC_REPO", LIBC_REPO),
+            libc_branch: env_or("WASIX_LIBC_BRANCH", LIBC_BRANCH),
+            rust_repo: env_or("WASIX_RUST_REPO", RUST_REPO),
+            rust_branch: env_or("WASIX_RUST_BRANCH", RUST_BRANCH),
         })
     }
+
+    /// The libc sysroot the rust build links the wasm{bits} target against.
+    fn sysroot_dir(&self, bits: u32) -> PathBuf {
+        match (&self.custom_sysroots, bits) {
+            (Some((dir32, _)), 32) => dir32.clone(),
+            (Some((_, dir64)), _) => dir64.clone(),
+            (None, _) => self.libc_dir.join(format!("sysroot{bits}")),
+        }
+    }
+
+    /// Parallel jobs to run, `--jobs` or the available parallelism.
+    fn jobs(&self) -> usize {
+        self.jobs
+            .unwrap_or_else(|| available_parallelism().map(|x| x.get()).unwrap_or(1))
+    }
+
+    /// Switch to a fully offline build.
+    ///
+    /// Requires vendored rust and libc sources and an existing LLVM, provided
+    /// via `WASIX_RUST_SOURCE_DIR`, `WASIX_LIBC_SOURCE_DIR` and
+    /// `WASIX_LLVM_DIR`.
+    pub fn set_offline_build(&mut self) -> Result<(), anyhow::Error> {
+        let mut checks = vec![("WASIX_RUST_SOURCE_DIR", self.rust_dir.join("x.py"))];
+        // Custom sysroots make the libc sources and LLVM unnecessary.
+        if self.custom_sysroots.is_none() {
+            checks.push(("WASIX_LIBC_SOURCE_DIR", self.libc_dir.join("Makefile")));
+            checks.push(("WASIX_LLVM_DIR", self.llvm_dir.join("bin").join("clang")));
+        }
+        let mut problems = Vec::new();
+        for (var, required) in &checks {
+            if std::env::var_os(var).is_none() {
+                problems.push(format!("{var} is not set"));
+            } else if !required.is_file() {
+                problems.push(format!("{var}: {} does not exist", required.display()));
+            }
+        }
+        if !problems.is_empty() {
+            bail!(
+                "An offline build requires vendored sources and LLVM:\n  {}",
+                problems.join("\n  ")
+            );
+        }
+
+        self.update_repos = false;
+        self.offline = true;
+        Ok(())
+    }
+}
+
+/// Check that the sysroot at `dir`, configured via env var `var`, looks like a
+/// wasix-libc sysroot for wasm{bits}.
+fn check_libc_sysroot(var: &str, dir: &Path, bits: u32) -> Result<(), anyhow::Error> {
+    if !dir.is_absolute() {
+        bail!(
+            "Invalid env var {var} with value '{}' - expected an absolute path",
+            dir.display()
+        );
+    }
+    let libc = dir.join(format!("lib/wasm{bits}-wasi/libc.a"));
+    if !libc.is_file() {
+        bail!(
+            "Invalid env var {var}: {} is not a wasm{bits} libc sysroot ({} not found)",
+            dir.display(),
+            libc.display()
+        );
+    }
+    Ok(())
+}
+
+/// Check that a sysroot of an earlier libc build is still complete, since a
+/// partially deleted one only fails much later when linking std.
+fn check_built_sysroot(dir: &Path, bits: u32) -> Result<(), ToolchainError> {
+    let missing = if !dir.is_dir() {
+        Vec::new()
+    } else {
+        vec![
+            format!("lib/wasm{bits}-wasi/libc.a"),
+            format!("lib/wasm{bits}-wasi/crt1.o"),
+            "include/stdlib.h".to_string(),
+        ]
+        .into_iter()
+        .filter(|file| !dir.join(file).is_file())
+        .collect::<Vec<_>>()
+    };
+    if !dir.is_dir() || !missing.is_empty() {
+        return Err(ToolchainError::SysrootMissing {
+            path: dir.to_path_buf(),
+            bits,
+            missing,
+        });
+    }
+    Ok(())
+}
+
+/// Parse the whitespace separated `WASIX_LIBC_MAKE_ARGS`.
+///
+/// Rejects variable assignments that would override the environment
+/// build_libc provides to `make`.
+fn parse_libc_make_args(raw: &str) -> Result<Vec<String>, anyhow::Error> {
+    let args = raw
+        .split_whitespace()
+        .map(|arg| arg.to_string())
+        .collect::<Vec<_>>();
+    for arg in &args {
+        if let Some((name, _)) = arg.split_once('=') {
+            if LIBC_MAKE_RESERVED_VARS.contains(&name) {
+                bail!(
+                    "Invalid env var WASIX_LIBC_MAKE_ARGS: '{arg}' conflicts with {name}, which is set by cargo-wasix"
+                );
+            }
+        }
+    }
+    Ok(args)
+}
+
+/// Parse the whitespace separated `WASIX_LIBC_CFLAGS`.
+///
+/// Rejects flags that would override the target or sysroot the libc Makefile
+/// compiles for, and an `EXTRA_CFLAGS` in `make_args`, which would silently
+/// drop them.
+fn parse_libc_cflags(raw: &str, make_args: &[String]) -> Result<Vec<String>, anyhow::Error> {
+    let flags = raw
+        .split_whitespace()
+        .map(|flag| flag.to_string())
+        .collect::<Vec<_>>();
+    for flag in &flags {
+        let name = flag.split('=').next().unwrap_or_default();
+        if LIBC_RESERVED_CFLAGS.contains(&name) {
+            bail!(
+                "Invalid env var WASIX_LIBC_CFLAGS: '{flag}' conflicts with the target and sysroot set by the libc build"
+            );
+        }
+    }
+    if !flags.is_empty() && make_args.iter().any(|arg| arg.starts_with("EXTRA_CFLAGS=")) {
+        bail!("WASIX_LIBC_CFLAGS can not be combined with EXTRA_CFLAGS in WASIX_LIBC_MAKE_ARGS");
+    }
+    Ok(flags)
+}
+
+/// Parse a `WASIX_BUILD_TIMEOUT` like `5400`, `90m` or `2h`, in seconds
+/// without a unit.
+fn parse_timeout(raw: &str) -> Result<std::time::Duration, anyhow::Error> {
+    let raw = raw.trim();
+    let (number, unit) = match raw.char_indices().last() {
+        Some((i, 's')) => (&raw[..i], 1),
+        Some((i, 'm')) => (&raw[..i], 60),
+        Some((i, 'h')) => (&raw[..i], 60 * 60),
+        _ => (raw, 1),
+    };
+    match number.parse::<u64>().ok().and_then(|n| n.checked_mul(unit)) {
+        Some(secs) if secs > 0 => Ok(std::time::Duration::from_secs(secs)),
+        _ => bail!(
+            "Invalid env var WASIX_BUILD_TIMEOUT with value '{raw}' - expected a duration like 5400, 90m or 2h"
+        ),
+    }
+}
+
+/// Parse `WASIX_COMPONENTS` into whether to build libc and Rust.
+///
+/// Accepts `all`, `none`, or a comma separated list of `libc` and `rust`.
+fn parse_components(raw: &str) -> Result<(bool, bool), anyhow::Error> {
+    let value = raw.trim().to_lowercase();
+    match value.as_str() {
+        "" | "all" => return Ok((true, true)),
+        "none" => return Ok((false, false)),
+        _ => {}
+    }
+    let (mut libc, mut rust) = (false, false);
+    for component in value.split(',').map(str::trim) {
+        match component {
+            "libc" => libc = true,
+            "rust" => rust = true,
+            _ => bail!(
+                "Invalid env var WASIX_COMPONENTS with value '{raw}' - expected 'all', 'none', 'libc', 'rust' or a comma separated list like 'libc,rust'"
+            ),
+        }
+    }
+    Ok((libc, rust))
+}
+
+/// Parse `WASIX_TARGET_BITS`: `32`, `64` or `both`.
+fn parse_target_bits(raw: &str) -> Result<Vec<u32>, anyhow::Error> {
+    match raw.trim().to_lowercase().as_str() {
+        "" | "both" => Ok(vec![32, 64]),
+        "32" => Ok(vec![32]),
+        "64" => Ok(vec![64]),
+        _ => bail!(
+            "Invalid env var WASIX_TARGET_BITS with value '{raw}' - expected '32', '64' or 'both'"
+        ),
+    }
+}
+
+/// Parse a `--jobs` value.
+pub fn parse_jobs(raw: &str) -> Result<usize, anyhow::Error> {
+    match raw.trim().parse() {
+        Ok(jobs) if jobs > 0 => Ok(jobs),
+        _ => bail!("invalid `--jobs` value: {raw} - expected a positive number"),
+    }
+}
+
+/// Parse a `WASIX_STD_OPT_LEVEL` into the TOML value for `rust.optimize`.
+fn parse_std_opt_level(raw: &str) -> Result<String, anyhow::Error> {
+    match raw.trim() {
+        level @ ("0" | "1" | "2" | "3") => Ok(level.to_string()),
+        level @ ("s" | "z") => Ok(format!("\"{level}\"")),
+        _ => bail!(
+            "Invalid env var WASIX_STD_OPT_LEVEL with value '{raw}' - expected 0, 1, 2, 3, s or z"
+        ),
+    }
+}
+
+/// The compiler cache to use for the Rust build, preferring sccache.
+fn find_compiler_cache(disabled: bool, find: impl Fn(&str) -> Option<PathBuf>) -> Option<PathBuf> {
+    if disabled {
+        return None;
+    }
+    find("sccache").or_else(|| find("ccache"))
+}
+
+/// Extra `[rust]` keys of the build config for the std options.
+fn std_rust_config(std_debug: bool, std_opt_level: Option<&str>) -> String {
+    let mut out = String::new();
+    if std_debug {
+        out.push_str("debug-assertions-std = true\noverflow-checks-std = true\n");
+    }
+    if let Some(level) = std_opt_level {
+        out.push_str(&format!("optimize = {level}\n"));
+    }
+    out
 }
 
 /// Build the wasix toolchain.
@@ -83,45 +468,119 @@ impl BuildToochainOptions {
 pub fn build_toolchain(
     options: BuildToochainOptions,
 ) -> Result<Option<RustBuildOutput>, anyhow::Error> {
-    eprintln!("Building the wasix toolchain...");
-    eprintln!("WARNING: this could take a long time and use a lot of disk space!");
+    info!("Building the wasix toolchain...");
+    log::warn!("this could take a long time and use a lot of disk space!");
+
+    // Every git, make and x.py run below counts against the limit.
+    let _time_limit = options.timeout.map(|limit| {
+        info!("The build will be aborted after {}s", limit.as_secs());
+        crate::utils::set_time_limit(limit)
+    });
+
+    // Repos cloned over ssh can't be checked with a http request.
+    let origin = url_origin(&options.rust_repo);
+    if !options.offline && origin.starts_with("http") {
+        check_network(&http_client()?, &origin, "--offline-build")?;
+    }
 
-    if ensure_binary("apt-get", &["--version"]).is_ok() {
+    if !options.offline && ensure_binary("apt-get", &["--version"]).is_ok() {
         setup_apt()?;
     }
 
     if options.build_libc {
-        build_libc(&options.root, None, options.update_repos)?;
+        build_libc(&options, None)?;
+    } else if options.custom_sysroots.is_some() {
+        info!(
+            "Skipping libc build, using the sysroots at {} and {}",
+            options.sysroot_dir(32).display(),
+            options.sysroot_dir(64).display()
+        );
     } else {
-        let dir = options.root.join("wasix-libc");
-        let dir32 = dir.join("sysroot32");
-        let dir64 = dir.join("sysroot64");
-        if !(dir32.is_dir() && dir64.is_dir()) {
-            bail!(
-                "Tried to skip libc build, but {} or {} were not found",
-                dir32.display(),
-                dir64.display()
-            )
+        for &bits in &options.target_bits {
+            check_built_sysroot(&options.libc_dir.join(format!("sysroot{bits}")), bits)
+                .context("Tried to skip libc build")?;
         }
-        eprintln!("Skipping libc build!");
+        info!("Skipping libc build, using the existing sysroot");
     }
 
     if !options.build_rust {
         return Ok(None);
     }
 
-    let out = build_rust(
-        &options.root,
-        None,
-        options.rust_host_triple.as_deref(),
-        options.update_repos,
-    )?;
+    let out = {
+        let _group = crate::utils::group("Build Rust");
+        build_rust(&options, None)?
+    };
+    let Some(out) = out else {
+        return Ok(None);
+    };
+    write_build_ids(&options, &out.toolchain_dir)?;
 
     RustupToolchain::link(RUSTUP_TOOLCHAIN_NAME, &out.toolchain_dir)?;
 
     Ok(Some(out))
 }
 
+/// Mark the Rust toolchain and the sysroots it was built against as one
+/// build. Reused sysroots keep their id, unless libc was rebuilt.
+///
+/// Custom sysroots are not ours to modify, so for them the id is only
+/// recorded in the toolchain.
+fn write_build_ids(
+    options: &BuildToochainOptions,
+    toolchain_dir: &Path,
+) -> Result<(), anyhow::Error> {
+    let bits = &options.target_bits;
+    let existing = read_build_id(&options.sysroot_dir(bits[0])).filter(|id| {
+        !options.build_libc
+            && bits
+                .iter()
+                .all(|&b| read_build_id(&options.sysroot_dir(b)).as_ref() == Some(id))
+    });
+    let id = existing.unwrap_or_else(|| {
+        let secs = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        format!("local-{secs}-{}", std::process::id())
+    });
+    if options.custom_sysroots.is_none() {
+        for &bits in bits {
+            let path = options.sysroot_dir(bits).join(BUILD_ID_FILE);
+            std::fs::write(&path, format!("{id}\n"))
+                .with_context(|| format!("Could not write {}", path.display()))?;
+        }
+    }
+    let path = toolchain_dir.join(BUILD_ID_FILE);
+    std::fs::write(&path, format!("{id}\n"))
+        .with_context(|| format!("Could not write {}", path.display()))
+}
+
+/// The build id recorded in `dir`, if any.
+fn read_build_id(dir: &Path) -> Option<String> {
+    let id = std::fs::read_to_string(dir.join(BUILD_ID_FILE)).ok()?;
+    Some(id.trim().to_string()).filter(|id| !id.is_empty())
+}
+
+/// Refuse a Rust toolchain and sysroots from different builds.
+///
+/// Artifacts without a build id predate it and are accepted.
+fn check_same_build(rust_dir: &Path, sysroot_dir: &Path) -> Result<(), anyhow::Error> {
+    let Some(rust_id) = read_build_id(rust_dir) else {
+        return Ok(());
+    };
+    for bits in [32, 64] {
+        let dir = sysroot_dir.join(format!("sysroot{bits}"));
+        match read_build_id(&dir) {
+            Some(id) if id != rust_id => bail!(
+                "The Rust toolchain (build {rust_id}) and the wasm{bits} libc sysroot (build {id}) come from different builds; install a toolchain and sysroot of the same release"
+            ),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
 /// Install basic required packages on Debian based systems.
 fn setup_apt() -> Result<(), anyhow::Error> {
     let have_sudo = ensure_binary("sudo", &["--version"]).is_ok();
@@ -152,104 +611,133 @@ fn setup_apt() -> Result<(), anyhow::Error> {
 /// Initialize a Git repo.
 ///
 /// Clone if it doesn't exist yet, otherwise update the branch/tag.
+///
+/// With `shallow`, only the requested commit is cloned and fetched, without
+/// history. Refs a shallow fetch can't resolve, like commits that aren't the
+/// tip of a branch or tag, fall back to fetching the full history.
 fn prepare_git_repo(
     source: &str,
     tag: &str,
     path: &Path,
     all_submodules: bool,
+    shallow: bool,
 ) -> Result<(), anyhow::Error> {
-    eprintln!("Preparing git repo {source} with tag/branch {tag}");
+    debug!("Preparing git repo {source} with tag/branch {tag}");
     ensure_binary("git", &["--version"])?;
 
     if !path.join(".git").is_dir() {
-        Command::new("git")
-            .args(["clone", source])
-            .arg(path)
+        let mut clone = Command::new("git");
+        clone.arg("clone");
+        if shallow {
+            clone.args(["--depth", "1", "--branch", tag]);
+        }
+        if clone.arg(source).arg(path).run_verbose().is_err() {
+            if !shallow {
+                bail!("Could not clone {source} into {}", path.display());
+            }
+            // --branch only takes branch and tag names.
+            info!("Shallow clone of {tag} failed, cloning the full repo");
+            if path.exists() {
+                std::fs::remove_dir_all(path)
+                    .with_context(|| format!("Could not remove {}", path.display()))?;
+            }
+            Command::new("git")
+                .arg("clone")
+                .arg(source)
+                .arg(path)
+                .run_verbose()?;
+        }
+    }
+
+    let shallow_fetch = shallow
+        && Command::new("git")
+            .args(["fetch", "--depth", "1", "origin", tag])
+            .current_dir(path)
+            .run_verbose()
+            .is_ok();
+    if !shallow_fetch {
+        if shallow {
+            info!("Shallow fetch of {tag} failed, fetching the full history");
+        }
+        let mut fetch = Command::new("git");
+        fetch.arg("fetch");
+        if path.join(".git/shallow").is_file() {
+            fetch.arg("--unshallow");
+        }
+        fetch
+            .args(["origin", tag])
+            .current_dir(path)
             .run_verbose()?;
     }
     Command::new("git")
-        .args(["fetch", "origin", tag])
-        .current_dir(path)
-        .run_verbose()?;
-    Command::new("git")
-        .args(["reset", "--hard", tag])
+        .args(["reset", "--hard", "FETCH_HEAD"])
         .current_dir(path)
         .run_verbose()?;
 
     if all_submodules {
-        Command::new("git")
-            .args(["submodule", "update", "--init", "--recursive"])
-            .current_dir(path)
-            .run_verbose()?;
+        let mut update = Command::new("git");
+        update.args(["submodule", "update", "--init", "--recursive"]);
+        if shallow {
+            update.args(["--depth", "1"]);
+        }
+        update.current_dir(path).run_verbose()?;
     }
 
-    eprintln!("Git repo ready at {}", path.display());
+    debug!("Git repo ready at {}", path.display());
 
     Ok(())
 }
 
-#[cfg(not(target_os = "linux"))]
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
 fn build_libc(
-    build_root: &Path,
+    options: &BuildToochainOptions,
     git_tag: Option<String>,
-    update_repo: bool,
 ) -> Result<(), anyhow::Error> {
-    anyhow::bail!("libc builds are only supported on Linux");
+    anyhow::bail!(
+        "wasix-libc can only be built on Linux and macOS - on Windows, build inside WSL, or install a pre-built toolchain with `cargo wasix download-toolchain` instead"
+    );
 }
 
 /// Build the wasix-libc sysroot.
-// Currently only works on Linux.
-#[cfg(target_os = "linux")]
+// Currently only works on Linux and macOS.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
 fn build_libc(
-    build_root: &Path,
+    options: &BuildToochainOptions,
     git_tag: Option<String>,
-    update_repo: bool,
 ) -> Result<(), anyhow::Error> {
-    eprintln!("Building wasix-libc...");
+    let _group = crate::utils::group("Build wasix-libc");
+    info!("Building wasix-libc...");
 
-    ensure_binary("git", &["--version"])?;
+    let build_root = &options.root;
+    let make_args = &options.libc_make_args;
 
-    let git_tag = git_tag.as_deref().unwrap_or("main");
+    let git_tag = git_tag.as_deref().unwrap_or(&options.libc_branch);
 
     std::fs::create_dir_all(build_root)
         .with_context(|| format!("Could not create directory: {}", build_root.display()))?;
-    let build_dir = build_root.join("wasix-libc");
-
-    if update_repo {
-        prepare_git_repo(LIBC_REPO, git_tag, &build_dir, true)?;
+    let build_dir = options.libc_dir.clone();
+
+    if options.update_repos {
+        ensure_binary("git", &["--version"])?;
+        prepare_git_repo(
+            &options.libc_repo,
+            git_tag,
+            &build_dir,
+            true,
+            options.shallow_clone,
+        )?;
     }
 
-    eprintln!("Ensuring LLVM...");
-    let llvm_dir = build_root.join("llvm-15");
-    if !llvm_dir.join("bin").join("clang").is_file() {
-        eprintln!("Downloading LLVM...");
-        std::fs::create_dir_all(&llvm_dir)?;
-
-        let archive_path = build_dir.join("llvm.tar.xz");
-
-        Command::new("curl")
-            .args(["-L", "-o"])
-            .arg(&archive_path)
-            .arg(LLVM_LINUX_SOURCE)
-            .run_verbose()?;
-
-        eprintln!("Extracting LLVM...");
-        Command::new("tar")
-            .args(["xJf"])
-            .arg(&archive_path)
-            .arg("-C")
-            .arg(&llvm_dir)
-            .args(["--strip-components", "1"])
-            .run_verbose()?;
-
-        std::fs::remove_file(&archive_path).ok();
-
-        eprintln!("Downloaded LLVM to {}", llvm_dir.display());
+    if crate::utils::is_dry_run() {
+        info!(
+            "Would ensure LLVM {} at {}, downloading it from {} if needed",
+            options.llvm_version,
+            options.llvm_dir.display(),
+            options.llvm_url
+        );
+    } else {
+        ensure_llvm(options)?;
     }
-    // Sanity check for clang.
-    Command::new(llvm_dir.join("bin").join("clang"))
-        .arg("--version")
-        .run_verbose()?;
 
     // Now run the build.
 
@@ -259,97 +747,202 @@ fn build_libc(
     //     .current_dir(&build_dir)
     //     .run_verbose()?;
 
-    eprintln!("Building wasm32...");
-    let dir32 = build_dir.join("sysroot32");
-
-    eprintln!("Generating headers...");
-    Command::new("cargo")
-        .args([
-            "run",
-            "--manifest-path",
-            "tools/wasix-headers/Cargo.toml",
-            "generate-libc",
-        ])
-        .current_dir(&build_dir)
-        .run_verbose()?;
-    Command::new("make")
-        .arg(format!(
-            "-j{}",
-            available_parallelism().map(|x| x.get()).unwrap_or(1)
-        ))
-        .current_dir(&build_dir)
-        .env("TARGET_ARCH", "wasm32")
-        .env("TARGET_OS", "wasix")
-        .env("CC", llvm_dir.join("bin").join("clang"))
-        .env("NM", llvm_dir.join("bin").join("llvm-nm"))
-        .env("AR", llvm_dir.join("bin").join("llvm-ar"))
-        .run_verbose()?;
-    std::fs::remove_file(build_dir.join("sysroot/lib/wasm32-wasi/libc-printscan-long-double.a"))
-        .ok();
-    if dir32.is_dir() {
-        std::fs::remove_dir_all(&dir32)?;
-    }
-    std::fs::rename(build_dir.join("sysroot"), &dir32)?;
-
-    eprintln!("Building wasm64...");
-    let dir64 = build_dir.join("sysroot64");
-
-    eprintln!("Generating headers...");
-    Command::new("cargo")
-        .args([
-            "run",
-            "--manifest-path",
-            "tools/wasix-headers/Cargo.toml",
-            "generate-libc",
-            "--64bit",
-        ])
-        .current_dir(&build_dir)
-        .run_verbose()?;
-    Command::new("make")
-        .current_dir(&build_dir)
-        .env("TARGET_ARCH", "wasm64")
+    let jobs = options.jobs();
+    info!("Building libc with {jobs} parallel jobs");
+    let mut threads_args = make_args.to_vec();
+    threads_args.push("THREAD_MODEL=posix".to_string());
+
+    let mut dirs = Vec::new();
+    for &bits in &options.target_bits {
+        let dir = build_dir.join(format!("sysroot{bits}"));
+        build_libc_sysroot(options, bits, jobs, make_args, &dir)?;
+        dirs.push(dir);
+
+        if options.libc_threads {
+            let dir = build_dir.join(format!("sysroot{bits}-threads"));
+            build_libc_sysroot(options, bits, jobs, &threads_args, &dir)?;
+            dirs.push(dir);
+        }
+    }
+
+    info!("wasix-libc build complete!");
+    for dir in dirs {
+        info!("{}", dir.display());
+    }
+
+    Ok(())
+}
+
+/// Build a single libc sysroot for wasm32 or wasm64 and move it to `out_dir`.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn build_libc_sysroot(
+    options: &BuildToochainOptions,
+    bits: u32,
+    jobs: usize,
+    make_args: &[String],
+    out_dir: &Path,
+) -> Result<(), anyhow::Error> {
+    let build_dir = &options.libc_dir;
+    let llvm_dir = &options.llvm_dir;
+    let arch = format!("wasm{bits}");
+    info!("Building {arch} ({})...", out_dir.display());
+
+    info!("Generating headers...");
+    let mut cmd = Command::new("cargo");
+    cmd.arg("run");
+    if options.offline {
+        cmd.arg("--offline");
+    }
+    cmd.args([
+        "--manifest-path",
+        "tools/wasix-headers/Cargo.toml",
+        "generate-libc",
+    ]);
+    if bits == 64 {
+        cmd.arg("--64bit");
+    }
+    cmd.current_dir(build_dir).run_verbose()?;
+
+    let mut cmd = Command::new("make");
+    cmd.arg(format!("-j{jobs}"));
+    if !options.libc_cflags.is_empty() {
+        cmd.env(
+            "EXTRA_CFLAGS",
+            format!("{LIBC_DEFAULT_CFLAGS} {}", options.libc_cflags.join(" ")),
+        );
+    }
+    cmd.args(make_args)
+        .current_dir(build_dir)
+        .env("TARGET_ARCH", &arch)
         .env("TARGET_OS", "wasix")
         .env("CC", llvm_dir.join("bin").join("clang"))
         .env("NM", llvm_dir.join("bin").join("llvm-nm"))
         .env("AR", llvm_dir.join("bin").join("llvm-ar"))
         .run_verbose()?;
-    std::fs::remove_file(build_dir.join("sysroot/lib/wasm64-wasi/libc-printscan-long-double.a"))
-        .ok();
-    if dir64.is_dir() {
-        std::fs::remove_dir_all(&dir64)?;
+    if crate::utils::is_dry_run() {
+        return Ok(());
     }
-    std::fs::rename(build_dir.join("sysroot"), &dir64)?;
-
-    eprintln!(
-        "wasix-libc build complete!\n{}\n{}",
-        dir32.display(),
-        dir64.display(),
-    );
 
+    std::fs::remove_file(build_dir.join(format!(
+        "sysroot/lib/{arch}-wasi/libc-printscan-long-double.a"
+    )))
+    .ok();
+    if out_dir.is_dir() {
+        std::fs::remove_dir_all(out_dir)?;
+    }
+    std::fs::rename(build_dir.join("sysroot"), out_dir)?;
     Ok(())
 }
 
+/// Explain a dynamic loader failure like
+/// `error while loading shared libraries: libtinfo.so.5: cannot open shared object file`.
+pub(crate) fn missing_library_hint(stderr: &str) -> Option<String> {
+    let (_, rest) = stderr.split_once("error while loading shared libraries: ")?;
+    let lib = rest.split(':').next()?.trim();
+
+    let stem = lib.split(".so").next().unwrap_or(lib);
+    let package = match stem {
+        "libtinfo" | "libncurses" if lib.ends_with(".so.5") => Some(format!(
+            "{stem}5 (Debian/Ubuntu) or ncurses-compat-libs (Fedora)"
+        )),
+        "libtinfo" | "libncurses" => {
+            Some(format!("{stem}6 (Debian/Ubuntu) or ncurses-libs (Fedora)"))
+        }
+        "libxml2" => Some("libxml2".to_string()),
+        "libz" => Some("zlib1g (Debian/Ubuntu) or zlib (Fedora)".to_string()),
+        "libstdc++" => Some("libstdc++6 (Debian/Ubuntu) or libstdc++ (Fedora)".to_string()),
+        _ => None,
+    };
+
+    let mut msg = format!("the shared library {lib} is missing");
+    if let Some(package) = package {
+        msg.push_str(&format!(" - try installing the package {package}"));
+    }
+    Some(msg)
+}
+
 /// Output info of a successful rust toolchain build.
+#[derive(Debug)]
 pub struct RustBuildOutput {
     pub target: String,
     pub toolchain_dir: PathBuf,
 }
 
 /// Build the Rust toolchain for wasm{32,64}-wasmer-wasi
+///
+/// Returns `None` for a `--dry-run`, which doesn't build anything.
 fn build_rust(
-    build_root: &Path,
+    options: &BuildToochainOptions,
     tag: Option<&str>,
-    host_triple: Option<&str>,
-    update_repo: bool,
-) -> Result<RustBuildOutput, anyhow::Error> {
-    let rust_dir = build_root.join("wasix-rust");
-    let git_tag = tag.unwrap_or(RUST_BRANCH);
+) -> Result<Option<RustBuildOutput>, anyhow::Error> {
+    let rust_dir = options.rust_dir.clone();
+    let host_triple = options.rust_host_triple.as_deref();
+    let git_tag = tag.unwrap_or(&options.rust_branch);
+
+    if options.update_repos {
+        prepare_git_repo(
+            &options.rust_repo,
+            git_tag,
+            &rust_dir,
+            true,
+            options.shallow_clone,
+        )?;
+    }
 
-    if update_repo {
-        prepare_git_repo(RUST_REPO, git_tag, &rust_dir, true)?;
+    let targets = WASIX_TARGETS
+        .iter()
+        .filter(|(_, bits)| options.target_bits.contains(bits))
+        .collect::<Vec<_>>();
+    let target_list = targets
+        .iter()
+        .map(|(target, _)| format!("\"{target}\""))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let target_config = targets
+        .iter()
+        .map(|(target, bits)| {
+            let root = options.sysroot_dir(*bits).to_string_lossy().into_owned();
+            format!(
+                "[target.{target}]\nwasi-root = {}\n",
+                toml::Value::String(root)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    // Offline builds must use the vendored crate sources and must not try
+    // to fetch a CI build of LLVM.
+    let vendor = if options.offline {
+        "vendor = true\n"
+    } else {
+        ""
+    };
+    let mut llvm_keys = Vec::new();
+    if options.offline {
+        llvm_keys.push("download-ci-llvm = false".to_string());
+    }
+    match &options.compiler_cache {
+        Some(cache) => {
+            info!(
+                "Caching the LLVM and rustc compilation with {}",
+                cache.display()
+            );
+            llvm_keys.push(format!(
+                "ccache = {}",
+                toml::Value::String(cache.to_string_lossy().into_owned())
+            ));
+        }
+        None => info!("No sccache or ccache found, building without a compiler cache"),
     }
+    let llvm = if llvm_keys.is_empty() {
+        String::new()
+    } else {
+        format!("\n[llvm]\n{}\n", llvm_keys.join("\n"))
+    };
+
+    let std_config = std_rust_config(options.std_debug, options.std_opt_level.as_deref());
 
-    let config = r#"
+    let config = format!(
+        r#"
 changelog-seen = 2
 
 # NOTE: can't enable because using the cached llvm prevents building rust-lld,
@@ -358,77 +951,281 @@ changelog-seen = 2
 #download-ci-llvm = true
 
 [build]
-target = ["wasm32-wasmer-wasi", "wasm64-wasmer-wasi"]
+target = [{target_list}]
 extended = true
 tools = [ "clippy", "rustfmt" ]
 configure-args = []
-
+{vendor}
 [rust]
 lld = true
 llvm-tools = true
+{std_config}
+{target_config}{llvm}"#
+    );
 
-[target.wasm32-wasmer-wasi]
-wasi-root = "../wasix-libc/sysroot32"
+    let overrides_path = options.root.join(RUST_CONFIG_OVERRIDES_FILE);
+    let config = if overrides_path.is_file() {
+        info!(
+            "Applying config.toml overrides from {}",
+            overrides_path.display()
+        );
+        let overrides = std::fs::read_to_string(&overrides_path)
+            .with_context(|| format!("Could not read {}", overrides_path.display()))?;
+        merge_rust_config(&config, &overrides)
+            .with_context(|| format!("Invalid config overrides in {}", overrides_path.display()))?
+    } else {
+        config
+    };
 
-[target.wasm64-wasmer-wasi]
-wasi-root = "../wasix-libc/sysroot64"
-"#;
+    let config_path = rust_dir.join("config.toml");
+    if crate::utils::is_dry_run() {
+        info!("Would write {}:\n{config}", config_path.display());
+    } else {
+        std::fs::write(&config_path, config)?;
+    }
+
+    let jobs = options.jobs().to_string();
+    info!("Building Rust with {jobs} parallel jobs");
 
-    std::fs::write(rust_dir.join("config.toml"), config)?;
+    // sccache can also wrap the rustc invocations of x.py; ccache only
+    // handles the C/C++ of LLVM via `llvm.ccache`.
+    let rustc_wrapper = options
+        .compiler_cache
+        .as_ref()
+        .filter(|cache| cache.file_stem().is_some_and(|stem| stem == "sccache"));
 
     // Stage 1.
     let mut cmd = Command::new("python3");
-    cmd.args(["x.py", "build"]);
+    cmd.args(["x.py", "build", "-j", &jobs]);
     if let Some(triple) = host_triple {
         cmd.args(["--host", triple]);
     }
+    if let Some(wrapper) = rustc_wrapper {
+        cmd.env("RUSTC_WRAPPER", wrapper);
+    }
     cmd.current_dir(&rust_dir).run_verbose()?;
 
     // Stage 2.
     let mut cmd = Command::new("python3");
     cmd.arg(rust_dir.join("x.py"))
-        .args(["build", "--stage", "2"]);
+        .args(["build", "--stage", "2", "-j", &jobs]);
     if let Some(triple) = host_triple {
         cmd.args(["--host", triple]);
     }
+    if let Some(wrapper) = rustc_wrapper {
+        cmd.env("RUSTC_WRAPPER", wrapper);
+    }
     cmd.current_dir(&rust_dir).run_verbose()?;
 
-    eprintln!("Rust build complete!");
+    if crate::utils::is_dry_run() {
+        return Ok(None);
+    }
+    info!("Rust build complete!");
 
-    if let Some(triple) = host_triple {
-        let dir = rust_dir.join("build").join(triple).join("stage2");
-        Ok(RustBuildOutput {
-            target: triple.to_string(),
-            toolchain_dir: dir,
-        })
+    // Docs are a nice-to-have and must not fail an otherwise good build.
+    if options.build_docs {
+        if let Err(err) = build_std_docs(&rust_dir, host_triple, &options.target_bits) {
+            warn(format!("Could not build the wasix std docs: {err:#}"));
+        }
+    }
+
+    find_stage2(&rust_dir, host_triple).map(Some)
+}
+
+/// Deep-merge the user's `overrides` over the generated rust `config`.
+///
+/// The build targets and their `wasi-root` stay as generated, since the
+/// toolchain can't be built without them.
+fn merge_rust_config(config: &str, overrides: &str) -> Result<String, anyhow::Error> {
+    let mut config: toml::Value = toml::from_str(config).context("Invalid generated config")?;
+    let overrides: toml::Value = toml::from_str(overrides)?;
+    merge_toml(&mut config, overrides, &mut Vec::new());
+    Ok(toml::to_string(&config)?)
+}
+
+fn merge_toml(base: &mut toml::Value, overrides: toml::Value, path: &mut Vec<String>) {
+    let (base, overrides) = match (base, overrides) {
+        (toml::Value::Table(base), toml::Value::Table(overrides)) => (base, overrides),
+        (base, overrides) => {
+            *base = overrides;
+            return;
+        }
+    };
+    for (key, value) in overrides {
+        path.push(key);
+        let protected = match path.as_slice() {
+            [build, target] => build == "build" && target == "target",
+            [target, triple, key] => {
+                target == "target"
+                    && key == "wasi-root"
+                    && WASIX_TARGETS.iter().any(|(t, _)| t == triple)
+            }
+            _ => false,
+        };
+        let key = path.last().unwrap().clone();
+        if protected {
+            warn(format!(
+                "Ignoring config override {}: it is set by cargo-wasix",
+                path.join(".")
+            ));
+        } else if let Some(existing) = base.get_mut(&key) {
+            merge_toml(existing, value, path);
+        } else {
+            base.insert(key, value);
+        }
+        path.pop();
+    }
+}
+
+/// Build the std docs for the wasix targets and move them to
+/// [`Config::std_docs_dir`].
+fn build_std_docs(
+    rust_dir: &Path,
+    host_triple: Option<&str>,
+    target_bits: &[u32],
+) -> Result<(), anyhow::Error> {
+    let docs_root = Config::std_docs_dir()?;
+    for (target, bits) in WASIX_TARGETS {
+        if !target_bits.contains(bits) {
+            continue;
+        }
+        info!("Building std docs for {target}...");
+        let mut cmd = Command::new("python3");
+        cmd.arg(rust_dir.join("x.py")).args([
+            "doc",
+            "--stage",
+            "2",
+            "library/std",
+            "--target",
+            target,
+        ]);
+        if let Some(triple) = host_triple {
+            cmd.args(["--host", triple]);
+        }
+        cmd.current_dir(rust_dir).run_verbose()?;
+
+        let built = rust_dir.join("build").join(target).join("doc");
+        if !built.join("std").join("index.html").is_file() {
+            bail!("x.py doc did not produce docs in {}", built.display());
+        }
+        let out = docs_root.join(target);
+        if out.exists() {
+            std::fs::remove_dir_all(&out)
+                .with_context(|| format!("Could not remove {}", out.display()))?;
+        }
+        std::fs::create_dir_all(&out)
+            .with_context(|| format!("Could not create {}", out.display()))?;
+        let mut copy_options = fs_extra::dir::CopyOptions::new();
+        copy_options.content_only = true;
+        fs_extra::dir::copy(&built, &out, &copy_options)
+            .with_context(|| format!("Could not copy docs to {}", out.display()))?;
+        info!("std docs for {target} are at {}", out.display());
+    }
+    Ok(())
+}
+
+/// Open the std docs built by `build-toolchain` for the wasm32 or wasm64
+/// target.
+pub fn open_std_docs(config: &Config, is64bit: bool) -> Result<(), anyhow::Error> {
+    let target = if is64bit {
+        "wasm64-wasmer-wasi"
     } else {
-        // Find target.
-        // TODO: properly detect host triple from output?
-        // Currently could return the wrong result if multiple hosts were built.
-        for res in std::fs::read_dir(rust_dir.join("build"))? {
+        "wasm32-wasmer-wasi"
+    };
+    let index = Config::std_docs_dir()?
+        .join(target)
+        .join("std")
+        .join("index.html");
+    if !index.is_file() {
+        bail!(
+            "No std docs found at {} - build them with `WASIX_BUILD_DOCS=1 cargo wasix build-toolchain`",
+            index.display()
+        );
+    }
+    config.status("Opening", &index.display().to_string());
+
+    let mut cmd = if cfg!(target_os = "macos") {
+        Command::new("open")
+    } else if cfg!(target_os = "windows") {
+        let mut cmd = Command::new("cmd");
+        cmd.args(["/C", "start", ""]);
+        cmd
+    } else {
+        Command::new("xdg-open")
+    };
+    if cmd.arg(&index).run().is_err() {
+        config.info(&format!("Open {} in your browser", index.display()));
+    }
+    Ok(())
+}
+
+/// Locate the stage2 toolchain produced by x.py.
+///
+/// Honours a `build-dir` set in the `[build]` section of config.toml and looks
+/// for `*/stage2/bin/rustc` instead of assuming a fixed layout.
+fn find_stage2(
+    rust_dir: &Path,
+    host_triple: Option<&str>,
+) -> Result<RustBuildOutput, anyhow::Error> {
+    let config_path = rust_dir.join("config.toml");
+    let custom_build_dir = match std::fs::read_to_string(&config_path) {
+        Ok(raw) => {
+            let config: toml::Value = toml::from_str(&raw)
+                .with_context(|| format!("Could not parse {}", config_path.display()))?;
+            config
+                .get("build")
+                .and_then(|b| b.get("build-dir"))
+                .and_then(|d| d.as_str())
+                .map(PathBuf::from)
+        }
+        Err(_) => None,
+    };
+    let build_dir = rust_dir.join(custom_build_dir.as_deref().unwrap_or(Path::new("build")));
+
+    let mut found = Vec::new();
+    let mut contents = Vec::new();
+    if let Ok(iter) = std::fs::read_dir(&build_dir) {
+        for res in iter {
             let entry = res?;
+            let name = entry.file_name().to_string_lossy().to_string();
             let toolchain_dir = entry.path().join("stage2");
-            if toolchain_dir.is_dir() {
-                let target = entry.file_name().to_string_lossy().to_string();
-                return Ok(RustBuildOutput {
-                    target,
+            if toolchain_dir.join("bin").join("rustc").is_file() {
+                found.push(RustBuildOutput {
+                    target: name.clone(),
                     toolchain_dir,
                 });
             }
+            contents.push(name);
         }
-
-        bail!("Could not find build directory")
     }
+    contents.sort();
+
+    let out = match host_triple {
+        Some(triple) => found.into_iter().find(|out| out.target == triple),
+        // TODO: properly detect host triple from output?
+        // Currently could return the wrong result if multiple hosts were built.
+        None => found.into_iter().next(),
+    };
+    out.with_context(|| {
+        format!(
+            "Could not find a stage2 rustc (*/stage2/bin/rustc) in build directory {} - it contains: [{}]",
+            build_dir.display(),
+            contents.join(", ")
+        )
+    })
 }
 
 /// Try to get the host target triple.
 ///
 /// Only checks for targets that have pre-built toolchains.
 #[allow(unreachable_code)]
-fn guess_host_target() -> Option<&'static str> {
+pub(crate) fn guess_host_target() -> Option<&'static str> {
     #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
     return Some("x86_64-unknown-linux-gnu");
 
+    #[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+    return Some("aarch64-unknown-linux-gnu");
+
     #[cfg(all(target_arch = "x86_64", target_os = "macos"))]
     return Some("x86_64-apple-darwin");
 
@@ -436,101 +1233,447 @@ fn guess_host_target() -> Option<&'static str> {
     return Some("aarch64-apple-darwin");
 
     #[cfg(all(target_arch = "x86_64", target_os = "windows"))]
-    return Some("x86_64-apple-darwin");
+    return Some("x86_64-pc-windows-msvc");
 
     None
 }
 
-/// Release returned by Github API.
-#[derive(serde::Deserialize)]
-struct GithubReleaseData {
-    assets: Vec<GithubAsset>,
-    tag_name: String,
+/// File in the toolchains dir with the unix time of the last
+/// `WASIX_AUTO_UPDATE` check.
+const AUTO_UPDATE_CHECK_FILE: &str = ".auto-update-check";
+
+/// Whether `toolchain` is the release pinned by `options`, if any.
+fn is_pinned_release(toolchain: &RustupToolchain, options: &DownloadOptions) -> bool {
+    let Some(tag) = &options.version else {
+        return true;
+    };
+    let meta = toolchain
+        .path
+        .parent()
+        .and_then(|dir| ToolchainMeta::load(dir).ok().flatten());
+    match meta {
+        Some(meta) if &meta.tag == tag => true,
+        Some(meta) => {
+            info!(
+                "Installed toolchain is release {}, but release {tag} is pinned",
+                meta.tag
+            );
+            false
+        }
+        // Locally built toolchains are not from any release.
+        None => {
+            info!("Installed toolchain is not a release, but release {tag} is pinned");
+            false
+        }
+    }
 }
 
-/// Release asset returned by Github API.
-#[derive(serde::Deserialize)]
-struct GithubAsset {
-    browser_download_url: String,
-    name: String,
+/// The latest release tag, if it is not `tag` and an auto update check is
+/// due.
+///
+/// Never checks offline or with a pinned release. The time of the check is
+/// recorded in `stamp` even if it fails, so an unreachable API does not slow
+/// down every invocation.
+fn newer_release(
+    tag: &str,
+    is_offline: bool,
+    options: &DownloadOptions,
+    stamp: &Path,
+) -> Option<String> {
+    let interval = options.auto_update?;
+    if is_offline || options.version.is_some() || options.archive_dir.is_some() {
+        return None;
+    }
+    let checked_at = std::fs::read_to_string(stamp)
+        .ok()
+        .and_then(|v| v.trim().parse::<u64>().ok());
+    if checked_at.is_some_and(|t| unix_now().saturating_sub(t) < interval.as_secs()) {
+        return None;
+    }
+
+    let latest = http_client().and_then(|client| fetch_release(&client, options));
+    if let Err(err) = std::fs::write(stamp, unix_now().to_string()) {
+        debug!(
+            "Could not record the update check in {}: {err}",
+            stamp.display()
+        );
+    }
+    match latest {
+        Ok(release) if release.tag_name != tag => Some(release.tag_name),
+        Ok(_) => None,
+        Err(err) => {
+            warn(format!(
+                "Could not check for a newer wasix toolchain: {err:#}"
+            ));
+            None
+        }
+    }
+}
+
+/// Replace `toolchain` with the latest release if it is an older one, as
+/// `WASIX_AUTO_UPDATE` says.
+///
+/// A failed update keeps the installed toolchain.
+fn auto_update(
+    toolchain: RustupToolchain,
+    is_offline: bool,
+    options: &DownloadOptions,
+) -> Result<RustupToolchain, anyhow::Error> {
+    if options.auto_update.is_none() {
+        return Ok(toolchain);
+    }
+    // Locally built toolchains are not from any release.
+    let Some(meta) = toolchain
+        .path
+        .parent()
+        .and_then(|dir| ToolchainMeta::load(dir).ok().flatten())
+    else {
+        return Ok(toolchain);
+    };
+    let root = Config::toolchain_dir()?;
+    let Some(tag) = newer_release(
+        &meta.tag,
+        is_offline,
+        options,
+        &root.join(AUTO_UPDATE_CHECK_FILE),
+    ) else {
+        return Ok(toolchain);
+    };
+
+    info!(
+        "Updating the wasix toolchain from release {} to {tag}",
+        meta.tag
+    );
+    let mut options = options.clone();
+    options.version = Some(tag);
+    match install_prebuilt_toolchain(&options, &root) {
+        Ok(updated) => Ok(updated),
+        Err(err) => {
+            warn(format!(
+                "Could not update the wasix toolchain, keeping release {}: {err:#}",
+                meta.tag
+            ));
+            Ok(toolchain)
+        }
+    }
+}
+
+/// Make sure the sysroot at `dir` holds wasm{bits} libraries, by looking at
+/// the `lib/wasm{bits}-*` directories it contains.
+pub(crate) fn check_sysroot_arch(dir: &Path, bits: u32) -> Result<(), anyhow::Error> {
+    let lib = dir.join("lib");
+    let mut arches = Vec::new();
+    for entry in
+        std::fs::read_dir(&lib).with_context(|| format!("Could not read {}", lib.display()))?
+    {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if let Some(arch) = name.split('-').next().filter(|a| a.starts_with("wasm")) {
+            arches.push(arch.to_string());
+        }
+    }
+    let expected = format!("wasm{bits}");
+    if !arches.contains(&expected) {
+        arches.sort();
+        arches.dedup();
+        bail!(
+            "sysroot contents don't match their directory names: {} should contain {expected} libraries, but contains [{}]",
+            dir.display(),
+            arches.join(", ")
+        );
+    }
+    Ok(())
 }
 
 /// Download a pre-built toolchain from Github releases.
-fn download_toolchain(target: &str, toolchains_root_dir: &Path) -> Result<PathBuf, anyhow::Error> {
-    let mut headers = reqwest::header::HeaderMap::new();
-
-    // Use a GITHUB_TOKEN env var as auth token if present.
-    // Useful on Github Actions CI, since those IPs often get rate limited.
-    if let Ok(token) = std::env::var("GITHUB_TOKEN") {
-        headers.insert("authorization", format!("Bearer {token}").parse().unwrap());
-    }
-    let client = reqwest::blocking::Client::builder()
-        .user_agent("cargo-wasix")
-        .default_headers(headers)
-        .build()?;
-
-    let repo = RUST_REPO
-        .trim_start_matches("https://github.com/")
-        .trim_end_matches(".git");
-    let release_url = format!("https://api.github.com/repos/{repo}/releases/latest");
-    let release: GithubReleaseData = client
-        .get(&release_url)
-        .send()?
-        .error_for_status()
-        .context("Could not download release info")?
-        .json()
-        .context("Could not deserialize release info")?;
+fn download_toolchain(
+    options: &DownloadOptions,
+    target: &str,
+    toolchains_root_dir: &Path,
+) -> Result<PathBuf, anyhow::Error> {
+    let client = http_client()?;
+    let release_url = options.release_url();
+    check_network(&client, &url_origin(&release_url), "--offline")?;
+    let release = fetch_selected_release(&client, options)?;
+    let _group = crate::utils::group(&format!("Install wasix toolchain {}", release.tag_name));
+    if release.prerelease {
+        info!("Using prerelease {}", release.tag_name);
+    }
 
     // Try to find the asset for the wanted target triple.
-    let rust_asset_name = format!("rust-toolchain-{target}.tar.gz");
-    let rust_asset = release
-        .assets
-        .iter()
-        .find(|asset| asset.name == rust_asset_name)
-        .with_context(|| {
-            format!(
-                "Release {} does not have a prebuilt toolchain for host {}",
-                release.tag_name, target
-            )
-        })?;
+    let rust_asset = release.rust_asset(target)?;
+    let sysroot_asset = release.sysroot_asset()?;
 
-    // Find sysroot asset.
-    let sysroot_asset = release
-        .assets
-        .iter()
-        .find(|asset| asset.name == "wasix-libc.tar.gz")
-        .with_context(|| {
-            format!(
-                "Release {} does not have the sysroot asset",
+    if let Some(max) = options.max_download_size {
+        let total = rust_asset.size + sysroot_asset.size;
+        if total > max {
+            bail!(
+                "Refusing to download {} from release {}, which exceeds the maximum download size of {}:\n  {} ({})\n  {} ({})",
+                format_bytes(total),
                 release.tag_name,
+                format_bytes(max),
+                rust_asset.name,
+                format_bytes(rust_asset.size),
+                sysroot_asset.name,
+                format_bytes(sysroot_asset.size),
+            );
+        }
+    }
+
+    install_release_assets(
+        &client,
+        options,
+        &release.tag_name,
+        target,
+        &checked_source(&client, options, &release, sysroot_asset)?,
+        &checked_source(&client, options, &release, rust_asset)?,
+        toolchains_root_dir,
+    )
+}
+
+/// Install the pre-built toolchain for `target` from `WASIX_TOOLCHAIN_ARCHIVE_DIR`
+/// if set, or else download it.
+pub(crate) fn obtain_toolchain(
+    options: &DownloadOptions,
+    target: &str,
+    toolchains_root_dir: &Path,
+) -> Result<PathBuf, anyhow::Error> {
+    match &options.archive_dir {
+        Some(dir) => install_local_archives(options, target, dir, toolchains_root_dir)
+            .with_context(|| format!("Could not install toolchain from {}", dir.display())),
+        None => download_toolchain(options, target, toolchains_root_dir),
+    }
+}
+
+/// Install the `wasix-libc.tar.gz` and `rust-toolchain-{target}.tar.gz`
+/// release archives copied to `dir`.
+///
+/// The archives carry no release tag, so the toolchain is named by their
+/// checksums instead.
+fn install_local_archives(
+    options: &DownloadOptions,
+    target: &str,
+    dir: &Path,
+    toolchains_root_dir: &Path,
+) -> Result<PathBuf, anyhow::Error> {
+    let source = |name: String| -> Result<AssetSource, anyhow::Error> {
+        let path = dir.join(&name);
+        let file = std::fs::File::open(&path).with_context(|| {
+            format!(
+                "Could not open {} - WASIX_TOOLCHAIN_ARCHIVE_DIR must contain wasix-libc.tar.gz and rust-toolchain-{target}.tar.gz",
+                path.display()
             )
         })?;
+        let sha256 = HashReader::new(file)
+            .finish()
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        let path = path.canonicalize()?;
+        Ok(AssetSource {
+            name,
+            url: format!("file://{}", path.display()),
+            sha256: Some(sha256),
+        })
+    };
+    let sysroot = source("wasix-libc.tar.gz".to_string())?;
+    let rust = source(format!("rust-toolchain-{target}.tar.gz"))?;
+    let tag = local_archive_tag(&sysroot, &rust);
+    let _group = crate::utils::group(&format!("Install wasix toolchain {tag}"));
+    install_release_assets(
+        &http_client()?,
+        options,
+        &tag,
+        target,
+        &sysroot,
+        &rust,
+        toolchains_root_dir,
+    )
+}
+
+/// Tag of a toolchain installed from local archives.
+fn local_archive_tag(sysroot: &AssetSource, rust: &AssetSource) -> String {
+    let short = |asset: &AssetSource| {
+        asset
+            .sha256
+            .as_deref()
+            .unwrap_or_default()
+            .chars()
+            .take(8)
+            .collect::<String>()
+    };
+    format!("local-{}{}", short(sysroot), short(rust))
+}
+
+/// Name of the content addressed dir for a toolchain with the given archive
+/// checksums, so identical assets always install to the same path.
+fn content_dir_name(target: &str, sysroot_sha256: &str, rust_sha256: &str) -> String {
+    use sha2::Digest;
+    let input = format!(
+        "{target}\n{}\n{}\n",
+        sysroot_sha256.to_ascii_lowercase(),
+        rust_sha256.to_ascii_lowercase()
+    );
+    let hash = sha2::Sha256::digest(input.as_bytes());
+    let id: String = hash[..8].iter().map(|b| format!("{b:02x}")).collect();
+    format!("{target}_sha256-{id}")
+}
 
-    let toolchain_dir = toolchains_root_dir.join(format!("{target}_{}", release.tag_name));
-    if toolchain_dir.is_dir() {
-        eprintln!(
-            "Toolchain path {} already exists - deleting existing files!",
-            toolchain_dir.display()
+/// Whether `dir` holds a complete toolchain of the current layout.
+fn is_complete_install(dir: &Path) -> bool {
+    check_toolchain_layout(dir).is_ok() && dir.join("rust").join("bin").join("rustc").is_file()
+}
+
+/// Download and extract the sysroot and rust toolchain assets of release
+/// `tag` into a new toolchain dir below `toolchains_root_dir`.
+///
+/// With [`DownloadOptions::content_addressed`] the dir is named by the asset
+/// checksums instead, and an existing install of the same content is reused.
+///
+/// The assets are extracted into a hidden staging dir next to the toolchain
+/// dir, which is only renamed into place once the install is complete and
+/// removed on any error, so an interrupted install never leaves a partial
+/// toolchain behind.
+pub(crate) fn install_release_assets(
+    client: &reqwest::blocking::Client,
+    options: &DownloadOptions,
+    tag: &str,
+    target: &str,
+    sysroot_asset: &AssetSource,
+    rust_asset: &AssetSource,
+    toolchains_root_dir: &Path,
+) -> Result<PathBuf, anyhow::Error> {
+    // Metadata of the toolchain being replaced, to detect sysroot changes.
+    let previous = RustupToolchain::find_by_name(RUSTUP_TOOLCHAIN_NAME)
+        .ok()
+        .flatten()
+        .and_then(|chain| ToolchainMeta::load(chain.path.parent()?).ok().flatten())
+        .filter(|meta| meta.target == target);
+
+    if options.content_addressed {
+        if let (Some(sysroot), Some(rust)) = (&sysroot_asset.sha256, &rust_asset.sha256) {
+            let dir = toolchains_root_dir.join(content_dir_name(target, sysroot, rust));
+            if is_complete_install(&dir) {
+                info!(
+                    "Toolchain with identical content already installed at {}",
+                    dir.display()
+                );
+                return Ok(dir);
+            }
+        }
+    }
+
+    // Leftovers of installs that were killed mid-way are never resumed.
+    let staging_prefix = format!(".{target}_{tag}.tmp-");
+    if let Ok(entries) = std::fs::read_dir(toolchains_root_dir) {
+        for entry in entries.flatten() {
+            if entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with(&staging_prefix)
+            {
+                drop(std::fs::remove_dir_all(entry.path()));
+            }
+        }
+    }
+    let staging_dir = toolchains_root_dir.join(format!("{staging_prefix}{}", std::process::id()));
+
+    let (sysroot_sha256, rust_sha256) = match stage_release_assets(
+        client,
+        options,
+        tag,
+        target,
+        sysroot_asset,
+        rust_asset,
+        previous.as_ref(),
+        &staging_dir,
+    ) {
+        Ok(checksums) => checksums,
+        Err(err) => {
+            drop(std::fs::remove_dir_all(&staging_dir));
+            return Err(err);
+        }
+    };
+
+    let final_dir = if options.content_addressed {
+        toolchains_root_dir.join(content_dir_name(target, &sysroot_sha256, &rust_sha256))
+    } else {
+        toolchains_root_dir.join(format!("{target}_{tag}"))
+    };
+    if options.content_addressed && is_complete_install(&final_dir) {
+        // The checksums were only known after downloading.
+        drop(std::fs::remove_dir_all(&staging_dir));
+        info!(
+            "Toolchain with identical content already installed at {}",
+            final_dir.display()
         );
-        std::fs::remove_dir_all(&toolchain_dir)?;
+        return Ok(final_dir);
+    }
+    if final_dir.exists() {
+        warn(format!(
+            "Toolchain path {} already exists - replacing it!",
+            final_dir.display()
+        ));
+    }
+    if let Err(err) = replace_dir(&staging_dir, &final_dir) {
+        drop(std::fs::remove_dir_all(&staging_dir));
+        return Err(err);
     }
 
-    // Download and extract sysroot.
-    eprintln!(
-        "Downloading sysroot from url '{}'...",
-        &sysroot_asset.browser_download_url
+    info!(
+        "Downloaded toolchain {} to {}",
+        target,
+        final_dir.join("rust").display()
     );
-    let res = client
-        .get(&sysroot_asset.browser_download_url)
-        .send()?
-        .error_for_status()?;
 
-    eprintln!("Extracting...");
-    let decoder = flate2::read::GzDecoder::new(res);
-    let mut archive = tar::Archive::new(decoder);
+    Ok(final_dir)
+}
 
+/// Move `from` to `to`, replacing whatever is at `to` only once the move
+/// succeeded.
+fn replace_dir(from: &Path, to: &Path) -> Result<(), anyhow::Error> {
+    let move_to = || {
+        std::fs::rename(from, to)
+            .with_context(|| format!("Could not move {} to {}", from.display(), to.display()))
+    };
+    if !to.exists() {
+        return move_to();
+    }
+    let name = to.file_name().unwrap_or_default().to_string_lossy();
+    let old = to.with_file_name(format!(".{name}.old-{}", std::process::id()));
+    std::fs::rename(to, &old)
+        .with_context(|| format!("Could not move {} out of the way", to.display()))?;
+    if let Err(err) = move_to() {
+        drop(std::fs::rename(&old, to));
+        return Err(err);
+    }
+    drop(std::fs::remove_dir_all(&old));
+    Ok(())
+}
+
+/// Download and extract the assets of [`install_release_assets`] into
+/// `toolchain_dir`, returning the sysroot and rust checksums.
+#[allow(clippy::too_many_arguments)]
+fn stage_release_assets(
+    client: &reqwest::blocking::Client,
+    options: &DownloadOptions,
+    tag: &str,
+    target: &str,
+    sysroot_asset: &AssetSource,
+    rust_asset: &AssetSource,
+    previous: Option<&ToolchainMeta>,
+    toolchain_dir: &Path,
+) -> Result<(String, String), anyhow::Error> {
+    // Download and extract sysroot.
+    info!("Downloading sysroot...");
     let out_dir = toolchain_dir.join("sysroot");
-    archive.unpack(&out_dir)?;
+    let cancelled = |err: anyhow::Error| {
+        if options.cancel.is_cancelled() {
+            return err.context("The toolchain install was cancelled");
+        }
+        err
+    };
+    prefetch_archives(client, options, tag, &[sysroot_asset, rust_asset]).map_err(cancelled)?;
+    let (_, sysroot_sha256) =
+        download_release_asset_with(client, options, tag, sysroot_asset, &out_dir)
+            .map_err(cancelled)?;
 
     // The archive contains a redundant additional directory. Strip it.
     let wrapper = out_dir.join("wasix-libc");
@@ -542,60 +1685,144 @@ fn download_toolchain(target: &str, toolchains_root_dir: &Path) -> Result<PathBu
 
         std::fs::remove_dir_all(wrapper).context("Could not delete intermediate directory")?;
     }
+    check_sysroot_arch(&out_dir.join("sysroot32"), 32)?;
+    check_sysroot_arch(&out_dir.join("sysroot64"), 64)?;
 
     // Download.
-    eprintln!(
-        "Downloading Rust toolchain from url '{}'...",
-        &rust_asset.browser_download_url
-    );
-    let res = client
-        .get(&rust_asset.browser_download_url)
-        .send()?
-        .error_for_status()?;
-
-    eprintln!("Extracting...");
-    let decoder = flate2::read::GzDecoder::new(res);
-    let mut archive = tar::Archive::new(decoder);
-
+    info!("Downloading Rust toolchain...");
     let rust_dir = toolchain_dir.join("rust");
-    archive.unpack(&rust_dir)?;
+    let (_, rust_sha256) = download_release_asset_with(client, options, tag, rust_asset, &rust_dir)
+        .map_err(cancelled)?;
+    check_same_build(&rust_dir, &out_dir)?;
 
     // Ensure permissions.
     #[cfg(target_family = "unix")]
     {
         use std::os::unix::fs::PermissionsExt;
 
-        let iter1 = std::fs::read_dir(rust_dir.join("bin"))?;
-        let iter2 = std::fs::read_dir(rust_dir.join(format!("lib/rustlib/{target}/bin")))?;
+        let read_bin_dir = |dir: PathBuf| {
+            std::fs::read_dir(&dir).with_context(|| {
+                format!(
+                    "Could not read {} to make the toolchain binaries executable - the downloaded toolchain is incomplete",
+                    dir.display()
+                )
+            })
+        };
+        let iter1 = read_bin_dir(rust_dir.join("bin"))?;
+        let iter2 = read_bin_dir(rust_dir.join(format!("lib/rustlib/{target}/bin")))?;
 
         // Make sure the binaries can be executed.
+        let exec_mode = match options.install_umask {
+            Some(umask) => install_mode(0o755, false, umask),
+            None => 0o755,
+        };
         for res in iter1.chain(iter2) {
             let entry = res?;
             if entry.file_type()?.is_file() {
                 let mut perms = entry.metadata()?.permissions();
-                perms.set_mode(0o755);
-                std::fs::set_permissions(entry.path(), perms)?;
+                perms.set_mode(exec_mode);
+                if let Err(err) = std::fs::set_permissions(entry.path(), perms) {
+                    warn(format!(
+                        "Could not make {} executable: {err}",
+                        entry.path().display()
+                    ));
+                }
             }
         }
+
+        if let Some(umask) = options.install_umask {
+            apply_install_umask(toolchain_dir, umask)?;
+        }
     }
 
-    eprintln!("Downloaded toolchain {} to {}", target, rust_dir.display());
+    // Before it replaces anything, make sure a toolchain for this host runs.
+    if guess_host_target() == Some(target) {
+        check_rustc(&rust_dir)?;
+    }
+
+    if let Some(message) = previous.and_then(|meta| sysroot_change(meta, &sysroot_sha256)) {
+        warn(message);
+    }
+
+    if let Some(dir) = &options.save_assets {
+        manifest::record_saved_assets(
+            dir,
+            tag,
+            target,
+            (sysroot_asset, &sysroot_sha256),
+            (rust_asset, &rust_sha256),
+        )?;
+    }
+
+    let source_url = |asset: &AssetSource| match asset.url.strip_prefix("file://") {
+        Some(_) => asset.url.clone(),
+        None => options.asset_url(tag, &asset.name, &asset.url),
+    };
+    let mut meta = ToolchainMeta::new(tag, target);
+    meta.sysroot_sha256 = Some(sysroot_sha256.clone());
+    meta.rust_sha256 = Some(rust_sha256.clone());
+    meta.sysroot_url = Some(source_url(sysroot_asset));
+    meta.rust_url = Some(source_url(rust_asset));
+    meta.save(toolchain_dir)?;
+
+    Ok((sysroot_sha256, rust_sha256))
+}
 
-    Ok(toolchain_dir)
+/// Mode of an installed file or directory with `mode` under `umask`.
+///
+/// Directories and files executable by anyone keep their execute bits.
+#[cfg(target_family = "unix")]
+fn install_mode(mode: u32, is_dir: bool, umask: u32) -> u32 {
+    let full = if is_dir || mode & 0o111 != 0 {
+        0o777
+    } else {
+        0o666
+    };
+    full & !umask
+}
+
+/// Set the permissions of everything below `path` according to `umask`.
+///
+/// Symlinks are left alone. Directories are updated after their contents,
+/// so a umask that removes owner bits cannot lock us out half way.
+#[cfg(target_family = "unix")]
+fn apply_install_umask(path: &Path, umask: u32) -> Result<(), anyhow::Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let meta = std::fs::symlink_metadata(path)
+        .with_context(|| format!("Could not read metadata of {}", path.display()))?;
+    if meta.file_type().is_symlink() {
+        return Ok(());
+    }
+    if meta.is_dir() {
+        let entries = std::fs::read_dir(path)
+            .with_context(|| format!("Could not read directory {}", path.display()))?;
+        for entry in entries {
+            apply_install_umask(&entry?.path(), umask)?;
+        }
+    }
+    let mode = install_mode(meta.permissions().mode(), meta.is_dir(), umask);
+    if let Err(err) = std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)) {
+        warn(format!(
+            "Could not set permissions {mode:o} on {}: {err}",
+            path.display()
+        ));
+    }
+    Ok(())
 }
 
 /// Tries to download a pre-built toolchain if possible, and builds the
 /// toolchain locally otherwise.
 ///
 /// Returns the path to the toolchain.
-pub fn install_prebuilt_toolchain(toolchain_dir: &Path) -> Result<RustupToolchain, anyhow::Error> {
+pub fn install_prebuilt_toolchain(
+    options: &DownloadOptions,
+    toolchain_dir: &Path,
+) -> Result<RustupToolchain, anyhow::Error> {
     if let Some(target) = guess_host_target() {
-        match download_toolchain(target, toolchain_dir) {
-            Ok(path) => RustupToolchain::link(RUSTUP_TOOLCHAIN_NAME, &path.join("rust")),
-            Err(err) => {
-                eprintln!("Could not download pre-built toolchain: {err:?}");
-                Err(err.context("Download of pre-built toolchain failed"))
-            }
+        match obtain_toolchain(options, target, toolchain_dir) {
+            Ok(path) => activate_toolchain(&path.join("rust")),
+            Err(err) => Err(err.context("Download of pre-built toolchain failed")),
         }
     } else {
         Err(anyhow::anyhow!(
@@ -604,17 +1831,112 @@ pub fn install_prebuilt_toolchain(toolchain_dir: &Path) -> Result<RustupToolchai
     }
 }
 
+/// Sanity check that the rustc of a downloaded toolchain can run on this
+/// host, so an incompatible build fails at install time instead of at the
+/// first compile, and does not replace a working install.
+fn check_rustc(rust_dir: &Path) -> Result<(), anyhow::Error> {
+    let rustc = rust_dir
+        .join("bin")
+        .join("rustc")
+        .with_extension(std::env::consts::EXE_EXTENSION);
+    debug!("Running {} --version", rustc.display());
+    let (status, stderr) = match Command::new(&rustc).arg("--version").output() {
+        Ok(output) if output.status.success() => {
+            debug!("{}", String::from_utf8_lossy(&output.stdout).trim_end());
+            return Ok(());
+        }
+        Ok(output) => (
+            output.status.to_string(),
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        ),
+        Err(err) => (err.to_string(), String::new()),
+    };
+    let cause =
+        missing_library_hint(&stderr).unwrap_or_else(|| "likely a glibc/host mismatch".to_string());
+    bail!(
+        "The downloaded toolchain binary {} failed to run ({status}), {cause} - try building the toolchain with 'cargo wasix build-toolchain'\n{}",
+        rustc.display(),
+        stderr.trim()
+    )
+}
+
+/// Print what [`install_prebuilt_toolchain`] would download, and where to,
+/// for `--dry-run`.
+pub fn plan_prebuilt_toolchain(
+    options: &DownloadOptions,
+    toolchain_dir: &Path,
+) -> Result<(), anyhow::Error> {
+    let target = guess_host_target()
+        .context("The WASIX toolchain is not available for download on this platform")?;
+    if let Some(dir) = &options.archive_dir {
+        info!(
+            "Would install wasix-libc.tar.gz and rust-toolchain-{target}.tar.gz from {}",
+            dir.display()
+        );
+        return Ok(());
+    }
+    let client = http_client()?;
+    let release = fetch_selected_release(&client, options)?;
+    let dir = toolchain_dir.join(format!("{target}_{}", release.tag_name));
+    for (asset, sub_dir) in [
+        (release.sysroot_asset()?, "sysroot"),
+        (release.rust_asset(target)?, "rust"),
+    ] {
+        let url = options.asset_url(&release.tag_name, &asset.name, &asset.browser_download_url);
+        info!(
+            "Would download {url} ({}) to {}",
+            format_bytes(asset.size),
+            dir.join(sub_dir).display()
+        );
+    }
+    Ok(())
+}
+
+/// Use the toolchain at `rust_dir`: linked into rustup, or self-contained
+/// without rustup.
+pub(crate) fn activate_toolchain(rust_dir: &Path) -> Result<RustupToolchain, anyhow::Error> {
+    if rustup_available() {
+        RustupToolchain::link(RUSTUP_TOOLCHAIN_NAME, rust_dir)
+    } else {
+        Ok(RustupToolchain::self_contained(rust_dir))
+    }
+}
+
+/// Whether rustup can be used to link and select the toolchain.
+///
+/// Without rustup, or with `WASIX_NO_RUSTUP=1`, toolchains are used
+/// self-contained: run straight from the toolchains dir.
+pub(crate) fn rustup_available() -> bool {
+    !rustup_disabled() && which::which("rustup").is_ok()
+}
+
+/// Whether `WASIX_NO_RUSTUP` asks for a self-contained toolchain.
+pub(crate) fn rustup_disabled() -> bool {
+    std::env::var("WASIX_NO_RUSTUP").is_ok_and(|v| v == "1" || v == "true")
+}
+
 #[derive(Clone, Debug)]
 pub struct RustupToolchain {
     pub name: String,
     pub path: PathBuf,
+    /// Not linked into rustup: rustc and cargo are run by path.
+    pub self_contained: bool,
 }
 
 impl RustupToolchain {
+    /// A toolchain at `dir` that is used without rustup.
+    pub(crate) fn self_contained(dir: &Path) -> Self {
+        Self {
+            name: RUSTUP_TOOLCHAIN_NAME.to_string(),
+            path: dir.into(),
+            self_contained: true,
+        }
+    }
+
     /// Verify if the "wasix" toolchain is present in rustup.
     ///
     /// Returns the path to the toolchain.
-    fn find_by_name(name: &str) -> Result<Option<Self>, anyhow::Error> {
+    pub(crate) fn find_by_name(name: &str) -> Result<Option<Self>, anyhow::Error> {
         let out = Command::new("rustup")
             .args(["toolchain", "list", "--verbose"])
             .capture_stdout()?;
@@ -626,6 +1948,7 @@ impl RustupToolchain {
             Ok(Some(Self {
                 name: name.to_string(),
                 path: path.into(),
+                self_contained: false,
             }))
         } else {
             Ok(None)
@@ -633,8 +1956,8 @@ impl RustupToolchain {
     }
 
     /// Link the "wasix" toolchain to a local directory via rustup.
-    fn link(name: &str, dir: &Path) -> Result<Self, anyhow::Error> {
-        eprintln!(
+    pub(crate) fn link(name: &str, dir: &Path) -> Result<Self, anyhow::Error> {
+        info!(
             "Activating rustup toolchain {} at {}...",
             name,
             dir.display()
@@ -652,29 +1975,116 @@ impl RustupToolchain {
         // If already present, unlink first.
         // This is required because otherwise rustup can get in a buggy state.
         if Self::find_by_name(name)?.is_some() {
-            Command::new("rustup")
-                .args(["toolchain", "remove", name])
-                .run()
-                .context("Could not remove wasix toolchain")?;
+            Self::unlink(name)?;
         }
 
+        SysrootCache::invalidate();
+        EnsureMemo::invalidate();
         Command::new("rustup")
             .args(["toolchain", "link", name])
             .arg(dir)
             .run_verbose()
-            .context("Could not link toolchain: rustup not installed?")?;
+            .with_context(|| ToolchainError::LinkFailed {
+                name: name.to_string(),
+                path: dir.to_path_buf(),
+            })?;
 
-        eprintln!("rustup toolchain {name} was linked and is now available!");
+        info!("rustup toolchain {name} was linked and is now available!");
 
         Ok(Self {
             name: name.to_string(),
             path: dir.into(),
+            self_contained: false,
         })
     }
 
-    pub fn sysroot_dir(&self, is64bit: bool) -> Option<PathBuf> {
+    /// Remove the rustup toolchain link.
+    ///
+    /// The linked directory itself is left untouched.
+    pub(crate) fn unlink(name: &str) -> Result<(), anyhow::Error> {
+        SysrootCache::invalidate();
+        EnsureMemo::invalidate();
+        Command::new("rustup")
+            .args(["toolchain", "remove", name])
+            .run()
+            .context("Could not remove wasix toolchain")
+    }
+
+    /// The sysroot reported by `rustc --print sysroot`.
+    ///
+    /// The result is cached until the toolchain's rustc binary changes or the
+    /// toolchain is relinked.
+    pub(crate) fn rust_sysroot(&self) -> Result<PathBuf, anyhow::Error> {
+        let modified = SysrootCache::rustc_modified(&self.path);
+        if let Some(modified) = modified {
+            if let Some(cache) = SysrootCache::load() {
+                if cache.toolchain_path == self.path && cache.rustc_modified == modified {
+                    return Ok(cache.sysroot);
+                }
+            }
+        }
+
+        let sysroot = self
+            .rustc()
+            .arg("--print")
+            .arg("sysroot")
+            .capture_stdout()
+            .map(|out| PathBuf::from(out.trim()))
+            .context("Could not execute rustc")?;
+
+        if let Some(rustc_modified) = modified {
+            SysrootCache {
+                toolchain_path: self.path.clone(),
+                rustc_modified,
+                sysroot: sysroot.clone(),
+            }
+            .save();
+        }
+        Ok(sysroot)
+    }
+
+    /// A `rustc` command that runs this toolchain's compiler.
+    pub(crate) fn rustc(&self) -> Command {
+        if self.self_contained {
+            Command::new(self.path.join("bin").join("rustc"))
+        } else {
+            let mut cmd = Command::new("rustc");
+            cmd.arg(format!("+{}", self.name));
+            cmd
+        }
+    }
+
+    /// Make cargo and rustc spawned by this process use the toolchain.
+    ///
+    /// Through rustup this selects the toolchain by name. Self-contained
+    /// toolchains are put first on `PATH` instead, so their own cargo is
+    /// used if they ship one and the cargo already on `PATH` otherwise.
+    pub fn activate(&self) -> Result<(), anyhow::Error> {
+        if !self.self_contained {
+            std::env::set_var("RUSTUP_TOOLCHAIN", &self.name);
+            return Ok(());
+        }
+        let bin = self.path.join("bin");
+        let mut paths = vec![bin.clone()];
+        paths.extend(
+            std::env::var_os("PATH")
+                .iter()
+                .flat_map(std::env::split_paths),
+        );
+        let path = std::env::join_paths(paths).context("Could not add the toolchain to PATH")?;
+        std::env::set_var("PATH", path);
+        std::env::set_var("RUSTC", bin.join("rustc"));
+        std::env::set_var("RUSTDOC", bin.join("rustdoc"));
+        Ok(())
+    }
+
+    /// Path of the libc sysroot for the given bitness.
+    ///
+    /// With `threads` the thread-enabled variant is resolved instead.
+    pub fn sysroot_dir(&self, is64bit: bool, threads: bool) -> Option<PathBuf> {
         let size = if is64bit { 64 } else { 32 };
-        let path = self.path.parent()?.join(format!("sysroot{size}"));
+        let suffix = if threads { "-threads" } else { "" };
+        let path = self.path.parent()?.join(format!("sysroot{size}{suffix}"));
         if path.is_dir() {
             Some(path)
         } else {
@@ -683,6 +2093,131 @@ impl RustupToolchain {
     }
 }
 
+/// Cached `rustc --print sysroot` output of the linked toolchain.
+///
+/// Keyed on the toolchain path and the modification time of its rustc.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SysrootCache {
+    toolchain_path: PathBuf,
+    /// Nanoseconds since the unix epoch.
+    rustc_modified: u128,
+    sysroot: PathBuf,
+}
+
+impl SysrootCache {
+    fn path() -> Result<PathBuf, anyhow::Error> {
+        Config::data_dir().map(|d| d.join("sysroot-cache.json"))
+    }
+
+    fn rustc_modified(toolchain_path: &Path) -> Option<u128> {
+        let modified = std::fs::metadata(toolchain_path.join("bin").join("rustc"))
+            .and_then(|m| m.modified())
+            .ok()?;
+        modified
+            .duration_since(std::time::UNIX_EPOCH)
+            .ok()
+            .map(|d| d.as_nanos())
+    }
+
+    fn load() -> Option<Self> {
+        let data = std::fs::read(Self::path().ok()?).ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    /// Best effort, a missing cache only costs a rustc invocation.
+    fn save(&self) {
+        if let (Ok(path), Ok(data)) = (Self::path(), serde_json::to_vec(self)) {
+            std::fs::write(path, data).ok();
+        }
+    }
+
+    fn invalidate() {
+        if let Ok(path) = Self::path() {
+            std::fs::remove_file(path).ok();
+        }
+    }
+}
+
+/// Memo of the last successful [`ensure_toolchain`] run.
+///
+/// Lets commands run in quick succession skip the lock and the rustup and
+/// rustc checks. Only trusted for [`EnsureMemo::TTL`] and while the toolchain's
+/// rustc is unchanged, and dropped whenever a toolchain is linked or unlinked.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub(crate) struct EnsureMemo {
+    name: String,
+    toolchain_path: PathBuf,
+    /// Nanoseconds since the unix epoch.
+    rustc_modified: u128,
+    is64bit: bool,
+    rustc_requirement: Option<String>,
+    #[serde(default)]
+    self_contained: bool,
+    /// Seconds since the unix epoch.
+    verified_at: u64,
+}
+
+impl EnsureMemo {
+    const TTL: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+
+    fn path() -> Result<PathBuf, anyhow::Error> {
+        Config::data_dir().map(|d| d.join("ensure-memo.json"))
+    }
+
+    fn now() -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default()
+    }
+
+    fn new(
+        toolchain: &RustupToolchain,
+        is64bit: bool,
+        rustc_requirement: Option<String>,
+    ) -> Option<Self> {
+        Some(Self {
+            name: toolchain.name.clone(),
+            rustc_modified: SysrootCache::rustc_modified(&toolchain.path)?,
+            toolchain_path: toolchain.path.clone(),
+            is64bit,
+            rustc_requirement,
+            self_contained: toolchain.self_contained,
+            verified_at: Self::now(),
+        })
+    }
+
+    /// Returns the memoized toolchain if it is still valid.
+    fn lookup(is64bit: bool, rustc_requirement: Option<&str>) -> Option<RustupToolchain> {
+        let data = std::fs::read(Self::path().ok()?).ok()?;
+        let memo: Self = serde_json::from_slice(&data).ok()?;
+        let fresh = Self::now().saturating_sub(memo.verified_at) < Self::TTL.as_secs();
+        let valid = fresh
+            && memo.is64bit == is64bit
+            && memo.rustc_requirement.as_deref() == rustc_requirement
+            && memo.self_contained != rustup_available()
+            && SysrootCache::rustc_modified(&memo.toolchain_path) == Some(memo.rustc_modified);
+        valid.then_some(RustupToolchain {
+            name: memo.name,
+            path: memo.toolchain_path,
+            self_contained: memo.self_contained,
+        })
+    }
+
+    /// Best effort, a missing memo only costs a full check.
+    fn save(&self) {
+        if let (Ok(path), Ok(data)) = (Self::path(), serde_json::to_vec(self)) {
+            std::fs::write(path, data).ok();
+        }
+    }
+
+    pub(crate) fn invalidate() {
+        if let Ok(path) = Self::path() {
+            std::fs::remove_file(path).ok();
+        }
+    }
+}
+
 /// Makes sure that the wasix toolchain is available.
 ///
 /// Tries to download a pre-built toolchain if possible, and builds the toolchain
@@ -695,52 +2230,1087 @@ pub fn ensure_toolchain(
     _config: &Config,
     is64bit: bool,
     is_offline: bool,
+    download_options: &DownloadOptions,
 ) -> Result<RustupToolchain, anyhow::Error> {
+    let rustc_requirement = std::env::var("WASIX_RUSTC_VERSION").ok();
+    // The memo does not know which release a toolchain is.
+    if download_options.version.is_none() {
+        if let Some(toolchain) = EnsureMemo::lookup(is64bit, rustc_requirement.as_deref()) {
+            return Ok(toolchain);
+        }
+    }
+
     let _lock = Config::acquire_lock()?;
 
-    let toolchain = if let Some(chain) = RustupToolchain::find_by_name(RUSTUP_TOOLCHAIN_NAME)? {
-        chain
-    } else if !is_offline {
-        install_prebuilt_toolchain(&Config::toolchain_dir()?)?
+    let toolchain = if !rustup_available() {
+        let chain = self_contained_toolchain(is_offline, download_options)?;
+        auto_update(chain, is_offline, download_options)?
+    } else if let Some(chain) = RustupToolchain::find_by_name(RUSTUP_TOOLCHAIN_NAME)?
+        .filter(|chain| is_pinned_release(chain, download_options))
+    {
+        auto_update(chain, is_offline, download_options)?
     } else {
+        acquire_toolchain(is_offline, download_options)?
+    };
+
+    // Downloaded toolchains must match the layout we expect. Locally built
+    // toolchains live outside the toolchains dir and are not checked.
+    if let Some(dir) = toolchain.path.parent() {
+        if dir.starts_with(Config::toolchain_dir()?) {
+            check_toolchain_layout(dir)?;
+        }
+    }
+
+    // Sanity check the toolchain.
+    let rust_sysroot = toolchain.rust_sysroot()?;
+    let canonical = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    if canonical(&toolchain.path) != canonical(&rust_sysroot) {
         bail!(
-            r#"
-Could not detect wasix toolchain, and could not install because CARGO_WASIX_OFFLINE is set.
+            "The wasix toolchain at {} reports the sysroot {} - relink it with `cargo wasix toolchain relink`",
+            toolchain.path.display(),
+            rust_sysroot.display()
+        );
+    }
+
+    let (target, bits, other) = if is64bit {
+        ("wasm64-wasmer-wasi", 64, "wasm32-wasmer-wasi")
+    } else {
+        ("wasm32-wasmer-wasi", 32, "wasm64-wasmer-wasi")
+    };
+    let rustlib = rust_sysroot.join("lib/rustlib");
+    if !rustlib.join(target).exists() {
+        let err = anyhow::Error::new(ToolchainError::TargetMissing {
+            toolchain: toolchain.name.clone(),
+            path: toolchain.path.clone(),
+            target: target.to_string(),
+        });
+        if rustlib.join(other).exists() {
+            return Err(err.context(format!(
+                "The wasix toolchain {} at {} was built without wasm{bits} support - rebuild it with WASIX_TARGET_BITS=both or {bits}",
+                toolchain.name,
+                toolchain.path.display()
+            )));
+        }
+        return Err(err);
+    }
+
+    // Projects can require a specific underlying Rust version.
+    if let Some(requirement) = &rustc_requirement {
+        let output = toolchain
+            .rustc()
+            .arg("--version")
+            .capture_stdout()
+            .context("Could not execute rustc")?;
+        check_rustc_version(&output, requirement)?;
+    }
+
+    if let Some(memo) = EnsureMemo::new(&toolchain, is64bit, rustc_requirement) {
+        memo.save();
+    }
+
+    Ok(toolchain)
+}
+
+/// Download or build the missing toolchain, as `WASIX_TOOLCHAIN_POLICY` says.
+fn acquire_toolchain(
+    is_offline: bool,
+    download_options: &DownloadOptions,
+) -> Result<RustupToolchain, anyhow::Error> {
+    let policy = download_options.policy;
+    let prebuilt_available = guess_host_target().is_some();
+    // Local archives need no network, and are installed unless only builds
+    // are allowed.
+    let local = download_options.archive_dir.is_some() && policy != ToolchainPolicy::BuildOnly;
+    let can_build = cfg!(any(target_os = "linux", target_os = "macos"));
+    if local || !policy.should_build(prebuilt_available, can_build) {
+        if is_offline && !local {
+            bail!(
+                r#"
+Could not detect wasix toolchain, and could not install because --offline or CARGO_WASIX_OFFLINE is set.
 Run `cargo wasix build-toolchain if you want to build locally.
 WARNING: building takes a long time!"#
+            );
+        }
+        return install_prebuilt_toolchain(download_options, &Config::toolchain_dir()?);
+    }
+
+    info!("No wasix toolchain installed, building one ({policy:?})...");
+    let mut opts = BuildToochainOptions::from_env()?;
+    if is_offline {
+        opts.set_offline_build()?;
+    }
+    let out = build_toolchain(opts)?
+        .context("WASIX_COMPONENTS=libc skips the Rust build, so there is no toolchain to use")?;
+    RustupToolchain::find_by_name(RUSTUP_TOOLCHAIN_NAME)?.with_context(|| {
+        format!(
+            "The toolchain built at {} was not linked into rustup",
+            out.toolchain_dir.display()
+        )
+    })
+}
+
+/// The newest installed toolchain for this host, downloading one if there is
+/// none and downloads are allowed. Used when rustup is not available.
+fn self_contained_toolchain(
+    is_offline: bool,
+    download_options: &DownloadOptions,
+) -> Result<RustupToolchain, anyhow::Error> {
+    let host = guess_host_target().context(
+        "rustup is not installed and there are no pre-built toolchains for this platform",
+    )?;
+    let root = Config::toolchain_dir()?;
+    let installed = match &download_options.version {
+        Some(tag) => installed_toolchains(&root)?
+            .into_iter()
+            .find(|t| t.target == host && &t.tag == tag && check_toolchain_layout(&t.path).is_ok())
+            .map(|t| t.path),
+        None => newest_installed(&root, host)?.map(|(_, dir)| dir),
+    };
+    let dir = match installed {
+        Some(dir) => dir,
+        None if download_options.policy == ToolchainPolicy::BuildOnly => bail!(
+            "WASIX_TOOLCHAIN_POLICY=build-only needs rustup to use a locally built toolchain"
+        ),
+        None if !is_offline || download_options.archive_dir.is_some() => {
+            obtain_toolchain(download_options, host, &root)?
+        }
+        None => bail!(
+            "No wasix toolchain for {host} installed in {}, and could not download one because --offline or CARGO_WASIX_OFFLINE is set",
+            root.display()
+        ),
+    };
+    Ok(RustupToolchain::self_contained(&dir.join("rust")))
+}
+
+/// Check the version reported by `rustc --version` against a semver
+/// requirement like `>=1.78`.
+///
+/// Pre-release suffixes like `-nightly` are ignored, so a `1.78.0-nightly`
+/// compiler satisfies `1.78`.
+fn check_rustc_version(rustc_version: &str, requirement: &str) -> Result<(), anyhow::Error> {
+    let req = semver::VersionReq::parse(requirement.trim()).with_context(|| {
+        format!("Invalid env var WASIX_RUSTC_VERSION with value '{requirement}'")
+    })?;
+    let version = parse_tool_version("rustc", rustc_version)?;
+
+    if !req.matches(&version) {
+        bail!(
+            "The wasix toolchain is based on Rust {version}, but WASIX_RUSTC_VERSION requires {requirement} (rustc reports: {})",
+            rustc_version.trim()
         );
+    }
+    Ok(())
+}
+
+/// Parse the output of `rustc --version` or `cargo --version`, ignoring
+/// pre-release suffixes.
+fn parse_tool_version(tool: &str, output: &str) -> Result<semver::Version, anyhow::Error> {
+    let raw = output
+        .split_whitespace()
+        .nth(1)
+        .with_context(|| format!("Could not parse {tool} version from '{output}'"))?;
+    let mut version = semver::Version::parse(raw)
+        .with_context(|| format!("Could not parse {tool} version from '{output}'"))?;
+    version.pre.clear();
+    version.build.clear();
+    Ok(version)
+}
+
+/// Describes why `cargo --version` output `cargo_version` may not work with
+/// the toolchain's `rustc --version` output `rustc_version`.
+///
+/// A cargo older than rustc can fail to understand the metadata of the
+/// standard library built with it.
+pub(crate) fn cargo_compat_problem(cargo_version: &str, rustc_version: &str) -> Option<String> {
+    let cargo = parse_tool_version("cargo", cargo_version).ok()?;
+    let rustc = parse_tool_version("rustc", rustc_version).ok()?;
+    ((cargo.major, cargo.minor) < (rustc.major, rustc.minor)).then(|| {
+        format!(
+            "cargo {cargo} is older than the wasix toolchain's rustc {rustc} and may fail to read the wasix std metadata; use cargo {}.{} or newer",
+            rustc.major, rustc.minor
+        )
+    })
+}
+
+/// The toolchain cargo-wasix would use, if one is installed.
+pub(crate) fn installed_toolchain() -> Result<Option<RustupToolchain>, anyhow::Error> {
+    if rustup_available() {
+        return RustupToolchain::find_by_name(RUSTUP_TOOLCHAIN_NAME);
+    }
+    let Some(host) = guess_host_target() else {
+        return Ok(None);
     };
+    Ok(newest_installed(&Config::toolchain_dir()?, host)?
+        .map(|(_, dir)| RustupToolchain::self_contained(&dir.join("rust"))))
+}
 
-    // Sanity check the toolchain.
-    let rust_sysroot = Command::new("rustc")
-        .arg(format!("+{}", toolchain.name))
-        .arg("--print")
-        .arg("sysroot")
-        .capture_stdout()
-        .map(|out| PathBuf::from(out.trim()))
-        .context("Could not execute rustc")?;
-    assert_eq!(toolchain.path, rust_sysroot);
-
-    let lib_name = if is64bit {
-        "lib/rustlib/wasm64-wasmer-wasi"
+/// Print the supported targets, the commands that build for them and whether
+/// the installed toolchain has them.
+pub fn print_targets(config: &Config) -> Result<(), anyhow::Error> {
+    let chain = installed_toolchain()?;
+    for (target, bits) in WASIX_TARGETS {
+        let command = if *bits == 64 {
+            "cargo wasix build64"
+        } else {
+            "cargo wasix build"
+        };
+        let status = match &chain {
+            Some(chain) if chain.path.join("lib/rustlib").join(target).is_dir() => "installed",
+            Some(_) => "missing from the toolchain",
+            None => "not installed",
+        };
+        println!("{target:<20} {bits}-bit  {command:<20}  {status}");
+    }
+    match chain {
+        Some(chain) => config.info(&format!("Toolchain at {}", chain.path.display())),
+        None => config.info("No wasix toolchain installed yet, it is installed on first use"),
+    }
+    Ok(())
+}
+
+/// Split a toolchain dir name like `{target}_{tag}` into target and tag.
+///
+/// Only the arch of a target triple may contain `_` (`x86_64`), tags may
+/// contain any.
+fn parse_toolchain_dir_name(name: &str) -> Option<(&str, &str)> {
+    if name.starts_with('.') {
+        return None;
+    }
+    let arch_end = name.find('-')?;
+    let split = arch_end + name[arch_end..].find('_')?;
+    let (target, tag) = (&name[..split], &name[split + 1..]);
+    (!tag.is_empty()).then_some((target, tag))
+}
+
+/// A toolchain in the toolchains dir.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct InstalledToolchain {
+    /// Host target the toolchain runs on.
+    pub target: String,
+    /// Release tag, or `local-<hash>` for local archives.
+    pub tag: String,
+    /// The toolchain directory, with the `rust` and `sysroot` subdirs.
+    pub path: PathBuf,
+}
+
+/// The toolchains downloaded to `root`, sorted by target and tag.
+pub(crate) fn installed_toolchains(root: &Path) -> Result<Vec<InstalledToolchain>, anyhow::Error> {
+    let mut out = Vec::new();
+    if !root.is_dir() {
+        return Ok(out);
+    }
+    for entry in std::fs::read_dir(root)
+        .with_context(|| format!("Could not read directory {}", root.display()))?
+    {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        let Some((target, tag)) = parse_toolchain_dir_name(&name) else {
+            continue;
+        };
+        if !path.is_dir() {
+            continue;
+        }
+        // Content addressed dirs are only named by their target.
+        let (target, tag) = match ToolchainMeta::load(&path) {
+            Ok(Some(meta)) => (meta.target, meta.tag),
+            _ => (target.to_string(), tag.to_string()),
+        };
+        out.push(InstalledToolchain { target, tag, path });
+    }
+    out.sort();
+    Ok(out)
+}
+
+/// Print the downloaded toolchains, marking the one linked into rustup.
+pub fn list_toolchains(config: &Config) -> Result<(), anyhow::Error> {
+    let root = Config::toolchain_dir()?;
+    let toolchains = installed_toolchains(&root)?;
+    if toolchains.is_empty() {
+        config.info(&format!("No toolchains installed in {}", root.display()));
+        return Ok(());
+    }
+    let linked = linked_toolchain_path();
+
+    println!("  {:<28} {:<24} {:>10}  PATH", "TARGET", "TAG", "SIZE");
+    for toolchain in toolchains {
+        let is_linked = linked
+            .as_ref()
+            .is_some_and(|l| l.starts_with(&toolchain.path));
+        println!(
+            "{} {:<28} {:<24} {:>10}  {}",
+            if is_linked { "*" } else { " " },
+            toolchain.target,
+            toolchain.tag,
+            format_bytes(dir_size(&toolchain.path)?),
+            toolchain.path.display()
+        );
+    }
+    config.info("* is linked as the `wasix` rustup toolchain");
+    Ok(())
+}
+
+/// `cargo wasix toolchain <COMMAND>`
+pub fn main(args: &[std::ffi::OsString], config: &Config) -> Result<(), anyhow::Error> {
+    let args = args
+        .iter()
+        .map(|a| {
+            a.to_str()
+                .with_context(|| format!("invalid `toolchain` argument: {a:?}"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut args = args.into_iter();
+
+    match args.next() {
+        Some("export-manifest") => manifest::export_main(&mut args, config),
+        Some("install") => manifest::install_main(&mut args, config),
+        Some("verify-manifest") => manifest::verify_main(&mut args, config),
+        Some("relink") => {
+            if let Some(arg) = args.next() {
+                bail!("unsupported `toolchain relink` argument: {arg}");
+            }
+            relink(config)
+        }
+        Some("info") => {
+            let tag = args.next();
+            if let Some(arg) = args.next() {
+                bail!("unsupported `toolchain info` argument: {arg}");
+            }
+            let options = DownloadOptions::from_env()?;
+            let client = http_client()?;
+            let release = match tag {
+                Some(tag) => fetch_release_by_tag(&client, &options, tag)?,
+                None => fetch_release(&client, &options)?,
+            };
+            print!("{}", release.describe());
+            Ok(())
+        }
+        Some("verify") => {
+            let mut online = false;
+            for arg in args {
+                match arg {
+                    "--online" => online = true,
+                    _ => bail!("unsupported `toolchain verify` argument: {arg}"),
+                }
+            }
+            verify(config, online)
+        }
+        Some(other) => bail!("unsupported `toolchain` command: {}", other),
+        None => bail!(
+            "`toolchain` command must be followed by `export-manifest`, `install`, `verify-manifest`, `verify`, `info` or `relink`"
+        ),
+    }
+}
+
+/// Re-link the most recently installed valid toolchain for this host,
+/// without downloading anything.
+fn relink(config: &Config) -> Result<(), anyhow::Error> {
+    let host = guess_host_target()
+        .context("There are no pre-built toolchains for this platform to relink")?;
+    let root = Config::toolchain_dir()?;
+    let _lock = Config::acquire_lock()?;
+
+    if !root.is_dir() {
+        bail!("No toolchains installed in {}", root.display());
+    }
+    let (meta, dir) = newest_installed(&root, host)?.with_context(|| {
+        format!(
+            "No valid toolchain for {host} found in {} - install one with `cargo wasix download-toolchain`",
+            root.display()
+        )
+    })?;
+    let chain = activate_toolchain(&dir.join("rust"))?;
+    if chain.self_contained {
+        config.info(&format!(
+            "rustup is not available, using release {} at {} self-contained",
+            meta.tag,
+            chain.path.display()
+        ));
     } else {
-        "lib/rustlib/wasm32-wasmer-wasi"
+        config.info(&format!(
+            "Relinked rustup toolchain {} to release {} at {}",
+            chain.name,
+            meta.tag,
+            chain.path.display()
+        ));
+    }
+    Ok(())
+}
+
+/// The most recently installed toolchain for `host` in `root` that has the
+/// expected layout and a rustc binary.
+pub(crate) fn newest_installed(
+    root: &Path,
+    host: &str,
+) -> Result<Option<(ToolchainMeta, PathBuf)>, anyhow::Error> {
+    let mut best: Option<(std::time::SystemTime, ToolchainMeta, PathBuf)> = None;
+    let entries = match std::fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).with_context(|| format!("Could not read {}", root.display())),
     };
-    let lib_dir = rust_sysroot.join(lib_name);
-    if !lib_dir.exists() {
-        bail!(
-            "Invalid wasix rustup toolchain {} at {}: {} does not exist",
-            toolchain.name,
-            toolchain.path.display(),
-            lib_dir.display()
-        );
+    for entry in entries {
+        let dir = entry?.path();
+        let meta = match ToolchainMeta::load(&dir) {
+            Ok(Some(meta)) => meta,
+            _ => continue,
+        };
+        if meta.target != host {
+            continue;
+        }
+        let Some(installed) = usable_since(&dir) else {
+            continue;
+        };
+        if best.as_ref().is_none_or(|(time, _, _)| installed > *time) {
+            best = Some((installed, meta, dir));
+        }
     }
-    Ok(toolchain)
+    Ok(best.map(|(_, meta, dir)| (meta, dir)))
+}
+
+/// When the toolchain in `dir` was installed, if it has the expected layout
+/// and a rustc binary.
+///
+/// Toolchains are ordered by this to find the newest one.
+pub(crate) fn usable_since(dir: &Path) -> Option<std::time::SystemTime> {
+    if check_toolchain_layout(dir).is_err() || !dir.join("rust").join("bin").join("rustc").is_file()
+    {
+        return None;
+    }
+    std::fs::metadata(dir.join(TOOLCHAIN_META_FILE))
+        .and_then(|meta| meta.modified())
+        .ok()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::release::testing::{http_response, serve};
+    use crate::release::GithubReleaseData;
+
+    #[test]
+    fn test_parse_libc_make_args() {
+        assert!(parse_libc_make_args("").unwrap().is_empty());
+        assert_eq!(
+            parse_libc_make_args(" headers  THREAD_MODEL=posix ").unwrap(),
+            vec!["headers", "THREAD_MODEL=posix"]
+        );
+        let err = parse_libc_make_args("CC=gcc").unwrap_err();
+        assert!(err.to_string().contains("CC"));
+    }
+
+    #[test]
+    fn test_parse_target_bits() {
+        assert_eq!(parse_target_bits("").unwrap(), vec![32, 64]);
+        assert_eq!(parse_target_bits("Both").unwrap(), vec![32, 64]);
+        assert_eq!(parse_target_bits("32").unwrap(), vec![32]);
+        assert_eq!(parse_target_bits(" 64 ").unwrap(), vec![64]);
+        assert!(parse_target_bits("128").is_err());
+    }
+
+    #[test]
+    fn test_parse_jobs() {
+        assert_eq!(parse_jobs("4").unwrap(), 4);
+        assert_eq!(parse_jobs(" 16 ").unwrap(), 16);
+        assert!(parse_jobs("0").is_err());
+        assert!(parse_jobs("all").is_err());
+    }
+
+    #[test]
+    fn test_parse_components() {
+        assert_eq!(parse_components("").unwrap(), (true, true));
+        assert_eq!(parse_components("All").unwrap(), (true, true));
+        assert_eq!(parse_components("none").unwrap(), (false, false));
+        assert_eq!(parse_components(" libc ").unwrap(), (true, false));
+        assert_eq!(parse_components("rust").unwrap(), (false, true));
+        assert_eq!(parse_components("libc,rust").unwrap(), (true, true));
+        assert_eq!(parse_components("RUST, libc").unwrap(), (true, true));
+
+        for raw in ["llvm", "libc,", "libc,none"] {
+            let err = parse_components(raw).unwrap_err().to_string();
+            assert!(err.contains(&format!("'{raw}'")), "{}", err);
+            for valid in ["'all'", "'none'", "'libc'", "'rust'"] {
+                assert!(err.contains(valid), "{}", err);
+            }
+        }
+    }
+
+    #[test]
+    fn test_std_rust_config() {
+        assert_eq!(parse_std_opt_level("3").unwrap(), "3");
+        assert_eq!(parse_std_opt_level("z").unwrap(), "\"z\"");
+        let err = parse_std_opt_level("fast").unwrap_err();
+        assert!(err.to_string().contains("WASIX_STD_OPT_LEVEL"));
+
+        assert_eq!(std_rust_config(false, None), "");
+        let config = std_rust_config(true, Some("\"s\""));
+        let parsed: toml::Value = toml::from_str(&config).unwrap();
+        assert_eq!(parsed["debug-assertions-std"].as_bool(), Some(true));
+        assert_eq!(parsed["overflow-checks-std"].as_bool(), Some(true));
+        assert_eq!(parsed["optimize"].as_str(), Some("s"));
+    }
+
+    #[test]
+    fn test_parse_timeout() {
+        let secs = std::time::Duration::from_secs;
+        assert_eq!(parse_timeout("5400").unwrap(), secs(5400));
+        assert_eq!(parse_timeout("30s").unwrap(), secs(30));
+        assert_eq!(parse_timeout("90m").unwrap(), secs(90 * 60));
+        assert_eq!(parse_timeout("2h").unwrap(), secs(2 * 60 * 60));
+        assert!(parse_timeout("0").is_err());
+        assert!(parse_timeout("soon").is_err());
+        assert!(parse_timeout("18446744073709551615h").is_err());
+    }
+
+    #[test]
+    fn test_parse_libc_cflags() {
+        assert!(parse_libc_cflags("", &[]).unwrap().is_empty());
+        assert_eq!(
+            parse_libc_cflags(" -fwasm-exceptions  -DFOO=1 ", &[]).unwrap(),
+            vec!["-fwasm-exceptions", "-DFOO=1"]
+        );
+        let err = parse_libc_cflags("--target=wasm32-wasi", &[]).unwrap_err();
+        assert!(err.to_string().contains("conflicts with the target"));
+        let make_args = vec!["EXTRA_CFLAGS=-O3".to_string()];
+        assert!(parse_libc_cflags("-DFOO", &make_args).is_err());
+        assert!(parse_libc_cflags("", &make_args).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_check_built_sysroot() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let sysroot = dir.path().join("sysroot64");
+        std::fs::create_dir_all(sysroot.join("lib/wasm64-wasi")).unwrap();
+        std::fs::create_dir_all(sysroot.join("include")).unwrap();
+        std::fs::write(sysroot.join("lib/wasm64-wasi/crt1.o"), "").unwrap();
+        std::fs::write(sysroot.join("include/stdlib.h"), "").unwrap();
+        let err = check_built_sysroot(&sysroot, 64).unwrap_err().to_string();
+        assert!(err.contains("missing lib/wasm64-wasi/libc.a)"), "{}", err);
+        assert!(err.contains("WASIX_COMPONENTS=libc"));
+
+        std::fs::write(sysroot.join("lib/wasm64-wasi/libc.a"), "").unwrap();
+        check_built_sysroot(&sysroot, 64).unwrap();
+        assert!(check_built_sysroot(&sysroot, 32).is_err());
+    }
+
+    #[test]
+    fn test_prepare_git_repo_shallow() {
+        if which::which("git").is_err() {
+            return;
+        }
+        let dir = tempfile::TempDir::new().unwrap();
+        let origin = dir.path().join("origin");
+        std::fs::create_dir_all(&origin).unwrap();
+        let git = |args: &[&str]| {
+            let status = Command::new("git")
+                .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+                .args(args)
+                .current_dir(&origin)
+                .output()
+                .unwrap()
+                .status;
+            assert!(status.success(), "git {:?} failed", args);
+        };
+        git(&["init", "-q"]);
+        for (i, version) in ["v1", "v2"].iter().enumerate() {
+            std::fs::write(origin.join("version"), version).unwrap();
+            git(&["add", "version"]);
+            git(&["commit", "-q", "-m", version]);
+            git(&["tag", version]);
+            if i == 0 {
+                git(&["branch", "old"]);
+            }
+        }
+        let source = format!("file://{}", origin.display());
+
+        let checkout = dir.path().join("checkout");
+        prepare_git_repo(&source, "v1", &checkout, false, true).unwrap();
+        assert!(checkout.join(".git/shallow").is_file());
+        assert_eq!(
+            std::fs::read_to_string(checkout.join("version")).unwrap(),
+            "v1"
+        );
+
+        prepare_git_repo(&source, "v2", &checkout, false, true).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(checkout.join("version")).unwrap(),
+            "v2"
+        );
+
+        let full = dir.path().join("full");
+        prepare_git_repo(&source, "old", &full, false, false).unwrap();
+        assert!(!full.join(".git/shallow").exists());
+        assert_eq!(std::fs::read_to_string(full.join("version")).unwrap(), "v1");
+    }
+
+    #[test]
+    fn test_find_compiler_cache() {
+        let both = |name: &str| Some(PathBuf::from(format!("/usr/bin/{name}")));
+        assert_eq!(
+            find_compiler_cache(false, both),
+            Some(PathBuf::from("/usr/bin/sccache"))
+        );
+        assert_eq!(find_compiler_cache(true, both), None);
+        let ccache = |name: &str| (name == "ccache").then(|| PathBuf::from("/usr/bin/ccache"));
+        assert_eq!(
+            find_compiler_cache(false, ccache),
+            Some(PathBuf::from("/usr/bin/ccache"))
+        );
+        assert_eq!(find_compiler_cache(false, |_| None), None);
+    }
+
+    #[test]
+    fn test_merge_rust_config() {
+        let config = r#"
+changelog-seen = 2
+
+[build]
+target = ["wasm32-wasmer-wasi"]
+extended = true
+
+[rust]
+lld = true
+
+[target.wasm32-wasmer-wasi]
+wasi-root = "/sysroot32"
+"#;
+        let overrides = r#"
+[build]
+target = ["x86_64-unknown-linux-gnu"]
+jobs = 4
+
+[rust]
+lld = false
+codegen-units = 1
+
+[llvm]
+ccache = true
+
+[target.wasm32-wasmer-wasi]
+wasi-root = "/elsewhere"
+linker = "rust-lld"
+"#;
+        let merged: toml::Value =
+            toml::from_str(&merge_rust_config(config, overrides).unwrap()).unwrap();
+        let build = &merged["build"];
+        assert_eq!(build["target"][0].as_str(), Some("wasm32-wasmer-wasi"));
+        assert_eq!(build["jobs"].as_integer(), Some(4));
+        assert_eq!(build["extended"].as_bool(), Some(true));
+        assert_eq!(merged["rust"]["lld"].as_bool(), Some(false));
+        assert_eq!(merged["rust"]["codegen-units"].as_integer(), Some(1));
+        assert_eq!(merged["llvm"]["ccache"].as_bool(), Some(true));
+        let target = &merged["target"]["wasm32-wasmer-wasi"];
+        assert_eq!(target["wasi-root"].as_str(), Some("/sysroot32"));
+        assert_eq!(target["linker"].as_str(), Some("rust-lld"));
+
+        assert!(merge_rust_config(config, "[build\n").is_err());
+    }
+
+    #[test]
+    fn test_structured_errors() {
+        let release: GithubReleaseData =
+            serde_json::from_str(r#"{"tag_name": "v1", "assets": []}"#).unwrap();
+        let err = release
+            .rust_asset("x86_64-unknown-linux-gnu")
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<ToolchainError>(),
+            Some(ToolchainError::NoPrebuiltToolchain { release, .. }) if release == "v1"
+        ));
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let err = check_built_sysroot(&dir.path().join("sysroot32"), 32).unwrap_err();
+        assert!(matches!(
+            err,
+            ToolchainError::SysrootMissing { bits: 32, ref missing, .. } if missing.is_empty()
+        ));
+        assert!(err.to_string().contains("was not found"));
+    }
+
+    #[test]
+    fn test_check_libc_sysroot() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let err = check_libc_sysroot("SYSROOT", Path::new("rel/path"), 32).unwrap_err();
+        assert!(err.to_string().contains("absolute path"));
+        let err = check_libc_sysroot("SYSROOT", dir.path(), 32).unwrap_err();
+        assert!(err.to_string().contains("not a wasm32 libc sysroot"));
+
+        let lib = dir.path().join("lib/wasm32-wasi");
+        std::fs::create_dir_all(&lib).unwrap();
+        std::fs::write(lib.join("libc.a"), "").unwrap();
+        check_libc_sysroot("SYSROOT", dir.path(), 32).unwrap();
+        assert!(check_libc_sysroot("SYSROOT", dir.path(), 64).is_err());
+    }
+
+    #[test]
+    fn test_newest_installed() {
+        let root = tempfile::TempDir::new().unwrap();
+        let host = "x86_64-unknown-linux-gnu";
+        assert!(newest_installed(&root.path().join("missing"), host)
+            .unwrap()
+            .is_none());
+
+        let install = |name: &str, target: &str| {
+            let dir = root.path().join(name);
+            std::fs::create_dir_all(dir.join("rust/bin")).unwrap();
+            std::fs::write(dir.join("rust/bin/rustc"), "").unwrap();
+            ToolchainMeta::new(name, target).save(&dir).unwrap();
+        };
+        install("v1", host);
+        install("v2", "aarch64-apple-darwin");
+        // Broken install without a compiler.
+        std::fs::create_dir_all(root.path().join("v3")).unwrap();
+        ToolchainMeta::new("v3", host)
+            .save(&root.path().join("v3"))
+            .unwrap();
+
+        let (meta, dir) = newest_installed(root.path(), host).unwrap().unwrap();
+        assert_eq!(meta.tag, "v1");
+        assert_eq!(dir, root.path().join("v1"));
+    }
+
+    #[test]
+    fn test_parse_toolchain_dir_name() {
+        assert_eq!(
+            parse_toolchain_dir_name("x86_64-unknown-linux-gnu_v2023-05-01"),
+            Some(("x86_64-unknown-linux-gnu", "v2023-05-01"))
+        );
+        assert_eq!(
+            parse_toolchain_dir_name("aarch64-apple-darwin_v2023-05-01"),
+            Some(("aarch64-apple-darwin", "v2023-05-01"))
+        );
+        assert_eq!(
+            parse_toolchain_dir_name("aarch64-apple-darwin_rc_1"),
+            Some(("aarch64-apple-darwin", "rc_1"))
+        );
+        assert_eq!(
+            parse_toolchain_dir_name(".aarch64-apple-darwin_v1.partial"),
+            None
+        );
+        assert_eq!(parse_toolchain_dir_name("stray"), None);
+    }
+
+    #[test]
+    fn test_install_local_archives() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let root = dir.path().join("toolchains");
+        let target = "x86_64-unknown-linux-gnu";
+        let install = || {
+            install_local_archives(&DownloadOptions::default(), target, dir.path(), &root)
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(install().contains("wasix-libc.tar.gz"));
+        std::fs::write(dir.path().join("wasix-libc.tar.gz"), b"libc").unwrap();
+        assert!(install().contains(&format!("rust-toolchain-{target}.tar.gz")));
+        assert!(!root.exists());
+
+        // A failed extraction leaves nothing behind.
+        std::fs::write(
+            dir.path().join(format!("rust-toolchain-{target}.tar.gz")),
+            b"rust",
+        )
+        .unwrap();
+        install();
+        let leftovers = std::fs::read_dir(&root)
+            .map(|entries| entries.count())
+            .unwrap_or_default();
+        assert_eq!(leftovers, 0);
+
+        let asset = |sha256: &str| AssetSource {
+            name: String::new(),
+            url: String::new(),
+            sha256: Some(sha256.to_string()),
+        };
+        assert_eq!(
+            local_archive_tag(&asset("0123456789abcdef"), &asset("fedcba9876543210")),
+            "local-01234567fedcba98"
+        );
+    }
+
+    #[test]
+    fn test_newer_release() {
+        let (base, server) = serve(3, |_, _| {
+            http_response("200 OK", "", br#"{"tag_name": "v2", "assets": []}"#)
+        });
+        let url = format!("{base}/release.json");
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let stamp = dir.path().join(AUTO_UPDATE_CHECK_FILE);
+        let mut options = DownloadOptions::default();
+        options.release_api = Some(url);
+        options.auto_update = Some(std::time::Duration::from_secs(3600));
+        assert_eq!(
+            newer_release("v1", false, &options, &stamp).as_deref(),
+            Some("v2")
+        );
+        // The next check is only due after the interval.
+        assert_eq!(newer_release("v1", false, &options, &stamp), None);
+        options.auto_update = Some(std::time::Duration::ZERO);
+        assert_eq!(newer_release("v1", true, &options, &stamp), None);
+        assert_eq!(newer_release("v2", false, &options, &stamp), None);
+        assert_eq!(
+            newer_release("v1", false, &options, &stamp).as_deref(),
+            Some("v2")
+        );
+        options.version = Some("v1".to_string());
+        assert_eq!(newer_release("v1", false, &options, &stamp), None);
+        options.version = None;
+        options.auto_update = None;
+        assert_eq!(newer_release("v1", false, &options, &stamp), None);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_replace_dir() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (from, to) = (dir.path().join(".new.tmp-1"), dir.path().join("toolchain"));
+        std::fs::create_dir_all(&from).unwrap();
+        std::fs::write(from.join("version"), "new").unwrap();
+        replace_dir(&from, &to).unwrap();
+        assert_eq!(std::fs::read_to_string(to.join("version")).unwrap(), "new");
+
+        std::fs::create_dir_all(&from).unwrap();
+        std::fs::write(from.join("version"), "newer").unwrap();
+        replace_dir(&from, &to).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(to.join("version")).unwrap(),
+            "newer"
+        );
+        let names = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["toolchain"]);
+
+        // The existing dir stays if the move fails.
+        assert!(replace_dir(&from, &to).is_err());
+        assert_eq!(
+            std::fs::read_to_string(to.join("version")).unwrap(),
+            "newer"
+        );
+    }
+
+    #[test]
+    fn test_is_pinned_release() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let toolchain = RustupToolchain::self_contained(&dir.path().join("rust"));
+        let mut options = DownloadOptions::default();
+        assert!(is_pinned_release(&toolchain, &options));
+
+        options.version = Some("v2023-05-01".to_string());
+        assert!(!is_pinned_release(&toolchain, &options));
+        ToolchainMeta::new("v2023-05-01", "x86_64-unknown-linux-gnu")
+            .save(dir.path())
+            .unwrap();
+        assert!(is_pinned_release(&toolchain, &options));
+        options.version = Some("v2023-01-01".to_string());
+        assert!(!is_pinned_release(&toolchain, &options));
+    }
+
+    #[test]
+    fn test_check_same_build() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let rust = dir.path().join("rust");
+        let sysroot = dir.path().join("sysroot");
+        for path in [
+            &rust,
+            &sysroot.join("sysroot32"),
+            &sysroot.join("sysroot64"),
+        ] {
+            std::fs::create_dir_all(path).unwrap();
+        }
+        // Artifacts from before build ids are accepted.
+        check_same_build(&rust, &sysroot).unwrap();
+
+        std::fs::write(rust.join(BUILD_ID_FILE), "v2023-05-01\n").unwrap();
+        std::fs::write(sysroot.join("sysroot32").join(BUILD_ID_FILE), "v2023-05-01").unwrap();
+        std::fs::write(sysroot.join("sysroot64").join(BUILD_ID_FILE), "v2023-05-01").unwrap();
+        check_same_build(&rust, &sysroot).unwrap();
+
+        std::fs::write(sysroot.join("sysroot64").join(BUILD_ID_FILE), "v2023-01-01").unwrap();
+        let err = check_same_build(&rust, &sysroot).unwrap_err().to_string();
+        assert!(err.contains("v2023-05-01"));
+        assert!(err.contains("wasm64 libc sysroot (build v2023-01-01)"));
+    }
+
+    #[test]
+    fn test_content_dir_name() {
+        let target = "x86_64-unknown-linux-gnu";
+        let name = content_dir_name(target, &"ab".repeat(32), &"cd".repeat(32));
+        assert!(name.starts_with("x86_64-unknown-linux-gnu_sha256-"));
+        assert_eq!(name.len(), target.len() + "_sha256-".len() + 16);
+        // Checksums are compared case insensitively elsewhere.
+        assert_eq!(
+            name,
+            content_dir_name(target, &"AB".repeat(32), &"CD".repeat(32))
+        );
+        assert_ne!(
+            name,
+            content_dir_name(target, &"cd".repeat(32), &"ab".repeat(32))
+        );
+    }
+
+    #[test]
+    fn test_guess_host_target() {
+        use std::env::consts::{ARCH, OS};
+        let expected = match (ARCH, OS) {
+            ("x86_64", "linux") => Some("x86_64-unknown-linux-gnu"),
+            ("aarch64", "linux") => Some("aarch64-unknown-linux-gnu"),
+            ("x86_64", "macos") => Some("x86_64-apple-darwin"),
+            ("aarch64", "macos") => Some("aarch64-apple-darwin"),
+            ("x86_64", "windows") => Some("x86_64-pc-windows-msvc"),
+            _ => None,
+        };
+        assert_eq!(guess_host_target(), expected);
+        if let Some(target) = guess_host_target() {
+            assert!(target.starts_with(ARCH));
+        }
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_apply_install_umask() {
+        use std::os::unix::fs::PermissionsExt;
+
+        assert_eq!(install_mode(0o755, false, 0o027), 0o750);
+        assert_eq!(install_mode(0o644, false, 0o027), 0o640);
+        assert_eq!(install_mode(0o700, true, 0o002), 0o775);
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let bin = dir.path().join("rust/bin");
+        std::fs::create_dir_all(&bin).unwrap();
+        std::fs::write(bin.join("rustc"), "").unwrap();
+        std::fs::set_permissions(bin.join("rustc"), std::fs::Permissions::from_mode(0o755))
+            .unwrap();
+        std::fs::write(dir.path().join("rust/README"), "").unwrap();
+        std::fs::set_permissions(
+            dir.path().join("rust/README"),
+            std::fs::Permissions::from_mode(0o666),
+        )
+        .unwrap();
+        apply_install_umask(dir.path(), 0o027).unwrap();
+
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&bin), 0o750);
+        assert_eq!(mode(&bin.join("rustc")), 0o750);
+        assert_eq!(mode(&dir.path().join("rust/README")), 0o640);
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_check_rustc() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let bin = dir.path().join("bin");
+        std::fs::create_dir_all(&bin).unwrap();
+        let write_rustc = |script: &str| {
+            std::fs::write(bin.join("rustc"), script).unwrap();
+            std::fs::set_permissions(bin.join("rustc"), std::fs::Permissions::from_mode(0o755))
+                .unwrap();
+        };
+
+        write_rustc("#!/bin/sh\necho rustc 1.70.0-nightly\n");
+        check_rustc(dir.path()).unwrap();
+
+        write_rustc("#!/bin/sh\necho \"rustc: version \\`GLIBC_2.34' not found\" >&2\nexit 1\n");
+        let err = format!("{:#}", check_rustc(dir.path()).unwrap_err());
+        assert!(err.contains("glibc/host mismatch"), "{}", err);
+        assert!(err.contains("GLIBC_2.34"), "{}", err);
+        assert!(err.contains("cargo wasix build-toolchain"), "{}", err);
+
+        std::fs::remove_file(bin.join("rustc")).unwrap();
+        assert!(check_rustc(dir.path()).is_err());
+    }
+
+    #[test]
+    fn test_missing_library_hint() {
+        let stderr = "clang: error while loading shared libraries: libtinfo.so.5: cannot open shared object file: No such file or directory\n";
+        let hint = missing_library_hint(stderr).unwrap();
+        assert!(hint.contains("libtinfo.so.5 is missing"));
+        assert!(hint.contains("libtinfo5"));
+
+        let stderr = "clang: error while loading shared libraries: libfoo.so.1: cannot open shared object file";
+        assert_eq!(
+            missing_library_hint(stderr).unwrap(),
+            "the shared library libfoo.so.1 is missing"
+        );
+        assert!(missing_library_hint("segmentation fault").is_none());
+    }
+
+    #[test]
+    fn test_find_stage2() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let err = find_stage2(dir.path(), None).unwrap_err();
+        assert!(err.to_string().contains("Could not find a stage2 rustc"));
+
+        std::fs::write(
+            dir.path().join("config.toml"),
+            "[build]\nbuild-dir = \"out\"\n",
+        )
+        .unwrap();
+        std::fs::create_dir_all(dir.path().join("out/host-a/stage1")).unwrap();
+        let bin = dir.path().join("out/host-b/stage2/bin");
+        std::fs::create_dir_all(&bin).unwrap();
+        std::fs::write(bin.join("rustc"), "").unwrap();
+
+        let out = find_stage2(dir.path(), None).unwrap();
+        assert_eq!(out.target, "host-b");
+        assert_eq!(out.toolchain_dir, dir.path().join("out/host-b/stage2"));
+
+        let err = find_stage2(dir.path(), Some("host-a")).unwrap_err();
+        assert!(err.to_string().contains("[host-a, host-b]"));
+    }
+
+    #[test]
+    fn test_check_sysroot_arch() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let sysroot32 = dir.path().join("sysroot32");
+        let sysroot64 = dir.path().join("sysroot64");
+        // Swapped contents.
+        std::fs::create_dir_all(sysroot32.join("lib/wasm64-wasi")).unwrap();
+        std::fs::create_dir_all(sysroot64.join("lib/wasm32-wasi")).unwrap();
+
+        let err = check_sysroot_arch(&sysroot32, 32).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("sysroot contents don't match their directory names"));
+        assert!(err.to_string().contains("[wasm64]"));
+        assert!(check_sysroot_arch(&sysroot64, 64).is_err());
+        check_sysroot_arch(&sysroot32, 64).unwrap();
+        check_sysroot_arch(&sysroot64, 32).unwrap();
+    }
+
+    #[test]
+    fn test_cargo_compat_problem() {
+        let rustc = "rustc 1.78.0-nightly (a2b4c6d8e 2024-03-21)\n";
+        assert_eq!(
+            cargo_compat_problem("cargo 1.78.0 (54d8815d0 2024-03-26)", rustc),
+            None
+        );
+        assert_eq!(
+            cargo_compat_problem("cargo 1.80.1 (376290515 2024-07-16)", rustc),
+            None
+        );
+        let problem = cargo_compat_problem("cargo 1.74.0 (ecb9851af 2023-10-18)", rustc).unwrap();
+        assert!(problem.contains("cargo 1.74.0 is older than the wasix toolchain's rustc 1.78.0"));
+        assert_eq!(cargo_compat_problem("garbage", rustc), None);
+    }
+
+    #[test]
+    fn test_check_rustc_version() {
+        let out = "rustc 1.78.0-nightly (a2b4c6d8e 2024-03-21)\n";
+        check_rustc_version(out, "1.78").unwrap();
+        check_rustc_version(out, ">=1.70, <2").unwrap();
+        let err = check_rustc_version(out, ">=1.80").unwrap_err();
+        assert!(err.to_string().contains("based on Rust 1.78.0"));
+        assert!(check_rustc_version(out, "not a version").is_err());
+    }
 
     #[test]
     fn test_download_toolchain() {
@@ -748,7 +3318,12 @@ mod tests {
         if tmp_dir.is_dir() {
             std::fs::remove_dir_all(&tmp_dir).unwrap_or_default();
         }
-        let root = download_toolchain("x86_64-unknown-linux-gnu", &tmp_dir).unwrap();
+        let root = download_toolchain(
+            &DownloadOptions::default(),
+            "x86_64-unknown-linux-gnu",
+            &tmp_dir,
+        )
+        .unwrap();
         let dir = root.join("rust");
         assert!(dir.join("bin").join("rustc").is_file());
         std::fs::remove_dir_all(&tmp_dir).ok();
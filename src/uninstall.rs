@@ -0,0 +1,245 @@
+//! `cargo wasix uninstall-toolchain` and `cargo wasix uninstall`.
+
+use std::path::PathBuf;
+
+use anyhow::{bail, Context};
+
+use crate::{
+    config::Config,
+    toolchain::{
+        installed_toolchains, usable_since, InstalledToolchain, RustupToolchain,
+        RUSTUP_TOOLCHAIN_NAME,
+    },
+    utils::{dir_size, format_bytes},
+};
+
+/// Which toolchains `uninstall-toolchain` removes.
+#[derive(Debug)]
+enum ToolchainSelection {
+    Tag(String),
+    All,
+    /// Everything but the most recently installed toolchain of each target.
+    KeepLatest,
+}
+
+impl ToolchainSelection {
+    /// The toolchains to remove out of `installed`, which is sorted.
+    fn select(&self, installed: Vec<InstalledToolchain>) -> Vec<InstalledToolchain> {
+        match self {
+            Self::Tag(tag) => installed.into_iter().filter(|t| &t.tag == tag).collect(),
+            Self::All => installed,
+            Self::KeepLatest => {
+                // Keep the toolchain `newest_installed` picks, so relinking
+                // and self-contained mode still find the one in use.
+                let mut keep = std::collections::BTreeMap::new();
+                for toolchain in &installed {
+                    let since = usable_since(&toolchain.path);
+                    match keep.get(&toolchain.target) {
+                        Some((best, _)) if since < *best => {}
+                        _ => {
+                            keep.insert(toolchain.target.clone(), (since, toolchain.path.clone()));
+                        }
+                    }
+                }
+                installed
+                    .into_iter()
+                    .filter(|t| keep[&t.target].1 != t.path)
+                    .collect()
+            }
+        }
+    }
+}
+
+/// The path of the toolchain linked into rustup, if any.
+pub(crate) fn linked_toolchain_path() -> Option<PathBuf> {
+    RustupToolchain::find_by_name(RUSTUP_TOOLCHAIN_NAME)
+        .ok()
+        .flatten()
+        .map(|chain| chain.path)
+}
+
+/// `cargo wasix uninstall-toolchain (<TAG> | --all | --keep-latest)`
+pub fn uninstall_toolchain(
+    config: &Config,
+    args: &[std::ffi::OsString],
+) -> Result<(), anyhow::Error> {
+    let mut selection = None;
+    for arg in args {
+        let next = match arg.to_str() {
+            Some("--all") => ToolchainSelection::All,
+            Some("--keep-latest") => ToolchainSelection::KeepLatest,
+            Some(tag) if !tag.starts_with('-') => ToolchainSelection::Tag(tag.to_string()),
+            _ => bail!("unsupported `uninstall-toolchain` argument: {:?}", arg),
+        };
+        if selection.is_some() {
+            bail!("`uninstall-toolchain` takes exactly one of <TAG>, `--all` or `--keep-latest`");
+        }
+        selection = Some(next);
+    }
+    let selection = selection.context(
+        "`uninstall-toolchain` must be followed by a release tag, `--all` or `--keep-latest`",
+    )?;
+
+    let _lock = Config::acquire_lock()?;
+    let root = Config::toolchain_dir()?;
+    let remove = selection.select(installed_toolchains(&root)?);
+    if remove.is_empty() {
+        if let ToolchainSelection::Tag(tag) = &selection {
+            bail!(
+                "No toolchain with tag {tag} installed in {}",
+                root.display()
+            );
+        }
+        config.info("No toolchains to remove");
+        return Ok(());
+    }
+
+    // Don't leave rustup with a link to a removed directory.
+    let linked = linked_toolchain_path();
+    if remove
+        .iter()
+        .any(|t| linked.as_ref().is_some_and(|l| l.starts_with(&t.path)))
+    {
+        config.status(
+            "Unlinking",
+            &format!("rustup toolchain {RUSTUP_TOOLCHAIN_NAME}"),
+        );
+        RustupToolchain::unlink(RUSTUP_TOOLCHAIN_NAME)?;
+    }
+
+    let mut freed = 0;
+    for toolchain in remove {
+        let size = dir_size(&toolchain.path)?;
+        config.status(
+            "Removing",
+            &format!(
+                "{} {} ({})",
+                toolchain.target,
+                toolchain.tag,
+                format_bytes(size)
+            ),
+        );
+        std::fs::remove_dir_all(&toolchain.path)
+            .with_context(|| format!("Could not remove {}", toolchain.path.display()))?;
+        freed += size;
+    }
+    config.info(&format!("Freed {}", format_bytes(freed)));
+    Ok(())
+}
+
+/// Remove everything cargo-wasix installed.
+///
+/// Unlinks the rustup toolchain and deletes the downloaded toolchains. If
+/// `all` is set, the local build directory and the cache are removed too.
+///
+/// Safe to run when nothing is installed.
+pub fn uninstall(config: &Config, all: bool) -> Result<(), anyhow::Error> {
+    let _lock = Config::acquire_lock()?;
+
+    // A missing rustup means there is nothing to unlink.
+    if let Ok(Some(chain)) = RustupToolchain::find_by_name(RUSTUP_TOOLCHAIN_NAME) {
+        config.status(
+            "Unlinking",
+            &format!("rustup toolchain {} ({})", chain.name, chain.path.display()),
+        );
+        RustupToolchain::unlink(&chain.name)?;
+    }
+
+    let mut dirs = vec![Config::toolchain_dir()?];
+    if all {
+        dirs.push(Config::build_dir()?);
+        dirs.push(Config::llvm_cache_dir()?);
+        dirs.push(config.cache().all_versions_root().to_path_buf());
+    }
+
+    let mut freed = 0;
+    for dir in dirs {
+        if !dir.exists() {
+            continue;
+        }
+        let size = dir_size(&dir)?;
+        config.status(
+            "Removing",
+            &format!("{} ({})", dir.display(), format_bytes(size)),
+        );
+        std::fs::remove_dir_all(&dir)
+            .with_context(|| format!("Could not remove {}", dir.display()))?;
+        freed += size;
+    }
+
+    config.info(&format!("Freed {}", format_bytes(freed)));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::{ToolchainMeta, TOOLCHAIN_META_FILE};
+    use crate::toolchain::newest_installed;
+
+    #[test]
+    fn test_toolchain_selection() {
+        let root = tempfile::TempDir::new().unwrap();
+        for name in [
+            "x86_64-unknown-linux-gnu_v2023-05-01",
+            "x86_64-unknown-linux-gnu_v2023-06-01",
+            "aarch64-apple-darwin_v2023-05-01",
+            ".x86_64-unknown-linux-gnu_v2023-07-01.partial",
+        ] {
+            std::fs::create_dir(root.path().join(name)).unwrap();
+        }
+        let tags = |selection: ToolchainSelection| {
+            selection
+                .select(installed_toolchains(root.path()).unwrap())
+                .into_iter()
+                .map(|t| format!("{} {}", t.target, t.tag))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            tags(ToolchainSelection::Tag("v2023-05-01".to_string())),
+            [
+                "aarch64-apple-darwin v2023-05-01",
+                "x86_64-unknown-linux-gnu v2023-05-01"
+            ]
+        );
+        assert_eq!(tags(ToolchainSelection::All).len(), 3);
+        assert_eq!(
+            tags(ToolchainSelection::KeepLatest),
+            ["x86_64-unknown-linux-gnu v2023-05-01"]
+        );
+
+        // The most recently installed usable toolchain is kept, whatever its
+        // tag.
+        let root = tempfile::TempDir::new().unwrap();
+        let target = "x86_64-unknown-linux-gnu";
+        let now = std::time::SystemTime::now();
+        for (tag, age) in [("v2023-05-01.9", 60), ("v2023-05-01.10", 0)] {
+            let path = root.path().join(format!("{target}_{tag}"));
+            std::fs::create_dir_all(path.join("rust/bin")).unwrap();
+            std::fs::create_dir_all(path.join("rust/lib/rustlib/wasm32-wasmer-wasi")).unwrap();
+            std::fs::write(path.join("rust/bin/rustc"), b"").unwrap();
+            ToolchainMeta::new(tag, target).save(&path).unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(path.join(TOOLCHAIN_META_FILE))
+                .unwrap()
+                .set_modified(now - std::time::Duration::from_secs(age))
+                .unwrap();
+        }
+        std::fs::create_dir(root.path().join(format!("{target}_v2024-01-01"))).unwrap();
+        let removed = ToolchainSelection::KeepLatest
+            .select(installed_toolchains(root.path()).unwrap())
+            .into_iter()
+            .map(|t| format!("{} {}", t.target, t.tag))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            removed,
+            [
+                "x86_64-unknown-linux-gnu v2023-05-01.9",
+                "x86_64-unknown-linux-gnu v2024-01-01"
+            ]
+        );
+        let (newest, _) = newest_installed(root.path(), target).unwrap().unwrap();
+        assert_eq!(newest.tag, "v2023-05-01.10");
+    }
+}
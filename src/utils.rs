@@ -1,31 +1,625 @@
 use crate::config::Config;
 use anyhow::{anyhow, bail, Context, Error, Result};
 use fs2::FileExt;
-use reqwest::blocking::{Client, Response};
+use reqwest::blocking::Response;
 use reqwest::header::USER_AGENT;
-use reqwest::Proxy;
 use std::fs;
 use std::fs::{File, OpenOptions};
 use std::path::Path;
-use std::process::{Command, ExitStatus, Output, Stdio};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{env, fmt};
 
+/// File that all console output is additionally written to, see `--log-file`.
+static LOG_FILE: Mutex<Option<File>> = Mutex::new(None);
+
+/// Start appending all console output to the file at `path`.
+pub fn set_log_file(path: &Path) -> Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open log file `{}`", path.display()))?;
+    *LOG_FILE.lock().unwrap() = Some(file);
+    Ok(())
+}
+
+fn log_file_active() -> bool {
+    LOG_FILE.lock().unwrap().is_some()
+}
+
+/// Append `text` to the log file, if any, prefixing each line with a UTC
+/// timestamp.
+pub fn log_to_file(text: &str) {
+    use std::io::Write;
+
+    if let Some(file) = LOG_FILE.lock().unwrap().as_mut() {
+        let timestamp = format_utc(std::time::SystemTime::now());
+        for line in text.lines() {
+            drop(writeln!(file, "[{timestamp}] {line}"));
+        }
+    }
+}
+
+/// Receives the console output instead of stderr, see [`set_log_hook`].
+pub type LogHook = Arc<dyn Fn(&str) + Send + Sync>;
+
+static LOG_HOOK: Mutex<Option<LogHook>> = Mutex::new(None);
+
+/// Send all log lines to `hook` instead of stderr, returning the previous
+/// hook. Download progress is not drawn while a hook is set.
+pub fn set_log_hook(hook: Option<LogHook>) -> Option<LogHook> {
+    std::mem::replace(&mut *LOG_HOOK.lock().unwrap(), hook)
+}
+
+fn log_hook() -> Option<LogHook> {
+    LOG_HOOK.lock().unwrap().clone()
+}
+
+/// Print a line to stderr, or the log hook, and the log file.
+pub fn log_line(msg: &str) {
+    match log_hook() {
+        Some(hook) => hook(msg),
+        None => eprintln!("{msg}"),
+    }
+    log_to_file(msg);
+}
+
+/// Writes the records of the `log` macros with [`log_line`].
+///
+/// Other crates only get through at warning level, so `-vv` does not drown
+/// the output in http client internals.
+struct Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Warn
+            || metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match record.level() {
+            log::Level::Info => log_line(&record.args().to_string()),
+            level => log_line(&format!(
+                "{}: {}",
+                level.as_str().to_lowercase(),
+                record.args()
+            )),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Install the logger for the `log` macros, unless another logger is, and
+/// set the level from `--quiet` and the number of `-v` flags.
+///
+/// Info is the default, `--quiet` leaves warnings only, `-v` adds debug and
+/// `-vv` trace messages.
+pub fn init_logger() {
+    drop(log::set_logger(&Logger));
+    log::set_max_level(match (QUIET.load(Ordering::Relaxed), verbosity()) {
+        (true, _) => log::LevelFilter::Warn,
+        (false, 0) => log::LevelFilter::Info,
+        (false, 1) => log::LevelFilter::Debug,
+        (false, _) => log::LevelFilter::Trace,
+    });
+}
+
+/// Format a time as `YYYY-MM-DD HH:MM:SS` in UTC.
+pub(crate) fn format_utc(time: std::time::SystemTime) -> String {
+    let secs = time
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let (days, rem) = (secs / 86400, secs % 86400);
+
+    // Civil date from days since the epoch, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
+
+/// Non-fatal warnings emitted during this invocation.
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Print a non-fatal warning and remember it for `--deny-warnings`.
+pub fn warn(msg: impl Into<String>) {
+    let msg = msg.into();
+    log_line(&format!("warning: {}", msg));
+    annotate("warning", &msg);
+    WARNINGS.lock().unwrap().push(msg);
+}
+
+/// All warnings emitted so far.
+pub fn warnings() -> Vec<String> {
+    WARNINGS.lock().unwrap().clone()
+}
+
+/// Whether we run under GitHub Actions and should emit workflow commands.
+fn github_actions() -> bool {
+    env::var("GITHUB_ACTIONS").is_ok_and(|v| v == "true")
+}
+
+/// Escape a message for a GitHub Actions workflow command.
+fn escape_workflow_data(msg: &str) -> String {
+    msg.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+/// Emit an `::error::` or `::warning::` annotation under GitHub Actions.
+pub fn annotate(level: &str, msg: &str) {
+    if github_actions() {
+        println!("::{level}::{}", escape_workflow_data(msg));
+    }
+}
+
+/// Whether a log group is open; GitHub Actions groups can't be nested.
+static GROUP_OPEN: AtomicBool = AtomicBool::new(false);
+
+/// Fold the output until the returned guard is dropped into a collapsible
+/// group under GitHub Actions. Does nothing elsewhere, or inside another
+/// group.
+pub fn group(title: &str) -> Group {
+    let open = github_actions() && !GROUP_OPEN.swap(true, Ordering::SeqCst);
+    if open {
+        println!("::group::{}", escape_workflow_data(title));
+    }
+    Group { open }
+}
+
+pub struct Group {
+    open: bool,
+}
+
+impl Drop for Group {
+    fn drop(&mut self) {
+        if self.open {
+            println!("::endgroup::");
+            GROUP_OPEN.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Flag to abort downloads and extraction from elsewhere, like a signal
+/// handler or the UI thread of an application embedding the installer.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    flag: Arc<AtomicBool>,
+    parent: Option<Box<CancelToken>>,
+}
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::SeqCst) || self.parent.as_ref().is_some_and(|p| p.is_cancelled())
+    }
+
+    /// A token that is cancelled along with this one, but can also be
+    /// cancelled on its own without cancelling this one.
+    pub(crate) fn child(&self) -> CancelToken {
+        CancelToken {
+            flag: Default::default(),
+            parent: Some(Box::new(self.clone())),
+        }
+    }
+
+    /// Wrap `inner` so reads fail once the token is cancelled.
+    pub fn reader<R: std::io::Read>(&self, inner: R) -> CancellableReader<R> {
+        CancellableReader {
+            inner,
+            token: self.clone(),
+        }
+    }
+}
+
+/// Reader that fails with a "cancelled" error once its token is cancelled.
+pub struct CancellableReader<R> {
+    inner: R,
+    token: CancelToken,
+}
+
+impl<R: std::io::Read> std::io::Read for CancellableReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.token.is_cancelled() {
+            return Err(std::io::Error::other("cancelled"));
+        }
+        self.inner.read(buf)
+    }
+}
+
+/// Set by `--quiet`, hides download progress.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Wrap download `inner` to draw its progress on stderr, if that is a
+/// terminal and `--quiet` was not passed.
+///
+/// `done` bytes were downloaded earlier, `total` is the full size if known.
+pub fn progress<R: std::io::Read>(
+    inner: R,
+    label: &str,
+    done: u64,
+    total: Option<u64>,
+) -> ProgressReader<R> {
+    let enabled =
+        !QUIET.load(Ordering::Relaxed) && log_hook().is_none() && atty::is(atty::Stream::Stderr);
+    ProgressReader {
+        inner,
+        id: NEXT_PROGRESS_ID.fetch_add(1, Ordering::Relaxed),
+        label: label.to_string(),
+        enabled,
+        done,
+        start: done,
+        total,
+        started: Instant::now(),
+        drawn: None,
+    }
+}
+
+/// Progress of the downloads running at the same time, by reader id.
+static PROGRESS_LINES: Mutex<Vec<(usize, String)>> = Mutex::new(Vec::new());
+static NEXT_PROGRESS_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Set or, with `None`, remove the progress `line` of reader `id`, and return
+/// the progress of all running downloads as one line.
+fn progress_lines(id: usize, line: Option<String>) -> String {
+    let mut lines = PROGRESS_LINES.lock().unwrap_or_else(|err| err.into_inner());
+    let pos = lines.iter().position(|(other, _)| *other == id);
+    match (pos, line) {
+        (Some(pos), Some(line)) => lines[pos].1 = line,
+        (None, Some(line)) => lines.push((id, line)),
+        (Some(pos), None) => {
+            lines.remove(pos);
+        }
+        (None, None) => {}
+    }
+    lines
+        .iter()
+        .map(|(_, line)| line.trim_start())
+        .collect::<Vec<_>>()
+        .join(" | ")
+}
+
+/// Reader that draws a progress line, see [`progress`].
+///
+/// Readers used at the same time share the line.
+pub struct ProgressReader<R> {
+    inner: R,
+    id: usize,
+    label: String,
+    enabled: bool,
+    done: u64,
+    /// Bytes done before this reader started, excluded from the throughput.
+    start: u64,
+    total: Option<u64>,
+    started: Instant,
+    drawn: Option<Instant>,
+}
+
+impl<R> ProgressReader<R> {
+    fn line(&self) -> String {
+        let elapsed = self.started.elapsed().as_secs_f64().max(0.001);
+        let rate = ((self.done - self.start) as f64 / elapsed) as u64;
+        match self.total {
+            Some(total) if total > 0 => format!(
+                "{:>12} {} {:>3}% {} / {} ({}/s)",
+                "Downloading",
+                self.label,
+                self.done.min(total) * 100 / total,
+                format_bytes(self.done),
+                format_bytes(total),
+                format_bytes(rate)
+            ),
+            _ => {
+                let spinner = ['|', '/', '-', '\\'];
+                let frame = (self.started.elapsed().as_millis() / 100) as usize % spinner.len();
+                format!(
+                    "{:>12} {} {} {} ({}/s)",
+                    "Downloading",
+                    self.label,
+                    spinner[frame],
+                    format_bytes(self.done),
+                    format_bytes(rate)
+                )
+            }
+        }
+    }
+
+    fn draw(&mut self) {
+        if !self.enabled
+            || self
+                .drawn
+                .is_some_and(|at| at.elapsed() < Duration::from_millis(100))
+        {
+            return;
+        }
+        self.drawn = Some(Instant::now());
+        eprint!("\r{}\x1b[K", progress_lines(self.id, Some(self.line())));
+    }
+}
+
+impl<R: std::io::Read> std::io::Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.done += n as u64;
+        self.draw();
+        Ok(n)
+    }
+}
+
+impl<R> Drop for ProgressReader<R> {
+    fn drop(&mut self) {
+        if self.enabled && self.drawn.is_some() {
+            progress_lines(self.id, None);
+            eprintln!("\r{}\x1b[K", self.line());
+        }
+    }
+}
+
+/// The flag of the token cancelled by SIGINT, see [`cancel_on_sigint`].
+#[cfg(unix)]
+static SIGINT_FLAG: std::sync::atomic::AtomicPtr<AtomicBool> =
+    std::sync::atomic::AtomicPtr::new(std::ptr::null_mut());
+
+#[cfg(unix)]
+extern "C" fn on_sigint(_: libc::c_int) {
+    let flag = SIGINT_FLAG.load(Ordering::SeqCst);
+    if !flag.is_null() {
+        // SAFETY: the guard keeps the flag alive while the handler is set.
+        unsafe { (*flag).store(true, Ordering::SeqCst) };
+    }
+    // A second Ctrl-C terminates right away.
+    // SAFETY: signal is async-signal-safe.
+    unsafe { libc::signal(libc::SIGINT, libc::SIG_DFL) };
+}
+
+/// The token of the active [`cancel_on_sigint`] guard, whether it is
+/// cancelled, or `None` without a guard.
+fn sigint_cancelled() -> Option<bool> {
+    #[cfg(unix)]
+    {
+        let flag = SIGINT_FLAG.load(Ordering::SeqCst);
+        if !flag.is_null() {
+            // SAFETY: the guard keeps the flag alive while it is set.
+            return Some(unsafe { (*flag).load(Ordering::SeqCst) });
+        }
+    }
+    None
+}
+
+/// Cancel `token` on Ctrl-C until the returned guard is dropped, instead of
+/// terminating the process.
+///
+/// Only supported on unix; elsewhere Ctrl-C still terminates.
+pub fn cancel_on_sigint(token: &CancelToken) -> SigintGuard {
+    #[cfg(unix)]
+    {
+        SIGINT_FLAG.store(
+            Arc::as_ptr(&token.flag) as *mut AtomicBool,
+            Ordering::SeqCst,
+        );
+        // SAFETY: the handler only touches atomics.
+        let handler: extern "C" fn(libc::c_int) = on_sigint;
+        unsafe { libc::signal(libc::SIGINT, handler as libc::sighandler_t) };
+    }
+    SigintGuard {
+        _token: token.clone(),
+    }
+}
+
+pub struct SigintGuard {
+    /// Keeps the flag the handler points to alive.
+    _token: CancelToken,
+}
+
+impl Drop for SigintGuard {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        {
+            // SAFETY: restores the default disposition.
+            unsafe { libc::signal(libc::SIGINT, libc::SIG_DFL) };
+            SIGINT_FLAG.store(std::ptr::null_mut(), Ordering::SeqCst);
+        }
+    }
+}
+
+/// Number of `-v` flags passed to this invocation.
+static VERBOSITY: AtomicU8 = AtomicU8::new(0);
+
+pub fn set_verbosity(level: u8) {
+    VERBOSITY.store(level, Ordering::Relaxed);
+}
+
+pub fn verbosity() -> u8 {
+    VERBOSITY.load(Ordering::Relaxed)
+}
+
+/// Time limit for processes started with [`CommandExt::run`], as the deadline
+/// and the limit it was derived from.
+static DEADLINE: Mutex<Option<(Instant, Duration)>> = Mutex::new(None);
+
+/// Bounds the processes started with [`CommandExt::run`] to `limit` from now,
+/// until the returned guard is dropped.
+///
+/// A process still running at the deadline is killed together with its
+/// children, and fails with a timeout error.
+pub fn set_time_limit(limit: Duration) -> TimeLimit {
+    *DEADLINE.lock().unwrap() = Some((Instant::now() + limit, limit));
+    TimeLimit(())
+}
+
+fn deadline() -> Option<(Instant, Duration)> {
+    *DEADLINE.lock().unwrap()
+}
+
+pub struct TimeLimit(());
+
+impl Drop for TimeLimit {
+    fn drop(&mut self) {
+        *DEADLINE.lock().unwrap() = None;
+    }
+}
+
+/// Whether the value of env var `name` must not be printed.
+fn is_secret_env(name: &str) -> bool {
+    let name = name.to_uppercase();
+    [
+        "TOKEN",
+        "SECRET",
+        "PASSWORD",
+        "PASSWD",
+        "CREDENTIAL",
+        "AUTH",
+        "KEY",
+    ]
+    .iter()
+    .any(|s| name.contains(s))
+}
+
+/// Describe the fully resolved command line, working dir and the env vars
+/// set on `cmd`, with secret values masked.
+fn describe_command(verb: &str, cmd: &Command) -> String {
+    let program = cmd.get_program();
+    let resolved = match which::which(program) {
+        Ok(path) => path.display().to_string(),
+        Err(_) => program.to_string_lossy().into_owned(),
+    };
+    let mut out = format!("{verb} {resolved}");
+    for arg in cmd.get_args() {
+        out.push(' ');
+        out.push_str(&arg.to_string_lossy());
+    }
+    if let Some(dir) = cmd.get_current_dir() {
+        out.push_str(&format!("\n  cwd: {}", dir.display()));
+    }
+    for (key, value) in cmd.get_envs() {
+        let key = key.to_string_lossy();
+        let value = match value {
+            None => "<removed>".to_string(),
+            Some(_) if is_secret_env(&key) => "****".to_string(),
+            Some(value) => value.to_string_lossy().into_owned(),
+        };
+        out.push_str(&format!("\n  env: {key}={value}"));
+    }
+    out
+}
+
+/// At `-vv`, print what is about to be spawned.
+pub fn log_spawn(cmd: &Command) {
+    log::trace!("{}", describe_command("Spawning", cmd));
+}
+
+/// Set by `--dry-run`: commands are printed instead of run.
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+pub fn set_dry_run(dry_run: bool) {
+    DRY_RUN.store(dry_run, Ordering::Relaxed);
+}
+
+pub fn is_dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
+/// With `--dry-run`, print `cmd` and report that it should not be spawned.
+fn skip_dry_run(cmd: &Command) -> bool {
+    if is_dry_run() {
+        log_line(&describe_command("Would run", cmd));
+    }
+    is_dry_run()
+}
+
 /// Make sure a binary exists and runs with the given arguments.
+///
+/// Also runs with `--dry-run`, since the plan depends on what is installed.
 pub fn ensure_binary(command: &str, args: &[&str]) -> Result<(), anyhow::Error> {
-    Command::new(command)
-        .args(args)
-        .stdout(std::process::Stdio::piped())
-        .run_verbose()
-        .with_context(|| format!("Could not find or execute binary: {command}"))?;
+    log::debug!("Running {command} {}:", args.join(" "));
+    run_query(Command::new(command).args(args)).with_context(|| {
+        crate::error::ToolchainError::MissingBinary {
+            name: command.to_string(),
+        }
+    })?;
     Ok(())
 }
 
+/// Run `cmd`, which only reads state, even with `--dry-run`.
+fn run_query(cmd: &mut Command) -> Result<Output> {
+    log_spawn(cmd);
+    let output = cmd
+        .output()
+        .with_context(|| format!("failed to create process {:?}", cmd))?;
+    check_success(cmd, &output.status, &output.stdout, &output.stderr)?;
+    Ok(output)
+}
+
+/// Total size in bytes of all files below `path`.
+///
+/// Symlinks are not followed.
+pub fn dir_size(path: &Path) -> Result<u64> {
+    let meta = fs::symlink_metadata(path)
+        .with_context(|| format!("failed to read metadata of `{}`", path.display()))?;
+    if !meta.is_dir() {
+        return Ok(meta.len());
+    }
+    let mut size = 0;
+    for entry in
+        fs::read_dir(path).with_context(|| format!("failed to read `{}`", path.display()))?
+    {
+        size += dir_size(&entry?.path())?;
+    }
+    Ok(size)
+}
+
+/// Format a byte count for humans, e.g. `1.5 GiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
 pub trait CommandExt {
     fn as_command_mut(&mut self) -> &mut Command;
 
+    /// Run a command that only reads state, like `rustup toolchain list`,
+    /// and return its stdout. Also runs with `--dry-run`.
     fn capture_stdout(&mut self) -> Result<String> {
         let cmd = self.as_command_mut();
-        let output = cmd.stderr(Stdio::inherit()).output_if_success()?;
+        let output = run_query(cmd.stderr(Stdio::inherit()))?;
         let s = String::from_utf8(output.stdout)
             .map_err(|_| anyhow!("process output was not utf-8"))
             .with_context(|| format!("failed to execute {:?}", cmd))?;
@@ -34,7 +628,10 @@ pub trait CommandExt {
 
     fn run_verbose(&mut self) -> Result<()> {
         let c = self.as_command_mut();
-        eprintln!(
+        if is_dry_run() {
+            return self.run();
+        }
+        log::debug!(
             "Running {} {}:",
             c.get_program().to_string_lossy(),
             c.get_args()
@@ -47,15 +644,35 @@ pub trait CommandExt {
 
     fn run(&mut self) -> Result<()> {
         let cmd = self.as_command_mut();
+        if skip_dry_run(cmd) {
+            return Ok(());
+        }
+        if log_file_active() {
+            return run_tee(cmd.stdin(Stdio::inherit()));
+        }
         cmd.stderr(Stdio::inherit())
             .stdout(Stdio::inherit())
-            .stdin(Stdio::inherit())
-            .output_if_success()?;
+            .stdin(Stdio::inherit());
+        if deadline().is_some() {
+            log_spawn(cmd);
+            let mut child = spawn_killable(cmd)?;
+            let status = wait_until(cmd, &mut child, deadline())?;
+            return check_success(cmd, &status, &[], &[]);
+        }
+        cmd.output_if_success()?;
         Ok(())
     }
 
     fn output_if_success(&mut self) -> Result<Output> {
         let cmd = self.as_command_mut();
+        if skip_dry_run(cmd) {
+            return Ok(Output {
+                status: ExitStatus::default(),
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+            });
+        }
+        log_spawn(cmd);
         let output = cmd
             .output()
             .with_context(|| format!("failed to create process {:?}", cmd))?;
@@ -64,6 +681,106 @@ pub trait CommandExt {
     }
 }
 
+/// Run `cmd`, copying its output to the console and the log file.
+fn run_tee(cmd: &mut Command) -> Result<()> {
+    fn tee(reader: impl std::io::Read, mut console: impl std::io::Write) {
+        use std::io::BufRead;
+
+        let mut reader = std::io::BufReader::new(reader);
+        let mut line = Vec::new();
+        while reader.read_until(b'\n', &mut line).unwrap_or(0) > 0 {
+            drop(console.write_all(&line));
+            drop(console.flush());
+            log_to_file(&String::from_utf8_lossy(&line));
+            line.clear();
+        }
+    }
+
+    log_spawn(cmd);
+    let mut child = spawn_killable(cmd.stdout(Stdio::piped()).stderr(Stdio::piped()))?;
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    let stdout = std::thread::spawn(move || tee(stdout, std::io::stdout()));
+    let stderr = std::thread::spawn(move || tee(stderr, std::io::stderr()));
+    let status = wait_until(cmd, &mut child, deadline());
+    drop(stdout.join());
+    drop(stderr.join());
+    check_success(cmd, &status?, &[], &[])
+}
+
+/// Spawn `cmd` so that, while a time limit is set, it and all of its
+/// children can be killed at once.
+fn spawn_killable(cmd: &mut Command) -> Result<Child> {
+    #[cfg(unix)]
+    if deadline().is_some() {
+        // Lead a new process group, which kill_tree signals as a whole.
+        std::os::unix::process::CommandExt::process_group(cmd, 0);
+    }
+    cmd.spawn()
+        .with_context(|| format!("failed to create process {:?}", cmd))
+}
+
+/// Wait for `child`, killing its process tree if it is still running at
+/// `deadline`, or once Ctrl-C cancelled the token of [`cancel_on_sigint`].
+///
+/// The child leads its own process group while a deadline is set, so a
+/// Ctrl-C in the terminal does not reach it and it has to be killed here.
+fn wait_until(
+    cmd: &Command,
+    child: &mut Child,
+    deadline: Option<(Instant, Duration)>,
+) -> Result<ExitStatus> {
+    if deadline.is_none() && sigint_cancelled().is_none() {
+        return child
+            .wait()
+            .with_context(|| format!("failed to wait for process {:?}", cmd));
+    }
+    loop {
+        if let Some(status) = child
+            .try_wait()
+            .with_context(|| format!("failed to wait for process {:?}", cmd))?
+        {
+            return Ok(status);
+        }
+        if sigint_cancelled() == Some(true) {
+            kill_tree(child);
+            bail!("cancelled while running {:?}", cmd.get_program());
+        }
+        let mut wait = Duration::from_millis(100);
+        if let Some((deadline, limit)) = deadline {
+            let now = Instant::now();
+            if now >= deadline {
+                kill_tree(child);
+                bail!(
+                    "timed out after {}s while running {:?}",
+                    limit.as_secs(),
+                    cmd.get_program()
+                );
+            }
+            wait = wait.min(deadline - now);
+        }
+        std::thread::sleep(wait);
+    }
+}
+
+fn kill_tree(child: &mut Child) {
+    #[cfg(unix)]
+    if let Ok(pid) = <libc::pid_t as std::convert::TryFrom<u32>>::try_from(child.id()) {
+        // SAFETY: only sends a signal. The child leads its process group
+        // while a deadline is set; otherwise this fails and only the child
+        // is killed below.
+        unsafe { libc::killpg(pid, libc::SIGKILL) };
+    }
+    #[cfg(windows)]
+    drop(
+        Command::new("taskkill")
+            .args(["/T", "/F", "/PID", &child.id().to_string()])
+            .status(),
+    );
+    drop(child.kill());
+    drop(child.wait());
+}
+
 impl CommandExt for Command {
     fn as_command_mut(&mut self) -> &mut Command {
         self
@@ -98,16 +815,90 @@ impl Drop for FileLock {
 }
 
 pub fn flock(path: &Path) -> Result<FileLock> {
+    flock_timeout(path, None)
+}
+
+/// Lock the file at `path`, waiting at most `timeout` for another process
+/// to release it.
+///
+/// The holder writes its pid into the file, so waiting processes can say
+/// who they wait for. The OS releases the lock when its holder exits, so a
+/// lock can only stay held after a crash if the holder's child processes
+/// still run; the message then says the holder is gone.
+pub fn flock_timeout(path: &Path, timeout: Option<Duration>) -> Result<FileLock> {
+    use std::io::{Read, Seek, Write};
+
     let parent = path.parent().unwrap();
     fs::create_dir_all(parent)
         .context(format!("failed to create directory `{}`", parent.display()))?;
-    let file = OpenOptions::new()
+    let mut file = OpenOptions::new()
         .create(true)
+        .truncate(false)
         .read(true)
         .write(true)
         .open(path)?;
-    file.lock_exclusive()?;
-    return Ok(FileLock(file));
+
+    let started = Instant::now();
+    let mut announced = false;
+    while let Err(err) = file.try_lock_exclusive() {
+        if err.kind() != std::io::ErrorKind::WouldBlock
+            && err.raw_os_error() != fs2::lock_contended_error().raw_os_error()
+        {
+            return Err(err).with_context(|| format!("failed to lock `{}`", path.display()));
+        }
+        let holder = || {
+            let mut pid = String::new();
+            drop(File::open(path).and_then(|mut f| f.read_to_string(&mut pid)));
+            describe_lock_holder(pid.trim().parse().ok())
+        };
+        if timeout.is_some_and(|timeout| started.elapsed() >= timeout) {
+            bail!(
+                "timed out waiting for {} to release the lock `{}` - set WASIX_LOCK_TIMEOUT to wait longer",
+                holder(),
+                path.display()
+            );
+        }
+        if !announced && started.elapsed() >= Duration::from_secs(1) {
+            log::info!("waiting for {} to finish...", holder());
+            announced = true;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    // Best effort, the pid is only used in messages.
+    drop(
+        file.set_len(0)
+            .and_then(|()| file.rewind())
+            .and_then(|()| write!(file, "{}", std::process::id())),
+    );
+    Ok(FileLock(file))
+}
+
+/// Name the process with `pid` that holds a lock.
+fn describe_lock_holder(pid: Option<u32>) -> String {
+    match pid {
+        Some(pid) if process_alive(pid) => format!("another cargo-wasix process (pid {pid})"),
+        Some(pid) => format!(
+            "another cargo-wasix process (pid {pid}, which is no longer running but left programs it started running)"
+        ),
+        None => "another cargo-wasix process".to_string(),
+    }
+}
+
+/// Whether a process with `pid` exists, assuming it does where that can't
+/// be checked.
+fn process_alive(pid: u32) -> bool {
+    #[cfg(unix)]
+    {
+        // SAFETY: signal 0 only checks whether the process exists.
+        let ret = unsafe { libc::kill(pid as libc::pid_t, 0) };
+        ret == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = pid;
+        true
+    }
 }
 
 /// If `Error` is a `ProcessError` and it looks like a "normal exit", then it
@@ -172,27 +963,9 @@ impl fmt::Display for ProcessError {
 
 impl std::error::Error for ProcessError {}
 
-/// Finds an HTTP proxy, in order:
-/// * `http_proxy` env var
-/// * `HTTP_PROXY` env var
-/// * `https_proxy` env var
-/// * `HTTPS_PROXY` env var
-fn get_http_proxy() -> Option<String> {
-    ["http_proxy", "HTTP_PROXY", "https_proxy", "HTTPS_PROXY"]
-        .iter()
-        .map(env::var)
-        .find(|v| v.is_ok())
-        .and_then(|v| v.ok())
-}
-
 pub fn get(url: &str) -> Result<Response> {
-    let mut client = Client::builder();
-    if let Some(proxy_url) = get_http_proxy() {
-        if let Ok(proxy) = Proxy::all(&proxy_url) {
-            client = client.proxy(proxy);
-        }
-    }
-    let client = client.build()?;
+    // Same proxy and TLS settings as the toolchain downloads.
+    let client = crate::release::http_client()?;
 
     let response = client
         .get(url)
@@ -211,3 +984,143 @@ pub fn get(url: &str) -> Result<Response> {
     }
     Ok(response)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancellable_reader() {
+        use std::io::Read;
+        let token = CancelToken::default();
+        let mut reader = token.reader(&b"data"[..]);
+        let mut buf = [0; 2];
+        assert_eq!(reader.read(&mut buf).unwrap(), 2);
+        token.clone().cancel();
+        let err = reader.read(&mut buf).unwrap_err();
+        assert_eq!(err.to_string(), "cancelled");
+    }
+
+    #[test]
+    fn test_cancel_child_token() {
+        let token = CancelToken::default();
+        let child = token.child();
+        child.cancel();
+        assert!(child.is_cancelled() && !token.is_cancelled());
+
+        let child = token.child();
+        token.cancel();
+        assert!(child.is_cancelled());
+    }
+
+    #[test]
+    fn test_progress_reader() {
+        use std::io::Read;
+        let data = vec![0; 1024];
+        let mut reader = progress(&data[..], "rust.tar.gz", 1024, Some(2048));
+        reader.enabled = false;
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+        let line = reader.line();
+        assert!(
+            line.contains("rust.tar.gz 100% 2.0 KiB / 2.0 KiB"),
+            "{}",
+            line
+        );
+
+        reader.done = 1536;
+        assert!(reader.line().contains(" 75% 1.5 KiB / 2.0 KiB"));
+        reader.total = None;
+        assert!(reader.line().contains("1.5 KiB ("));
+    }
+
+    #[test]
+    fn test_flock_timeout() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("lock");
+        let lock = flock(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            std::process::id().to_string()
+        );
+        let err = flock_timeout(&path, Some(Duration::from_millis(200)))
+            .err()
+            .unwrap();
+        assert!(err
+            .to_string()
+            .contains(&format!("(pid {})", std::process::id())));
+        drop(lock);
+        flock_timeout(&path, Some(Duration::ZERO)).unwrap();
+    }
+
+    #[test]
+    fn test_progress_lines() {
+        let (a, b) = (usize::MAX - 1, usize::MAX);
+        assert!(progress_lines(a, Some("  Downloading a 10%".to_string())).contains("a 10%"));
+        let line = progress_lines(b, Some("  Downloading b 5%".to_string()));
+        assert!(
+            line.contains("Downloading a 10% | Downloading b 5%"),
+            "{}",
+            line
+        );
+        let line = progress_lines(a, Some("  Downloading a 20%".to_string()));
+        assert!(line.contains("a 20% | Downloading b 5%"), "{}", line);
+        let line = progress_lines(a, None);
+        assert!(!line.contains("Downloading a"), "{}", line);
+        progress_lines(b, None);
+    }
+
+    #[test]
+    fn test_escape_workflow_data() {
+        assert_eq!(
+            escape_workflow_data("100% broken\r\nsee below"),
+            "100%25 broken%0D%0Asee below"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_wait_until_kills_at_deadline() {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "sleep 30 & wait"]);
+        std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
+        let mut child = cmd.spawn().unwrap();
+        let limit = Duration::from_millis(200);
+        let start = Instant::now();
+        let err = wait_until(&cmd, &mut child, Some((start + limit, limit))).unwrap_err();
+        assert!(err.to_string().contains("timed out"));
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(child.try_wait().unwrap().is_some());
+
+        let mut cmd = Command::new("true");
+        let mut child = cmd.spawn().unwrap();
+        let later = Instant::now() + Duration::from_secs(30);
+        let status = wait_until(&cmd, &mut child, Some((later, limit))).unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_format_utc() {
+        let time = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_709_251_199);
+        assert_eq!(format_utc(time), "2024-02-29 23:59:59");
+        assert_eq!(format_utc(std::time::UNIX_EPOCH), "1970-01-01 00:00:00");
+    }
+
+    #[test]
+    fn test_describe_command_masks_secrets() {
+        let mut cmd = Command::new("definitely-not-a-binary");
+        cmd.arg("--flag")
+            .current_dir("/tmp")
+            .env("CC", "/llvm/bin/clang")
+            .env("GITHUB_TOKEN", "hunter2")
+            .env_remove("RUSTFLAGS");
+        let desc = describe_command("Spawning", &cmd);
+        assert!(desc.starts_with("Spawning definitely-not-a-binary --flag"));
+        assert!(desc.contains("cwd: /tmp"));
+        assert!(desc.contains("env: CC=/llvm/bin/clang"));
+        assert!(desc.contains("env: GITHUB_TOKEN=****"));
+        assert!(desc.contains("env: RUSTFLAGS=<removed>"));
+        assert!(!desc.contains("hunter2"));
+    }
+}
@@ -24,7 +24,7 @@ fn cargo_wasix(args: &str) -> Command {
     path.insert(0, me);
     cmd.env("PATH", std::env::join_paths(&path).unwrap());
 
-    return cmd;
+    cmd
 }
 
 #[test]
@@ -725,9 +725,8 @@ fn name_section() -> Result<()> {
 fn custom_sections(bytes: &[u8]) -> Result<Vec<&str>> {
     let mut sections = Vec::new();
     for payload in wasmparser::Parser::new(0).parse_all(bytes) {
-        match payload? {
-            wasmparser::Payload::CustomSection { name, .. } => sections.push(name),
-            _ => {}
+        if let wasmparser::Payload::CustomSection { name, .. } = payload? {
+            sections.push(name);
         }
     }
     Ok(sections)
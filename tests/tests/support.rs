@@ -16,7 +16,7 @@ pub fn root() -> PathBuf {
     path.pop(); // chop off 'debug'
     path.push("tests");
     fs::create_dir_all(&path).unwrap();
-    path.join(&format!("t{}", id))
+    path.join(format!("t{}", id))
 }
 
 pub fn project() -> ProjectBuilder {
@@ -121,6 +121,6 @@ impl Project {
             process.env("CARGO_TARGET_WASM32_WASIX_RUNNER", runtime_override);
         }
 
-        return process;
+        process
     }
 }